@@ -0,0 +1,78 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use image::{Rgba, RgbaImage};
+use std::hint::black_box;
+
+use zync_lights::capture::{ScreenArea, ZoneConfig, ZoneSampler, sample_zones};
+use zync_lights::layout::{BorderLayout, ZoneDepth};
+
+const DOWNSAMPLE_FACTORS: [u8; 5] = [1, 2, 5, 10, 20];
+const RESOLUTIONS: [(&str, u32, u32); 3] = [("1080p", 1920, 1080), ("1440p", 2560, 1440), ("4k", 3840, 2160)];
+
+/// gradient frame so every pixel and channel differs
+fn test_frame(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, 255])
+    })
+}
+
+/// cost of linear light averaging compared to plain sRGB averaging for a full 1080p zone
+fn linear_light(c: &mut Criterion) {
+    let frame = test_frame(1920, 1080);
+    let area = ScreenArea::full(&frame);
+    let mut group = c.benchmark_group("linear_light_1080p");
+
+    for linear_light in [false, true] {
+        let name = if linear_light { "linear" } else { "srgb" };
+        let zone = ZoneConfig::new(None, 0, 0, 1920, 1080, "bench".to_string());
+        let sampler = ZoneSampler::new(zone, linear_light).unwrap();
+
+        for downsample in DOWNSAMPLE_FACTORS {
+            group.bench_with_input(BenchmarkId::new(name, downsample), &downsample, |b, &downsample| {
+                b.iter(|| sampler.sample(black_box(&frame), downsample, &area).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+/// ring of 12 border zones like a typical multi light setup
+fn border_zones(width: u32, height: u32) -> Vec<ZoneSampler> {
+    let layout = BorderLayout { top: 4, right: 2, bottom: 4, left: 2, depth: ZoneDepth::Percent(15.0) };
+    let light_names: Vec<String> = (0..layout.zone_count()).map(|i| format!("light_{}", i)).collect();
+    layout.generate(width, height, &light_names).unwrap()
+        .into_iter()
+        .map(|zone| ZoneSampler::new(zone, false).unwrap())
+        .collect()
+}
+
+/// sampling 12 zones one at a time, in a single pass over rows, and in parallel across zones
+fn zone_sampling(c: &mut Criterion) {
+    for (resolution, width, height) in RESOLUTIONS {
+        let frame = test_frame(width, height);
+        let area = ScreenArea::full(&frame);
+        let zones = border_zones(width, height);
+        let mut group = c.benchmark_group(format!("12_zones_{}", resolution));
+
+        for downsample in [1, 5, 20] {
+            group.bench_with_input(BenchmarkId::new("per_zone", downsample), &downsample, |b, &downsample| {
+                b.iter(|| {
+                    zones.iter()
+                        .map(|zone| zone.sample(black_box(&frame), downsample, &area).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            });
+            group.bench_with_input(BenchmarkId::new("single_pass", downsample), &downsample, |b, &downsample| {
+                b.iter(|| sample_zones(&zones, black_box(&frame), downsample, &area, false).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("parallel", downsample), &downsample, |b, &downsample| {
+                b.iter(|| sample_zones(&zones, black_box(&frame), downsample, &area, true).unwrap())
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, linear_light, zone_sampling);
+criterion_main!(benches);
@@ -0,0 +1,165 @@
+use serde::Deserialize;
+
+/// Named starting points for how the lights look and move. Any field can be overridden in the aesthetics config section.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// slow, soft transitions and dimmer lights for background ambience
+    Subtle,
+    /// the original defaults
+    #[default]
+    Balanced,
+    /// fast transitions, bright and saturated colors for games
+    Intense,
+    /// smooth transitions and warm darks for films
+    Cinema,
+}
+
+/// Resolved look and feel settings used by MessageColor and transition timing
+#[derive(Debug, Clone, Copy)]
+pub struct Aesthetics {
+    pub transition_softness: f32,   // exponent on color distance. lower makes small changes fast too
+    pub transition_min: f32,        // transition in seconds for the biggest color changes
+    pub transition_max: f32,        // transition in seconds for the smallest color changes
+    pub brightness_curve: f32,      // exponent on screen brightness. below 1 lifts dark scenes
+    pub brightness_factor: f32,     // multiplier on brightness after the curve
+    pub min_brightness: u8,
+    pub dark_threshold: u8,         // brightness below which colors fade toward dark_color
+    pub dark_color: [u8; 3],        // color shown for near black scenes instead of muddy dim colors
+    pub saturation_exp: f32,        // exponent on each channel relative to the brightest one. above 1 is more saturated
+}
+
+impl Aesthetics {
+    pub fn preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Subtle => Aesthetics {
+                transition_softness: 0.6,
+                transition_min: 0.1,
+                transition_max: 1.5,
+                brightness_curve: 0.85,
+                brightness_factor: 0.85,
+                min_brightness: 1,
+                dark_threshold: 35,
+                dark_color: [250, 210, 190],
+                saturation_exp: 1.0,
+            },
+            Preset::Balanced => Aesthetics {
+                transition_softness: 0.4,
+                transition_min: 0.02,
+                transition_max: 1.0,
+                brightness_curve: 0.7,
+                brightness_factor: 1.0,
+                min_brightness: 1,
+                dark_threshold: 25,
+                dark_color: [250, 210, 190],
+                saturation_exp: 1.0,
+            },
+            Preset::Intense => Aesthetics {
+                transition_softness: 0.25,
+                transition_min: 0.0,
+                transition_max: 0.6,
+                brightness_curve: 0.6,
+                brightness_factor: 1.1,
+                min_brightness: 1,
+                dark_threshold: 15,
+                dark_color: [250, 210, 190],
+                saturation_exp: 1.3,
+            },
+            Preset::Cinema => Aesthetics {
+                transition_softness: 0.5,
+                transition_min: 0.05,
+                transition_max: 1.2,
+                brightness_curve: 0.8,
+                brightness_factor: 0.9,
+                min_brightness: 1,
+                dark_threshold: 30,
+                dark_color: [255, 190, 150],
+                saturation_exp: 1.1,
+            },
+        }
+    }
+}
+
+impl Default for Aesthetics {
+    fn default() -> Self {
+        Aesthetics::preset(Preset::Balanced)
+    }
+}
+
+/// aesthetics section of the config. Picks a preset and optionally overrides individual fields.
+#[derive(Deserialize, Default)]
+pub struct AestheticsConfig {
+    #[serde(default)]
+    pub preset: Preset,
+    pub transition_softness: Option<f32>,
+    pub transition_min: Option<f32>,
+    pub transition_max: Option<f32>,
+    pub brightness_curve: Option<f32>,
+    pub brightness_factor: Option<f32>,
+    pub min_brightness: Option<u8>,
+    pub dark_threshold: Option<u8>,
+    pub dark_color: Option<[u8; 3]>,
+    pub saturation_exp: Option<f32>,
+}
+
+impl AestheticsConfig {
+    /// preset values with any overrides from the config applied
+    pub fn resolve(&self) -> Aesthetics {
+        let preset = Aesthetics::preset(self.preset);
+        Aesthetics {
+            transition_softness: self.transition_softness.unwrap_or(preset.transition_softness),
+            transition_min: self.transition_min.unwrap_or(preset.transition_min),
+            transition_max: self.transition_max.unwrap_or(preset.transition_max),
+            brightness_curve: self.brightness_curve.unwrap_or(preset.brightness_curve),
+            brightness_factor: self.brightness_factor.unwrap_or(preset.brightness_factor),
+            min_brightness: self.min_brightness.unwrap_or(preset.min_brightness),
+            dark_threshold: self.dark_threshold.unwrap_or(preset.dark_threshold),
+            dark_color: self.dark_color.unwrap_or(preset.dark_color),
+            saturation_exp: self.saturation_exp.unwrap_or(preset.saturation_exp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(yaml: &str) -> Aesthetics {
+        serde_yaml::from_str::<AestheticsConfig>(yaml).unwrap().resolve()
+    }
+
+    #[test]
+    fn balanced_is_the_original_defaults() {
+        let aesthetics = resolve("{}");
+        assert_eq!(aesthetics.brightness_curve, 0.7);
+        assert_eq!(aesthetics.brightness_factor, 1.0);
+        assert_eq!(aesthetics.dark_threshold, 25);
+        assert_eq!(aesthetics.dark_color, [250, 210, 190]);
+        assert_eq!(resolve("preset: balanced").brightness_curve, 0.7);
+    }
+
+    #[test]
+    fn named_presets_resolve() {
+        let intense = resolve("preset: intense");
+        assert_eq!(intense.transition_max, 0.6);
+        assert_eq!(intense.saturation_exp, 1.3);
+        let cinema = resolve("preset: cinema");
+        assert_eq!(cinema.dark_threshold, 30);
+        assert_eq!(cinema.dark_color, [255, 190, 150]);
+        assert_eq!(resolve("preset: subtle").transition_min, 0.1);
+    }
+
+    #[test]
+    fn overrides_win_over_the_preset() {
+        let aesthetics = resolve("{ preset: intense, transition_max: 2.0, dark_color: [10, 20, 30] }");
+        assert_eq!(aesthetics.transition_max, 2.0);
+        assert_eq!(aesthetics.dark_color, [10, 20, 30]);
+        // fields that aren't overridden keep the preset's values
+        assert_eq!(aesthetics.saturation_exp, 1.3);
+    }
+
+    #[test]
+    fn unknown_presets_are_rejected() {
+        assert!(serde_yaml::from_str::<AestheticsConfig>("preset: loud").is_err());
+    }
+}
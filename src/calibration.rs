@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// colors stepped through by `zync calibrate`. whites and greys show tint and gamma, the rest show hue shifts
+pub const REFERENCE_COLORS: [(&str, [u8; 3]); 9] = [
+    ("white", [255, 255, 255]),
+    ("grey", [128, 128, 128]),
+    ("red", [255, 0, 0]),
+    ("orange", [255, 128, 0]),
+    ("yellow", [255, 255, 0]),
+    ("green", [0, 255, 0]),
+    ("cyan", [0, 255, 255]),
+    ("blue", [0, 0, 255]),
+    ("magenta", [255, 0, 255]),
+];
+
+/// Per light color correction so different bulb models show the same color for the same RGB.
+/// Applied in order: color matrix, RGB gains, then gamma, all on 0-1 channel values.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Calibration {
+    pub gains: [f32; 3],                        // multiplier per channel. lower a channel to remove a tint (e.g. [1.0, 0.9, 0.8] for a cool bulb)
+    pub gamma: f32,                             // exponent on each channel. above 1 darkens mid tones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<[[f32; 3]; 3]>,          // rows produce r, g, b from the input r, g, b. corrects hue shifts between models
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            gains: [1.0; 3],
+            gamma: 1.0,
+            matrix: None,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn is_identity(&self) -> bool {
+        *self == Calibration::default()
+    }
+
+    /// corrects an RGB color for this light
+    pub fn apply(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        if self.is_identity() {
+            return (r, g, b);
+        }
+
+        let input = [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0];
+        let mixed = match self.matrix {
+            None => input,
+            Some(matrix) => matrix.map(|row| row[0] * input[0] + row[1] * input[1] + row[2] * input[2]),
+        };
+
+        let channel = |c: usize| {
+            let value = (mixed[c] * self.gains[c]).clamp(0.0, 1.0).powf(self.gamma);
+            (value * 255.0).round() as u8
+        };
+        (channel(0), channel(1), channel(2))
+    }
+}
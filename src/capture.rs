@@ -1,15 +1,21 @@
 use image::RgbaImage;
-use serde::Deserialize;
-use anyhow::{Result};
+use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result, anyhow, bail};
 use xcap::*;
 use ashpd::desktop::screencast::{Screencast, CursorMode, SourceType};
 use ashpd::desktop::PersistMode;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tokio::runtime::Runtime;
 use gstreamer as gst;
 use gst::prelude::*;
 use gstreamer_app::{AppSink, AppSinkCallbacks};
+use rayon::prelude::*;
+
+use crate::color::{ColorAlgorithm, ColorMetric, linear_to_srgb, srgb_to_linear};
+use crate::smoothing::{Smoother, SmoothingConfig};
+use crate::weighting::{Edge, Falloff, ZoneWeighting};
 
 //use std::time::{Duration, Instant};
 
@@ -227,18 +233,85 @@ pub fn new_screen() -> Result<Box<dyn ScreenCapture>> {
     bail!("MacOS not yet supported");
 }
 
+/// Part of a frame that holds the picture. This is the full frame unless black bars were detected.
+/// Zones are defined against the full screen and get scaled into this area when sampling.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ScreenArea {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenArea {
+    pub fn full(frame: &RgbaImage) -> Self {
+        ScreenArea { x: 0, y: 0, width: frame.width(), height: frame.height() }
+    }
+}
+
 /// rectangular zone on screen to sample color from
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct ZoneConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
     x: u32,
     y: u32,
     width: u32,
     height: u32,
-    light_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    light_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    lights: Vec<ZoneLight>,
+    #[serde(default)]
+    algorithm: ColorAlgorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    falloff: Option<Falloff>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edge: Option<Edge>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mask: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    smoothing: Option<SmoothingConfig>,
+}
+
+/// A light driven by a zone. When several zones drive the same light, their samples are blended using weight.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ZoneLight {
+    pub light_name: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+impl ZoneConfig {
+    pub fn new (name: Option<String>, x: u32, y: u32, width: u32, height: u32, light_name: String) -> Self {
+        ZoneConfig { name, x, y, width, height, light_name: Some(light_name), lights: Vec::new(), algorithm: ColorAlgorithm::default(),
+            falloff: None, edge: None, mask: None, smoothing: None }
+    }
+
+    /// name used in log messages. Falls back to the zone's position if it isn't named in the config.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("at ({}, {})", self.x, self.y),
+        }
+    }
+
+    /// All lights this zone drives. `light_name` is shorthand for a single light with a weight of 1.
+    pub fn lights(&self) -> Vec<ZoneLight> {
+        let mut lights = self.lights.clone();
+        if let Some(light_name) = &self.light_name {
+            lights.push(ZoneLight { light_name: light_name.clone(), weight: default_weight() });
+        }
+        lights
+    }
 }
 
 /// This is a color sample from the screen. Its separate from ColorCommand because it implements differs_from and both could have their own unique functions in the future.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct ZoneColor { pub r: u8, pub g: u8, pub b: u8}
 
 impl ZoneColor {
@@ -246,70 +319,317 @@ impl ZoneColor {
     pub fn new (r: u8, g: u8, b: u8) -> Self {
         ZoneColor{ r, g, b }
     }
-    pub fn compare_sample(&self, other: &ZoneColor) -> f32 {
-        let dr = (self.r as f32 - other.r as f32).abs();
-        let dg = (self.g as f32 - other.g as f32).abs();
-        let db = (self.b as f32 - other.b as f32).abs();
+    pub fn compare_sample(&self, other: &ZoneColor, metric: ColorMetric) -> f32 {
+        metric.distance(self, other)
+    }
+    /// Weighted average of several samples. Used when more than one zone drives a light.
+    pub fn blend(samples: &[(ZoneColor, f32)]) -> ZoneColor {
+        let total: f32 = samples.iter().map(|(_, weight)| weight).sum();
+        if total <= 0.0 {
+            return ZoneColor::new(0, 0, 0);
+        }
 
-        (dr.powi(2) + dg.powi(2) + db.powi(2)).sqrt()
+        let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+        for (sample, weight) in samples {
+            r += sample.r as f32 * weight;
+            g += sample.g as f32 * weight;
+            b += sample.b as f32 * weight;
+        }
+
+        ZoneColor::new(
+            (r / total).round() as u8,
+            (g / total).round() as u8,
+            (b / total).round() as u8,
+        )
     }
     ///this function checks if any color channel exceeds a given threshold
-    pub fn differs_from (&self, other: &ZoneColor, threshold: u8) -> bool {
-        let diff = self.compare_sample(other);
-        diff > threshold as f32
+    pub fn differs_from (&self, other: &ZoneColor, threshold: f32, metric: ColorMetric) -> bool {
+        let diff = self.compare_sample(other, metric);
+        diff > threshold
     }
 
 }
 
 ///Used to sample a region on a monitor
 pub struct ZoneSampler {
-    config: ZoneConfig
+    config: ZoneConfig,
+    linear_light: bool,
+    weighting: Option<ZoneWeighting>,
+    clip_warned: bool,
 }
 
 impl ZoneSampler {
-    pub fn new (config: ZoneConfig) -> Result<Self> {
-        Ok(ZoneSampler {config})
+    pub fn new (config: ZoneConfig, linear_light: bool) -> Result<Self> {
+        if config.width == 0 || config.height == 0 {
+            bail!("Zone {} has zero width or height", config.label());
+        }
+        let weighting = ZoneWeighting::from_config(config.falloff, config.edge, config.mask.as_deref())
+            .with_context(|| format!("Invalid weighting for zone {}", config.label()))?;
+        Ok(ZoneSampler {config, linear_light, weighting, clip_warned: false})
+    }
+
+    pub fn get_lights(&self) -> Vec<ZoneLight> {
+        self.config.lights()
+    }
+
+    pub fn get_name(&self) -> Option<String> {
+        self.config.name.clone()
+    }
+
+    pub fn get_label(&self) -> String {
+        self.config.label()
+    }
+
+    /// the zone's rectangle as configured, before clipping to the frame
+    pub fn get_area(&self) -> ScreenArea {
+        ScreenArea { x: self.config.x, y: self.config.y, width: self.config.width, height: self.config.height }
+    }
+
+    /// fresh temporal filter for this zone's samples, if the zone has smoothing configured
+    pub fn smoother(&self) -> Option<Smoother> {
+        self.config.smoothing.map(Smoother::new)
+    }
+
+    /// the zone's rectangle clipped to a frame of the given size, as (x_start, x_end, y_start, y_end).
+    /// None if the zone is entirely outside the frame.
+    fn clip(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let x_start = self.config.x.min(width);
+        let x_end = self.config.x.saturating_add(self.config.width).min(width);
+        let y_start = self.config.y.min(height);
+        let y_end = self.config.y.saturating_add(self.config.height).min(height);
+
+        (x_end > x_start && y_end > y_start).then_some((x_start, x_end, y_start, y_end))
+    }
+
+    /// Checks the zone against a new frame size, e.g. after a resolution change or picking a smaller window on Wayland.
+    /// Zones that hang off the frame are clipped with a warning, given once per zone. Zones entirely outside it are an error.
+    pub fn resolve(&mut self, width: u32, height: u32) -> Result<()> {
+        match self.clip(width, height) {
+            None => bail!("Zone {} is outside the {}x{} frame. Check zone coordinates against your screen resolution.",
+                self.config.label(), width, height),
+            Some((x_start, x_end, y_start, y_end)) => {
+                let clipped = x_end - x_start < self.config.width || y_end - y_start < self.config.height;
+                if clipped && !self.clip_warned {
+                    eprintln!("Warning: zone {} extends past the {}x{} frame and was clipped to {}x{}",
+                        self.config.label(), width, height, x_end - x_start, y_end - y_start);
+                    self.clip_warned = true;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// pixel bounds (x_start, x_end, y_start, y_end) of the zone in the frame, clipped to the frame.
+    /// The zone is scaled from full screen coordinates into area, so it follows the picture when black bars are present.
+    fn bounds(&self, screenshot: &RgbaImage, area: &ScreenArea) -> Result<(u32, u32, u32, u32)> {
+        let (width, height) = screenshot.dimensions();
+        let (x_start, x_end, y_start, y_end) = self.clip(width, height)
+            .ok_or_else(|| anyhow!("Zone {} is outside the {}x{} frame", self.config.label(), width, height))?;
+
+        let scale_x = |x: u32| area.x + (x as u64 * area.width as u64 / width as u64) as u32;
+        let scale_y = |y: u32| area.y + (y as u64 * area.height as u64 / height as u64) as u32;
+
+        // scaling can collapse small zones, keep at least one pixel inside the frame
+        let x_start = scale_x(x_start).min(width - 1);
+        let x_end = scale_x(x_end).clamp(x_start + 1, width);
+        let y_start = scale_y(y_start).min(height - 1);
+        let y_end = scale_y(y_end).clamp(y_start + 1, height);
+
+        Ok((x_start, x_end, y_start, y_end))
     }
 
-    pub fn get_light_name(&self) -> String {
-        self.config.light_name.clone()
+    /// plain unweighted means only need running channel sums, everything else needs every downsampled pixel
+    fn is_plain_mean(&self) -> bool {
+        self.config.algorithm == ColorAlgorithm::Mean && self.weighting.is_none()
+    }
+
+    /// turns running channel sums from sum_row into the zone's color
+    fn finish_mean(&self, sums: [u64; 3], count: u64) -> ZoneColor {
+        let count = count.max(1);
+        if self.linear_light {
+            let decode = |sum: u64| linear_to_srgb(sum as f32 / count as f32);
+            return ZoneColor::new(decode(sums[0]), decode(sums[1]), decode(sums[2]));
+        }
+
+        ZoneColor {
+            r: (sums[0] / count) as u8,
+            g: (sums[1] / count) as u8,
+            b: (sums[2] / count) as u8,
+        }
     }
 
     /// Captures average rgb values for a zone. Uses downsampling for larger zones.
-    pub fn sample (&self, screenshot: &RgbaImage, downsample: u8) -> Result<ZoneColor> {
+    pub fn sample (&self, screenshot: &RgbaImage, downsample: u8, area: &ScreenArea) -> Result<ZoneColor> {
 
         //let time1 = Instant::now();
 
         //set loop start + stop for iterating through pixels
-        let x_start = self.config.x;
-        let x_end = x_start + self.config.width;
-        let y_start = self.config.y;
-        let y_end = y_start + self.config.height;
+        let (x_start, x_end, y_start, y_end) = self.bounds(screenshot, area)?;
+        let step = downsample.max(1) as usize;
+
+        if !self.is_plain_mean() {
+            let mut pixels = Vec::new();
+            let mut weights = Vec::new();
+            let (zone_width, zone_height) = ((x_end - x_start) as f32, (y_end - y_start) as f32);
+
+            for y_pixel in (y_start..y_end).step_by(step) {
+                let row = row_slice(screenshot, y_pixel, x_start, x_end);
+                for (i, pixel) in row.chunks_exact(4).step_by(step).enumerate() {
+                    pixels.push([pixel[0], pixel[1], pixel[2]]);
+
+                    if let Some(weighting) = &self.weighting {
+                        let u = (i * step) as f32 / zone_width;
+                        let v = (y_pixel - y_start) as f32 / zone_height;
+                        weights.push(weighting.weight(u, v));
+                    }
+                }
+            }
 
+            let weights = self.weighting.as_ref().map(|_| weights.as_slice());
+            return Ok(self.config.algorithm.extract(&mut pixels, weights, self.linear_light));
+        }
 
         // Calculate average
-        let mut r_sum = 0u64;
-        let mut g_sum = 0u64;
-        let mut b_sum = 0u64;
+        let mut sums = [0u64; 3];
         let mut count = 0u64;
 
-        for y_pixel in (y_start..y_end).step_by(downsample as usize) {
-            for x_pixel in (x_start..x_end).step_by(downsample as usize) {
-                let pixel = screenshot.get_pixel(x_pixel, y_pixel);
-                r_sum += pixel[0] as u64;
-                g_sum += pixel[1] as u64;
-                b_sum += pixel[2] as u64;
-                count += 1;
+        for y_pixel in (y_start..y_end).step_by(step) {
+            let row = row_slice(screenshot, y_pixel, x_start, x_end);
+            let (row_sums, row_count) = sum_row(row, step, self.linear_light);
+            for (sum, row_sum) in sums.iter_mut().zip(row_sums) {
+                *sum += row_sum;
             }
-
+            count += row_count;
         }
 
         //println!("Total image process time: {}micro sec", time1.elapsed().as_micros());
 
-        Ok(ZoneColor {
-            r: (r_sum / count) as u8,
-            g: (g_sum / count) as u8,
-            b: (b_sum / count) as u8,
+        Ok(self.finish_mean(sums, count))
+    }
+}
+
+/// rgba bytes of one row of the frame between x_start and x_end
+fn row_slice(screenshot: &RgbaImage, y: u32, x_start: u32, x_end: u32) -> &[u8] {
+    let row_start = (y as usize * screenshot.width() as usize + x_start as usize) * 4;
+    let row_end = row_start + (x_end - x_start) as usize * 4;
+    &screenshot.as_raw()[row_start..row_end]
+}
+
+/// Sums the rgb channels of every step-th pixel in a row of rgba bytes and returns the sums and pixel count.
+/// Rows are summed into u32 lanes over fixed size pixel chunks so the compiler can vectorise the loop.
+/// A row of 4K linear light values still fits in u32 (3840 * 65535).
+fn sum_row(row: &[u8], step: usize, linear_light: bool) -> ([u64; 3], u64) {
+    let mut lanes = [0u32; 4];
+    let mut count = 0u64;
+
+    if linear_light {
+        for pixel in row.chunks_exact(4).step_by(step) {
+            for channel in 0..3 {
+                lanes[channel] += srgb_to_linear(pixel[channel]) as u32;
+            }
+            count += 1;
+        }
+    } else if step == 1 {
+        // contiguous pixels: summing all four channels keeps the loop branch free and vectorisable
+        for pixel in row.chunks_exact(4) {
+            for channel in 0..4 {
+                lanes[channel] += pixel[channel] as u32;
+            }
+        }
+        count = (row.len() / 4) as u64;
+    } else {
+        for pixel in row.chunks_exact(4).step_by(step) {
+            for channel in 0..3 {
+                lanes[channel] += pixel[channel] as u32;
+            }
+            count += 1;
+        }
+    }
+
+    ([lanes[0] as u64, lanes[1] as u64, lanes[2] as u64], count)
+}
+
+/// Samples every zone for a frame. Plain mean zones are summed in a single pass over the frame's rows: each row is read
+/// once, top to bottom, and summed into every zone it falls in, so overlapping zones share reads. With parallel set, zones are sampled independently across a thread pool instead, which pays off
+/// with many zones at low downsample factors.
+pub fn sample_zones(zones: &[ZoneSampler], screenshot: &RgbaImage, downsample: u8, area: &ScreenArea, parallel: bool) -> Result<Vec<ZoneColor>> {
+    if parallel {
+        return zones.par_iter()
+            .map(|zone| zone.sample(screenshot, downsample, area))
+            .collect();
+    }
+
+    let step = downsample.max(1) as usize;
+    let bounds = zones.iter()
+        .map(|zone| zone.bounds(screenshot, area))
+        .collect::<Result<Vec<(u32, u32, u32, u32)>>>()?;
+    let mut sums = vec![([0u64; 3], 0u64); zones.len()];
+
+    let plain: Vec<usize> = (0..zones.len()).filter(|&i| zones[i].is_plain_mean()).collect();
+    let first_row = plain.iter().map(|&i| bounds[i].2).min().unwrap_or(0);
+    let last_row = plain.iter().map(|&i| bounds[i].3).max().unwrap_or(0);
+
+    for y_pixel in first_row..last_row {
+        for &i in &plain {
+            let (x_start, x_end, y_start, y_end) = bounds[i];
+            // zones sample every step-th row counted from their own top edge
+            if y_pixel < y_start || y_pixel >= y_end || !((y_pixel - y_start) as usize).is_multiple_of(step) {
+                continue;
+            }
+            let row = row_slice(screenshot, y_pixel, x_start, x_end);
+            let (row_sums, row_count) = sum_row(row, step, zones[i].linear_light);
+            for (sum, row_sum) in sums[i].0.iter_mut().zip(row_sums) {
+                *sum += row_sum;
+            }
+            sums[i].1 += row_count;
+        }
+    }
+
+    zones.iter().zip(sums)
+        .map(|(zone, (sums, count))| match zone.is_plain_mean() {
+            true => Ok(zone.finish_mean(sums, count)),
+            false => zone.sample(screenshot, downsample, area),
         })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn zone(x: u32, y: u32, width: u32, height: u32) -> ZoneSampler {
+        ZoneSampler::new(ZoneConfig::new(None, x, y, width, height, "light".to_string()), false).unwrap()
+    }
+
+    #[test]
+    fn single_pass_matches_sampling_each_zone() {
+        let frame = RgbaImage::from_fn(160, 90, |x, y| Rgba([(x * 3 % 256) as u8, (y * 5 % 256) as u8, ((x + y) % 256) as u8, 255]));
+        let area = ScreenArea::full(&frame);
+        let median = ZoneSampler::new(serde_yaml::from_str("{ x: 40, y: 30, width: 50, height: 30, light_name: light, algorithm: median }").unwrap(), false).unwrap();
+        let zones = vec![zone(0, 0, 160, 20), zone(10, 5, 30, 60), zone(100, 41, 60, 49), median, zone(150, 80, 40, 40)];
+
+        for downsample in [1, 2, 3, 7] {
+            let expected: Vec<ZoneColor> = zones.iter().map(|zone| zone.sample(&frame, downsample, &area).unwrap()).collect();
+            let single_pass = sample_zones(&zones, &frame, downsample, &area, false).unwrap();
+            let parallel = sample_zones(&zones, &frame, downsample, &area, true).unwrap();
+            assert_eq!(single_pass, expected, "downsample {}", downsample);
+            assert_eq!(parallel, expected, "downsample {}", downsample);
+        }
+    }
+
+    #[test]
+    fn resolve_clips_zones_and_rejects_zones_outside_the_frame() {
+        let mut hanging = zone(1800, 1000, 200, 200);
+        assert!(hanging.resolve(1920, 1080).is_ok());
+        assert!(hanging.clip_warned);
+        assert!(hanging.resolve(1920, 1080).is_ok());
+
+        let mut inside = zone(0, 0, 100, 100);
+        assert!(inside.resolve(1920, 1080).is_ok());
+        assert!(!inside.clip_warned);
+
+        let error = zone(2000, 0, 100, 100).resolve(1920, 1080).unwrap_err();
+        assert_eq!(error.to_string(), "Zone at (2000, 0) is outside the 1920x1080 frame. Check zone coordinates against your screen resolution.");
     }
 }
@@ -0,0 +1,83 @@
+use clap::{Args, Parser, Subcommand};
+
+use crate::layout::ZoneDepth;
+
+/// Command line interface. Running without a subcommand starts syncing lights to the screen.
+#[derive(Parser)]
+#[command(name = "zync", version, about = "Real-time ambilight for Linux and Zigbee2MQTT")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage screen zones in the config file
+    Zones {
+        #[command(subcommand)]
+        command: ZonesCommand,
+    },
+    /// Inspect lights known to Zigbee2MQTT and OpenRGB
+    Lights {
+        #[command(subcommand)]
+        command: LightsCommand,
+    },
+    /// Step a light through reference colors and adjust its calibration so it matches your other lights
+    Calibrate(CalibrateArgs),
+}
+
+#[derive(Subcommand)]
+pub enum ZonesCommand {
+    /// Generate zones around the screen border from a physical light arrangement.
+    /// Zones are assigned to the configured lights in clockwise order starting at the top left.
+    Generate(GenerateArgs),
+}
+
+#[derive(Subcommand)]
+pub enum LightsCommand {
+    /// List color capable lights and groups published by Zigbee2MQTT, with the names to use in the config
+    List {
+        /// also list lights that only support color temperature or brightness
+        #[arg(long)]
+        all: bool,
+    },
+    /// List devices and zones on the OpenRGB SDK server, with the names to use in the config
+    Openrgb,
+}
+
+#[derive(Args)]
+pub struct GenerateArgs {
+    /// number of lights along the top edge
+    #[arg(long, default_value_t = 0)]
+    pub top: u32,
+    /// number of lights along the right edge
+    #[arg(long, default_value_t = 0)]
+    pub right: u32,
+    /// number of lights along the bottom edge
+    #[arg(long, default_value_t = 0)]
+    pub bottom: u32,
+    /// number of lights along the left edge
+    #[arg(long, default_value_t = 0)]
+    pub left: u32,
+    /// how far zones reach into the screen, in pixels (120) or percent of the screen (15%)
+    #[arg(long, default_value = "15%")]
+    pub depth: ZoneDepth,
+    /// screen width in pixels
+    #[arg(long, default_value_t = 1920)]
+    pub width: u32,
+    /// screen height in pixels
+    #[arg(long, default_value_t = 1080)]
+    pub height: u32,
+    /// print the generated zones instead of writing them to the config file
+    #[arg(long)]
+    pub print: bool,
+}
+
+#[derive(Args)]
+pub struct CalibrateArgs {
+    /// light to calibrate, as named in the config
+    pub light: String,
+    /// light to compare against. it shows the same reference colors with its own calibration
+    #[arg(long)]
+    pub reference: Option<String>,
+}
@@ -0,0 +1,482 @@
+use std::sync::LazyLock;
+use serde::{Deserialize, Serialize};
+
+use crate::capture::ZoneColor;
+
+const DOMINANT_BITS: u32 = 4;               // bits per channel kept when bucketing colors for dominant
+const VIBRANT_MIN_WEIGHT: f32 = 0.01;       // keeps grey scenes from having zero total weight
+const LINEAR_MAX: f32 = 65535.0;            // linear light values are stored as u16 so sums stay in integers
+
+/// sRGB byte to linear light lookup table. Built once on first use.
+static SRGB_TO_LINEAR: LazyLock<[u16; 256]> = LazyLock::new(|| {
+    let mut table = [0u16; 256];
+    for (value, entry) in table.iter_mut().enumerate() {
+        let encoded = value as f32 / 255.0;
+        let linear = if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        };
+        *entry = (linear * LINEAR_MAX).round() as u16;
+    }
+    table
+});
+
+/// converts an sRGB channel to linear light (0-65535)
+pub fn srgb_to_linear(value: u8) -> u16 {
+    SRGB_TO_LINEAR[value as usize]
+}
+
+/// converts a linear light channel (0-65535) back to sRGB
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let linear = (value / LINEAR_MAX).clamp(0.0, 1.0);
+    let encoded = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+/// Running weighted per channel sums used to average pixels. In linear mode pixels are converted to linear light
+/// before summing and the average is converted back, which keeps mixes of bright and dark pixels from averaging too dark.
+pub struct ColorSum {
+    sums: [f64; 3],
+    weight: f64,
+    linear: bool,
+}
+
+impl ColorSum {
+    pub fn new(linear: bool) -> Self {
+        ColorSum { sums: [0.0; 3], weight: 0.0, linear }
+    }
+
+    pub fn add(&mut self, pixel: [u8; 3], weight: f32) {
+        for (sum, value) in self.sums.iter_mut().zip(pixel) {
+            let value = if self.linear { srgb_to_linear(value) as f64 } else { value as f64 };
+            *sum += value * weight as f64;
+        }
+        self.weight += weight as f64;
+    }
+
+    pub fn average(&self) -> ZoneColor {
+        if self.weight <= 0.0 {
+            return ZoneColor::new(0, 0, 0);
+        }
+        let channel = |sum: f64| {
+            let value = sum / self.weight;
+            if self.linear { linear_to_srgb(value as f32) } else { value.round().min(255.0) as u8 }
+        };
+        ZoneColor::new(channel(self.sums[0]), channel(self.sums[1]), channel(self.sums[2]))
+    }
+}
+
+/// How a zone's pixels are reduced to a single color. All of these run on the downsampled pixels.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorAlgorithm {
+    /// average of all pixels. fastest, but mixes distinct colors (red + blue = purple)
+    #[default]
+    Mean,
+    /// per channel median. ignores small bright or dark details
+    Median,
+    /// most common color, found by bucketing pixels into a coarse histogram
+    Dominant,
+    /// average weighted by saturation and brightness, so vivid colors win over greys and blacks
+    Vibrant,
+}
+
+impl ColorAlgorithm {
+    /// reduces a set of rgb pixels to one color. pixels must not be empty.
+    /// weights, when given, has one entry per pixel and scales how much each pixel counts.
+    /// linear averages in linear light. The median picks an existing value, so it is the same either way.
+    pub fn extract(&self, pixels: &mut [[u8; 3]], weights: Option<&[f32]>, linear: bool) -> ZoneColor {
+        match self {
+            ColorAlgorithm::Mean => mean(pixels, weights, linear),
+            ColorAlgorithm::Median => match weights {
+                Some(weights) => weighted_median(pixels, weights),
+                None => median(pixels),
+            },
+            ColorAlgorithm::Dominant => dominant(pixels, weights, linear),
+            ColorAlgorithm::Vibrant => vibrant(pixels, weights, linear),
+        }
+    }
+}
+
+fn weight_at(weights: Option<&[f32]>, index: usize) -> f32 {
+    weights.map_or(1.0, |weights| weights[index])
+}
+
+fn mean(pixels: &[[u8; 3]], weights: Option<&[f32]>, linear: bool) -> ZoneColor {
+    let mut sum = ColorSum::new(linear);
+    for (i, pixel) in pixels.iter().enumerate() {
+        sum.add(*pixel, weight_at(weights, i));
+    }
+    sum.average()
+}
+
+fn median(pixels: &mut [[u8; 3]]) -> ZoneColor {
+    let middle = pixels.len() / 2;
+    let mut channel_median = |channel: usize| {
+        pixels.select_nth_unstable_by_key(middle, |pixel| pixel[channel]);
+        pixels[middle][channel]
+    };
+    let r = channel_median(0);
+    let g = channel_median(1);
+    let b = channel_median(2);
+    ZoneColor::new(r, g, b)
+}
+
+/// per channel value where half of the total weight sits on either side. with equal weights it picks the same value
+/// as median, the upper of the two middle values
+fn weighted_median(pixels: &[[u8; 3]], weights: &[f32]) -> ZoneColor {
+    let half = weights.iter().sum::<f32>() / 2.0;
+    if half <= 0.0 {
+        return ZoneColor::new(0, 0, 0);
+    }
+    let channel_median = |channel: usize| {
+        // channels are bytes, so a 256 bin histogram of weight replaces sorting
+        let mut histogram = [0.0f32; 256];
+        for (pixel, weight) in pixels.iter().zip(weights) {
+            histogram[pixel[channel] as usize] += weight;
+        }
+        let mut total = 0.0;
+        for (value, weight) in histogram.iter().enumerate() {
+            total += weight;
+            if total > half {
+                return value as u8;
+            }
+        }
+        255
+    };
+    ZoneColor::new(channel_median(0), channel_median(1), channel_median(2))
+}
+
+/// Buckets pixels into a coarse 3d histogram and picks the fullest bucket. The result is the mean of the pixels in that
+/// bucket and its neighbours, so colors sitting on a bucket edge aren't split in two.
+fn dominant(pixels: &[[u8; 3]], weights: Option<&[f32]>, linear: bool) -> ZoneColor {
+    let bins = 1usize << DOMINANT_BITS;
+    let shift = 8 - DOMINANT_BITS;
+    let bin_of = |pixel: &[u8; 3]| ((pixel[0] >> shift) as usize, (pixel[1] >> shift) as usize, (pixel[2] >> shift) as usize);
+    let index = |(r, g, b): (usize, usize, usize)| (r * bins + g) * bins + b;
+
+    let mut counts = vec![0.0f32; bins * bins * bins];
+    for (i, pixel) in pixels.iter().enumerate() {
+        counts[index(bin_of(pixel))] += weight_at(weights, i);
+    }
+
+    let fullest = (0..counts.len()).max_by(|&a, &b| counts[a].total_cmp(&counts[b])).unwrap_or(0);
+    let (fr, fg, fb) = (fullest / (bins * bins), (fullest / bins) % bins, fullest % bins);
+
+    let mut sum = ColorSum::new(linear);
+    for (i, pixel) in pixels.iter().enumerate() {
+        let (r, g, b) = bin_of(pixel);
+        if r.abs_diff(fr) <= 1 && g.abs_diff(fg) <= 1 && b.abs_diff(fb) <= 1 {
+            sum.add(*pixel, weight_at(weights, i));
+        }
+    }
+    sum.average()
+}
+
+fn vibrant(pixels: &[[u8; 3]], weights: Option<&[f32]>, linear: bool) -> ZoneColor {
+    let mut sum = ColorSum::new(linear);
+    for (i, pixel) in pixels.iter().enumerate() {
+        let (_, saturation, value) = rgb_to_hsv(pixel[0], pixel[1], pixel[2]);
+        sum.add(*pixel, ((saturation * value).powi(2) + VIBRANT_MIN_WEIGHT) * weight_at(weights, i));
+    }
+    sum.average()
+}
+
+/// converts rgb to hue (0-360), saturation (0-1), and value (0-1)
+pub fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+
+    (hue, saturation, max)
+}
+
+/// How the difference between two colors is measured. Used for refresh_threshold and transition timing.
+/// Euclidean is rgb distance (0-441). OkLab and Ciede2000 are perceptual and scaled so black to white is about 100.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMetric {
+    #[default]
+    Euclidean,
+    OkLab,
+    Ciede2000,
+}
+
+impl ColorMetric {
+    pub fn distance(&self, a: &ZoneColor, b: &ZoneColor) -> f32 {
+        match self {
+            ColorMetric::Euclidean => {
+                let dr = a.r as f32 - b.r as f32;
+                let dg = a.g as f32 - b.g as f32;
+                let db = a.b as f32 - b.b as f32;
+                (dr.powi(2) + dg.powi(2) + db.powi(2)).sqrt()
+            }
+            ColorMetric::OkLab => {
+                let (l1, a1, b1) = to_oklab(a);
+                let (l2, a2, b2) = to_oklab(b);
+                ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt() * 100.0
+            }
+            ColorMetric::Ciede2000 => ciede2000(to_cielab(a), to_cielab(b)),
+        }
+    }
+
+    /// distance treated as the largest possible change when scaling transitions
+    pub fn max_distance(&self) -> f32 {
+        match self {
+            ColorMetric::Euclidean => 441.0,
+            ColorMetric::OkLab => 100.0,
+            ColorMetric::Ciede2000 => 100.0,
+        }
+    }
+}
+
+fn to_linear(color: &ZoneColor) -> (f64, f64, f64) {
+    let channel = |value: u8| srgb_to_linear(value) as f64 / LINEAR_MAX as f64;
+    (channel(color.r), channel(color.g), channel(color.b))
+}
+
+/// sRGB to OKLab (https://bottosson.github.io/posts/oklab/)
+pub fn to_oklab(color: &ZoneColor) -> (f32, f32, f32) {
+    let (r, g, b) = to_linear(color);
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    (
+        (0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s) as f32,
+        (1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s) as f32,
+        (0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s) as f32,
+    )
+}
+
+/// sRGB to CIE 1931 xy chromaticity. black has no chromaticity, so it maps to the D65 white point
+pub fn to_xy(color: &ZoneColor) -> (f32, f32) {
+    let (r, g, b) = to_linear(color);
+
+    let x = 0.4124564 * r + 0.3575761 * g + 0.1804375 * b;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = 0.0193339 * r + 0.1191920 * g + 0.9503041 * b;
+
+    let sum = x + y + z;
+    if sum == 0.0 {
+        return (0.3127, 0.3290);
+    }
+    ((x / sum) as f32, (y / sum) as f32)
+}
+
+/// sRGB to CIE L*a*b* with a D65 white point
+pub fn to_cielab(color: &ZoneColor) -> (f32, f32, f32) {
+    let (r, g, b) = to_linear(color);
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f64| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    ((116.0 * fy - 16.0) as f32, (500.0 * (fx - fy)) as f32, (200.0 * (fy - fz)) as f32)
+}
+
+/// CIEDE2000 color difference between two L*a*b* colors
+/// (Sharma, Wu, Dalal. "The CIEDE2000 Color-Difference Formula", 2005)
+pub fn ciede2000(lab1: (f32, f32, f32), lab2: (f32, f32, f32)) -> f32 {
+    let (l1, a1, b1) = (lab1.0 as f64, lab1.1 as f64, lab1.2 as f64);
+    let (l2, a2, b2) = (lab2.0 as f64, lab2.1 as f64, lab2.2 as f64);
+
+    let c1 = a1.hypot(b1);
+    let c2 = a2.hypot(b2);
+    let c_mean = (c1 + c2) / 2.0;
+    let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = a1p.hypot(b1);
+    let c2p = a2p.hypot(b2);
+
+    let hue = |b: f64, a: f64| if a == 0.0 && b == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dl = l2 - l1;
+    let dc = c2p - c1p;
+    let dh = if c1p * c2p == 0.0 {
+        0.0
+    } else if (h2p - h1p).abs() <= 180.0 {
+        h2p - h1p
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else {
+        h2p - h1p + 360.0
+    };
+    let dh_big = 2.0 * (c1p * c2p).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_mean = (l1 + l2) / 2.0;
+    let cp_mean = (c1p + c2p) / 2.0;
+    let hp_mean = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * hp_mean).to_radians().cos()
+        + 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (cp_mean.powi(7) / (cp_mean.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + (0.015 * (l_mean - 50.0).powi(2)) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * cp_mean;
+    let s_h = 1.0 + 0.015 * cp_mean * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let dl = dl / s_l;
+    let dc = dc / s_c;
+    let dh_big = dh_big / s_h;
+
+    (dl.powi(2) + dc.powi(2) + dh_big.powi(2) + r_t * dc * dh_big).sqrt() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Lab = (f32, f32, f32);
+
+    /// test pairs from Sharma, Wu, Dalal (2005), table 1
+    const SHARMA_PAIRS: [(Lab, Lab, f32); 34] = [
+        ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+        ((50.0, 3.1571, -77.2803), (50.0, 0.0, -82.7485), 2.8615),
+        ((50.0, 2.8361, -74.0200), (50.0, 0.0, -82.7485), 3.4412),
+        ((50.0, -1.3802, -84.2814), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, -1.1848, -84.8006), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, -0.9009, -85.5211), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, 0.0, 0.0), (50.0, -1.0, 2.0), 2.3669),
+        ((50.0, -1.0, 2.0), (50.0, 0.0, 0.0), 2.3669),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0009), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0010), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0011), 7.2195),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0012), 7.2195),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0009, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0010, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0011, -2.4900), 4.7461),
+        ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+        ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+        ((50.0, 2.5, 0.0), (61.0, -5.0, 29.0), 22.8977),
+        ((50.0, 2.5, 0.0), (56.0, -27.0, -3.0), 31.9030),
+        ((50.0, 2.5, 0.0), (58.0, 24.0, 15.0), 19.4535),
+        ((50.0, 2.5, 0.0), (50.0, 3.1736, 0.5854), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 3.2972, 0.0), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 1.8634, 0.5757), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 3.2592, 0.3350), 1.0000),
+        ((60.2574, -34.0099, 36.2677), (60.4626, -34.1751, 39.4387), 1.2644),
+        ((63.0109, -31.0961, -5.8663), (62.8187, -29.7946, -4.0864), 1.2630),
+        ((61.2901, 3.7196, -5.3901), (61.4292, 2.2480, -4.9620), 1.8731),
+        ((35.0831, -44.1164, 3.7933), (35.0232, -40.0716, 1.5901), 1.8645),
+        ((22.7233, 20.0904, -46.6940), (23.0331, 14.9730, -42.5619), 2.0373),
+        ((36.4612, 47.8580, 18.3852), (36.2715, 50.5065, 21.2231), 1.4146),
+        ((90.8027, -2.0831, 1.4410), (91.1528, -1.6435, 0.0447), 1.4441),
+        ((90.9257, -0.5406, -0.9208), (88.6381, -0.8985, -0.7239), 1.5381),
+        ((6.7747, -0.2908, -2.4247), (5.8714, -0.0985, -2.2286), 0.6377),
+        ((2.0776, 0.0795, -1.1350), (0.9033, -0.0636, -0.5514), 0.9082),
+    ];
+
+    #[test]
+    fn ciede2000_matches_sharma_reference_pairs() {
+        for (i, (lab1, lab2, expected)) in SHARMA_PAIRS.iter().enumerate() {
+            let difference = ciede2000(*lab1, *lab2);
+            assert!((difference - expected).abs() < 1e-3, "pair {}: expected {}, got {}", i + 1, expected, difference);
+            // the formula is symmetric
+            assert!((ciede2000(*lab2, *lab1) - expected).abs() < 1e-3, "pair {} reversed", i + 1);
+        }
+    }
+
+    /// every pixel repeated count times
+    fn pixels(colors: &[([u8; 3], usize)]) -> Vec<[u8; 3]> {
+        colors.iter().flat_map(|(color, count)| std::iter::repeat_n(*color, *count)).collect()
+    }
+
+    #[test]
+    fn dominant_picks_red_or_blue_instead_of_purple() {
+        let mut zone = pixels(&[([250, 10, 5], 30), ([255, 0, 0], 30), ([0, 0, 255], 40)]);
+
+        let mean = ColorAlgorithm::Mean.extract(&mut zone.clone(), None, false);
+        assert_eq!(mean, ZoneColor::new(152, 3, 104));
+
+        let dominant = ColorAlgorithm::Dominant.extract(&mut zone, None, false);
+        assert_eq!(dominant, ZoneColor::new(253, 5, 3));
+
+        // weights can tip it the other way
+        let mut weights = vec![1.0; 60];
+        weights.extend([2.0; 40]);
+        let blue = ColorAlgorithm::Dominant.extract(&mut zone, Some(&weights), false);
+        assert_eq!(blue, ZoneColor::new(0, 0, 255));
+    }
+
+    #[test]
+    fn median_ignores_small_details_and_breaks_ties_upward() {
+        let mut zone = pixels(&[([40, 40, 40], 9), ([255, 255, 255], 2)]);
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, None, false), ZoneColor::new(40, 40, 40));
+
+        // with an even count both medians take the upper middle value
+        let mut zone = vec![[10, 40, 0], [20, 30, 0], [30, 20, 0], [40, 10, 0]];
+        let expected = ZoneColor::new(30, 30, 0);
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone.clone(), None, false), expected);
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, Some(&[1.0; 4]), false), expected);
+    }
+
+    #[test]
+    fn weighted_median_follows_the_weight() {
+        let mut zone = vec![[10, 10, 10], [20, 20, 20], [30, 30, 30]];
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, Some(&[1.0, 1.0, 1.0]), false), ZoneColor::new(20, 20, 20));
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, Some(&[1.0, 1.0, 5.0]), false), ZoneColor::new(30, 30, 30));
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, Some(&[4.0, 1.0, 1.0]), false), ZoneColor::new(10, 10, 10));
+        assert_eq!(ColorAlgorithm::Median.extract(&mut zone, Some(&[0.0, 0.0, 0.0]), false), ZoneColor::new(0, 0, 0));
+    }
+
+    #[test]
+    fn vibrant_favours_saturated_pixels() {
+        let mut zone = pixels(&[([128, 128, 128], 8), ([255, 0, 0], 2)]);
+
+        let mean = ColorAlgorithm::Mean.extract(&mut zone.clone(), None, false);
+        assert_eq!(mean, ZoneColor::new(153, 102, 102));
+
+        let vibrant = ColorAlgorithm::Vibrant.extract(&mut zone, None, false);
+        assert!(vibrant.r > 240 && vibrant.g < 10 && vibrant.b < 10, "{:?}", vibrant);
+
+        // all grey still gives the grey rather than black
+        let mut grey = pixels(&[([128, 128, 128], 4)]);
+        assert_eq!(ColorAlgorithm::Vibrant.extract(&mut grey, None, false), ZoneColor::new(128, 128, 128));
+    }
+
+    #[test]
+    fn perceptual_metrics_scale_black_to_white_near_100() {
+        let black = ZoneColor::new(0, 0, 0);
+        let white = ZoneColor::new(255, 255, 255);
+        assert!((ColorMetric::OkLab.distance(&black, &white) - 100.0).abs() < 0.5);
+        assert!((ColorMetric::Ciede2000.distance(&black, &white) - 100.0).abs() < 0.5);
+        assert!((ColorMetric::Euclidean.distance(&black, &white) - 441.67).abs() < 0.01);
+    }
+}
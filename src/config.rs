@@ -4,53 +4,157 @@ use rumqttc::*;
 use serde::Deserialize;
 use anyhow::{Result, Context, bail};
 use std::fs;
+use std::path::PathBuf;
 use dirs;
 use std::time::Duration;
 
-use crate::lights::LightConfig;
+use crate::aesthetics::AestheticsConfig;
+use crate::calibration::Calibration;
+use crate::homeassistant::HomeAssistantConfig;
+use crate::hue::HueConfig;
+use crate::dmx::DmxConfig;
+use crate::openrgb::OpenRgbConfig;
+use crate::lights::{DEFAULT_BASE_TOPIC, LightConfig, LightService};
 use crate::capture::ZoneConfig;
 use crate::sync::PerformanceConfig;
+use crate::letterbox::LetterboxConfig;
+use crate::scenecut::SceneCutConfig;
 
 
 // App config loads all of the configuratoin parameters for the app, including mqtt configs, the lights, zones, and global settings for the app.
 // These are passed into the other objects with config.field_name syntax.
 #[derive(Deserialize)]
 pub struct AppConfig {
-    pub mqtt: MQTTConfig,
+    pub mqtt: Option<MQTTConfig>,      // only needed for Zigbee2MQTT, Tasmota, and ESPHome lights
+    #[serde(default)]
+    pub zigbee2mqtt: Zigbee2MQTTConfig,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    pub hue: Option<HueConfig>,
+    pub dmx: Option<DmxConfig>,
+    pub openrgb: Option<OpenRgbConfig>,
     pub lights: Vec<LightConfig>,
     pub zones: Vec<ZoneConfig>,
     pub downsample_factor: u8,
+    #[serde(default)]
+    pub linear_light: bool,
     pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub letterbox: LetterboxConfig,
+    #[serde(default)]
+    pub scene_cut: SceneCutConfig,
+    #[serde(default)]
+    pub aesthetics: AestheticsConfig,
 }
 
 impl AppConfig {
     /// loads the yaml file using dir for cross-platform compatibility, and serde_yaml to construct the AppConfig
     pub fn load() -> Result<Self> {
-        let config_dir = dirs::config_dir()
-            .context("Could not find config directory")?
-            .join("zync");
-        let path = config_dir.join("config.yaml");
+        let path = Self::path()?;
+        let config_dir = path.parent().context("Could not find config directory")?;
 
         if !path.exists() {
             if std::env::var("USER").unwrap_or_default() == "root" {
                 bail!("Don't run as root. Run as normal user without sudo.");
             }
-            fs::create_dir_all(&config_dir).context("Failed to create directory during example config file creation")?;
+            fs::create_dir_all(config_dir).context("Failed to create directory during example config file creation")?;
             fs::write(&path, Self::example_config()).context("Failed to create example config file")?;
             bail!("Config file created at {:?}\nPlease edit it and run again.", path);
         }
 
         let contents = fs::read_to_string(&path)
             .context("Failed to read configuration file")?;
-        let config: AppConfig = serde_yaml::from_str(&contents)
+        let mut config: AppConfig = serde_yaml::from_str(&contents)
             .context("Error processing configuration file. Check formatting.")?;
+
+        // lights without their own base_topic use the one from the zigbee2mqtt section
+        for light in &mut config.lights {
+            light.base_topic.get_or_insert_with(|| config.zigbee2mqtt.base_topic.clone());
+        }
         Ok(config)
     }
 
+    /// every Zigbee2MQTT base topic in use, starting with the default one
+    pub fn base_topics(&self) -> Vec<&str> {
+        let mut base_topics = vec![self.zigbee2mqtt.base_topic.as_str()];
+        for light in &self.lights {
+            if !base_topics.contains(&light.base_topic()) {
+                base_topics.push(light.base_topic());
+            }
+        }
+        base_topics
+    }
+
+    /// base topics of the Zigbee2MQTT lights that discovery checks. lights with a raw topic override aren't checked
+    pub fn verified_base_topics(&self) -> Vec<&str> {
+        let mut base_topics = Vec::new();
+        for light in self.lights.iter().filter(|light| matches!(light.service, LightService::Zigbee2MQTT) && light.topic.is_none()) {
+            if !base_topics.contains(&light.base_topic()) {
+                base_topics.push(light.base_topic());
+            }
+        }
+        base_topics
+    }
+
+    /// MQTT client and connection when any of the lights go through the broker
+    pub fn mqtt_client(&self, lights: &[LightConfig]) -> Result<Option<(Client, Connection)>> {
+        if !lights.iter().any(|light| light.service.uses_mqtt()) {
+            return Ok(None);
+        }
+        let mqtt = self.mqtt.as_ref()
+            .context("Zigbee2MQTT, Tasmota, and ESPHome lights need an mqtt section with the broker")?;
+        mqtt.create_client().map(Some)
+    }
+
+    /// location of the config file, ~/.config/zync/config.yaml on Linux
+    pub fn path() -> Result<PathBuf> {
+        let config_dir = dirs::config_dir()
+            .context("Could not find config directory")?
+            .join("zync");
+        Ok(config_dir.join("config.yaml"))
+    }
+
+    /// Replaces the zones section of the config file. The file is re-serialized, so comments in it are not preserved.
+    pub fn write_zones(zones: &[ZoneConfig]) -> Result<()> {
+        let path = Self::path()?;
+        let contents = fs::read_to_string(&path)
+            .context("Failed to read configuration file")?;
+        let mut document: serde_yaml::Value = serde_yaml::from_str(&contents)
+            .context("Error processing configuration file. Check formatting.")?;
+
+        let mapping = document.as_mapping_mut()
+            .context("Configuration file is not a yaml mapping")?;
+        mapping.insert("zones".into(), serde_yaml::to_value(zones)?);
+
+        fs::write(&path, serde_yaml::to_string(&document)?)
+            .context("Failed to write configuration file")?;
+        Ok(())
+    }
+
+    /// replaces the calibration of one light in the config file, keeping the rest of the file's settings
+    pub fn write_calibration(light_name: &str, calibration: &Calibration) -> Result<()> {
+        let path = Self::path()?;
+        let contents = fs::read_to_string(&path)
+            .context("Failed to read configuration file")?;
+        let mut document: serde_yaml::Value = serde_yaml::from_str(&contents)
+            .context("Error processing configuration file. Check formatting.")?;
+
+        let light = document.get_mut("lights")
+            .and_then(|lights| lights.as_sequence_mut())
+            .and_then(|lights| lights.iter_mut().find(|light| light.get("light_name").and_then(|name| name.as_str()) == Some(light_name)))
+            .and_then(|light| light.as_mapping_mut())
+            .with_context(|| format!("Light {} not found in configuration file", light_name))?;
+        light.insert("calibration".into(), serde_yaml::to_value(calibration)?);
+
+        fs::write(&path, serde_yaml::to_string(&document)?)
+            .context("Failed to write configuration file")?;
+        Ok(())
+    }
+
     fn example_config() -> &'static str {
         r###"
 # Sample configuration file for one light and single zone covering full 1080p monitor
 # Enter mqtt options, define lights, and set zones that map to those lights in this file.
+# The mqtt section is only needed for Zigbee2MQTT, Tasmota, and ESPHome lights.
 mqtt:
   name: "my-connection"
   broker: "192.168.1.100"
@@ -58,31 +162,66 @@ mqtt:
   user: "user name"         # optional depending on broker config
   password: "password"      # optional depending on broker config
 
+zigbee2mqtt:
+  base_topic: "zigbee2mqtt"         # match base_topic in Zigbee2MQTT's configuration.yaml. lights can override it
+
 downsample_factor: 20
+linear_light: false                 # average colors in linear light. more accurate mixes of bright and dark pixels at a small cost
 
 lights:
   - light_name: "your_device_name"    # Must match the device name in Z2M. Can be a Z2M group or single light
     service: "Zigbee2MQTT"
     brightness: 0.8                   # percent brightness of light. range is 0-1. anything over 1 will be capped to 1 by the app.
 
-zone:
+zones:
   - name: "main_screen"
     x: 0
     y: 0
     width: 1920
     height: 1080
     light_name: "your_device_name"  # Must match device_name of the lights imported above
+    algorithm: mean                 # mean, median, dominant, or vibrant
 
 performance:
   max_fps: 12                       # max_fps. make sure it isn't too high for your lights. 10-12 is a safe starting point.
   max_delay: 500                    # max recovery delay in ms before retrying connection
   refresh_threshold: 10             # difference in color required to send MQTT light change
+  color_metric: euclidean           # euclidean (rgb distance, 0-441), oklab or ciede2000 (perceptual, 0-100). threshold is in the metric's units
   percent_thread_work: 0.25         # max work/interval ratio.
   fps_reporting: 10                 # time in seconds between fps averages output in terminal. raise percent_thread_work for higher FPS.
+  parallel_sampling: false          # sample zones across CPU cores. helps with many zones at a low downsample_factor
+
+letterbox:
+  enabled: false                    # detect black bars (e.g. 21:9 films on a 16:9 screen) and move zones into the picture
+
+scene_cut:
+  enabled: false                    # snap all lights together on hard scene cuts, skipping smoothing and slow transitions
+  threshold: 0.5                    # fraction of the frame's colors that must change to count as a cut
+
+aesthetics:
+  preset: balanced                  # subtle, balanced, intense, or cinema. any preset field can be overridden here
 "###
     }
 }
 
+/// Settings for the Zigbee2MQTT instance lights are on by default
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Zigbee2MQTTConfig {
+    pub base_topic: String,         // must match base_topic in Zigbee2MQTT's configuration.yaml
+}
+
+impl Default for Zigbee2MQTTConfig {
+    fn default() -> Self {
+        Zigbee2MQTTConfig {
+            base_topic: DEFAULT_BASE_TOPIC.to_string(),
+        }
+    }
+}
+
+const MAX_INCOMING_PACKET: usize = 4 * 1024 * 1024;     // zigbee2mqtt/bridge/devices lists every device and its features, often hundreds of KB
+const MAX_OUTGOING_PACKET: usize = 64 * 1024;
+
 ///This struct and its methods are essentially a wrapper for the rumqttc client.
 #[derive(Deserialize)]
 pub struct MQTTConfig {
@@ -98,6 +237,7 @@ impl MQTTConfig {
     pub fn create_client(&self) -> Result<(Client, Connection)> {
         let mut mqttoptions = MqttOptions::new(&self.name, &self.broker, 1883);
         mqttoptions.set_keep_alive(Duration::from_secs(5));
+        mqttoptions.set_max_packet_size(MAX_INCOMING_PACKET, MAX_OUTGOING_PACKET);
 
         if let (Some(user), Some(password)) = (&self.user, &self.password) {
             mqttoptions.set_credentials(user, password);
@@ -107,3 +247,34 @@ impl MQTTConfig {
         Ok((client, connection))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(lights: &str) -> AppConfig {
+        serde_yaml::from_str(&format!("
+            lights: {}
+            zones: []
+            downsample_factor: 4
+            performance: {{ max_fps: 30, max_delay: 100, refresh_threshold: 0.1, percent_thread_work: 0.8, fps_reporting: 0 }}",
+            lights)).unwrap()
+    }
+
+    #[test]
+    fn verified_base_topics_skip_raw_topic_lights() {
+        let config = config("
+            - { service: Zigbee2MQTT, light_name: lamp, brightness: 1.0 }
+            - { service: Zigbee2MQTT, light_name: desk, brightness: 1.0, base_topic: upstairs }
+            - { service: Zigbee2MQTT, light_name: hall, brightness: 1.0, base_topic: upstairs }
+            - { service: Zigbee2MQTT, light_name: porch, brightness: 1.0, base_topic: outside, topic: custom/porch }
+            - { service: Tasmota, light_name: shelf, brightness: 1.0, base_topic: tasmota }");
+        assert_eq!(config.verified_base_topics(), ["zigbee2mqtt", "upstairs"]);
+    }
+
+    #[test]
+    fn no_base_topics_when_every_light_has_a_raw_topic() {
+        let config = config("[{ service: Zigbee2MQTT, light_name: porch, brightness: 1.0, topic: custom/porch }]");
+        assert!(config.verified_base_topics().is_empty());
+    }
+}
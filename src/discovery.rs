@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, bail};
+use rumqttc::{Client, Connection, Event, Packet, QoS, RecvTimeoutError};
+use serde_json::Value;
+
+use crate::lights::{LightConfig, LightService};
+
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a light can do, read from the features it exposes in Zigbee2MQTT
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub brightness: bool,
+    pub color_xy: bool,
+    pub color_hs: bool,
+    pub color_temp: bool,
+}
+
+impl Capabilities {
+    pub fn supports_color(&self) -> bool {
+        self.color_xy || self.color_hs
+    }
+
+    fn union(self, other: Capabilities) -> Self {
+        Capabilities {
+            brightness: self.brightness || other.brightness,
+            color_xy: self.color_xy || other.color_xy,
+            color_hs: self.color_hs || other.color_hs,
+            color_temp: self.color_temp || other.color_temp,
+        }
+    }
+
+    /// short description for listing, e.g. "color (xy, hs), color_temp, brightness"
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        let color_spaces: Vec<&str> = [(self.color_xy, "xy"), (self.color_hs, "hs")]
+            .into_iter()
+            .filter_map(|(supported, name)| supported.then_some(name))
+            .collect();
+        if !color_spaces.is_empty() {
+            parts.push(format!("color ({})", color_spaces.join(", ")));
+        }
+        if self.color_temp {
+            parts.push("color_temp".to_string());
+        }
+        if self.brightness {
+            parts.push("brightness".to_string());
+        }
+        if parts.is_empty() {
+            return "on/off".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Device,
+    Group,
+}
+
+/// A light or group of lights known to Zigbee2MQTT
+#[derive(Debug, Clone)]
+pub struct DiscoveredLight {
+    pub name: String,
+    pub base_topic: String,
+    pub kind: LightKind,
+    pub capabilities: Capabilities,
+}
+
+/// Lights and groups published by Zigbee2MQTT on its retained bridge topics
+pub struct Discovery {
+    pub lights: Vec<DiscoveredLight>,
+}
+
+impl Discovery {
+    /// subscribes to the bridge topics of each Zigbee2MQTT instance and waits for all of the retained payloads.
+    /// Uses the connection directly, so call this before handing the connection to the notification thread.
+    pub fn discover(client: &Client, connection: &mut Connection, base_topics: &[&str], timeout: Duration) -> Result<Self> {
+        let topics: Vec<String> = base_topics.iter()
+            .flat_map(|base_topic| [devices_topic(base_topic), groups_topic(base_topic)])
+            .collect();
+        for topic in &topics {
+            client.subscribe(topic, QoS::AtMostOnce)?;
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut payloads: HashMap<String, Value> = HashMap::new();
+
+        while let Some(waiting) = topics.iter().find(|topic| !payloads.contains_key(*topic)) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let event = match connection.recv_timeout(remaining) {
+                Ok(event) => event.context("MQTT connection failed during light discovery")?,
+                Err(RecvTimeoutError::Timeout) => bail!("No response on {} within {:?}. Is Zigbee2MQTT running with this base_topic?", waiting, timeout),
+                Err(RecvTimeoutError::Disconnected) => bail!("MQTT connection closed during light discovery"),
+            };
+
+            if let Event::Incoming(Packet::Publish(publish)) = event
+                && topics.contains(&publish.topic)
+            {
+                let payload: Value = serde_json::from_slice(&publish.payload)
+                    .with_context(|| format!("Invalid payload on {}", publish.topic))?;
+                payloads.insert(publish.topic, payload);
+            }
+        }
+
+        for topic in &topics {
+            client.unsubscribe(topic)?;
+        }
+
+        let mut lights = Vec::new();
+        for base_topic in base_topics {
+            let devices = &payloads[&devices_topic(base_topic)];
+            let groups = &payloads[&groups_topic(base_topic)];
+            lights.extend(Self::from_bridge(base_topic, devices, groups).lights);
+        }
+        Ok(Discovery { lights })
+    }
+
+    /// builds the light list of one Zigbee2MQTT instance from its bridge/devices and bridge/groups payloads.
+    /// groups get the combined capabilities of their light members.
+    pub fn from_bridge(base_topic: &str, devices: &Value, groups: &Value) -> Self {
+        let mut lights = Vec::new();
+        let mut by_address: HashMap<&str, Capabilities> = HashMap::new();
+
+        for device in devices.as_array().into_iter().flatten() {
+            let Some(capabilities) = light_capabilities(device) else { continue };
+            let name = device["friendly_name"].as_str().unwrap_or_default();
+            if let Some(address) = device["ieee_address"].as_str() {
+                by_address.insert(address, capabilities);
+            }
+            lights.push(DiscoveredLight { name: name.to_string(), base_topic: base_topic.to_string(), kind: LightKind::Device, capabilities });
+        }
+
+        for group in groups.as_array().into_iter().flatten() {
+            let members: Vec<Capabilities> = group["members"].as_array().into_iter().flatten()
+                .filter_map(|member| member["ieee_address"].as_str())
+                .filter_map(|address| by_address.get(address).copied())
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let capabilities = members.into_iter().fold(Capabilities::default(), Capabilities::union);
+            let name = group["friendly_name"].as_str().unwrap_or_default();
+            lights.push(DiscoveredLight { name: name.to_string(), base_topic: base_topic.to_string(), kind: LightKind::Group, capabilities });
+        }
+
+        Discovery { lights }
+    }
+
+    pub fn find(&self, name: &str, base_topic: &str) -> Option<&DiscoveredLight> {
+        self.lights.iter().find(|light| light.name == name && light.base_topic == base_topic)
+    }
+
+    /// errors if a configured Zigbee2MQTT light doesn't exist, and warns about lights that can't show colors.
+    /// lights with a raw topic override aren't checked.
+    pub fn verify(&self, lights: &[LightConfig]) -> Result<()> {
+        let mut missing = Vec::new();
+
+        for light in lights.iter().filter(|light| matches!(light.service, LightService::Zigbee2MQTT) && light.topic.is_none()) {
+            match self.find(&light.light_name, light.base_topic()) {
+                None => missing.push(format!("{} ({})", light.light_name, light.base_topic())),
+                Some(found) if found.capabilities.supports_color() => {}
+                Some(found) if found.capabilities.color_temp => println!("Light {} only supports color temperature. Colors will be approximated as whites.", light.light_name),
+                Some(found) => println!("Light {} doesn't support color ({}). It will only follow brightness.", light.light_name, found.capabilities.describe()),
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!("Lights not found in Zigbee2MQTT: {}. Run `zync lights list` to see available lights and groups.", missing.join(", "));
+        }
+        Ok(())
+    }
+}
+
+fn devices_topic(base_topic: &str) -> String {
+    format!("{}/bridge/devices", base_topic)
+}
+
+fn groups_topic(base_topic: &str) -> String {
+    format!("{}/bridge/groups", base_topic)
+}
+
+/// capabilities of a device that exposes a light. None for devices that aren't lights (sensors, switches, the coordinator)
+fn light_capabilities(device: &Value) -> Option<Capabilities> {
+    let exposes = device["definition"]["exposes"].as_array()?;
+    let lights: Vec<&Value> = exposes.iter().filter(|expose| expose["type"] == "light").collect();
+    if lights.is_empty() {
+        return None;
+    }
+
+    let mut capabilities = Capabilities::default();
+    for light in lights {
+        collect_features(light, &mut capabilities);
+    }
+    Some(capabilities)
+}
+
+/// walks nested features, since multi endpoint lights and composite color features nest them
+fn collect_features(expose: &Value, capabilities: &mut Capabilities) {
+    match expose["name"].as_str() {
+        Some("brightness") => capabilities.brightness = true,
+        Some("color_xy") => capabilities.color_xy = true,
+        Some("color_hs") => capabilities.color_hs = true,
+        Some("color_temp") => capabilities.color_temp = true,
+        _ => {}
+    }
+    for feature in expose["features"].as_array().into_iter().flatten() {
+        collect_features(feature, capabilities);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// trimmed bridge/devices payload: a color bulb, a color temperature bulb, an on/off light,
+    /// a two endpoint LED controller, a sensor, and the coordinator
+    fn devices() -> Value {
+        json!([
+            { "friendly_name": "Coordinator", "ieee_address": "0x00", "type": "Coordinator", "definition": null },
+            { "friendly_name": "Desk Lamp", "ieee_address": "0x01", "definition": { "exposes": [
+                { "type": "light", "features": [
+                    { "name": "state" },
+                    { "name": "brightness" },
+                    { "name": "color_temp" },
+                    { "type": "composite", "name": "color_xy", "features": [{ "name": "x" }, { "name": "y" }] },
+                    { "type": "composite", "name": "color_hs", "features": [{ "name": "hue" }, { "name": "saturation" }] }
+                ] },
+                { "type": "enum", "name": "effect" }
+            ] } },
+            { "friendly_name": "Hallway", "ieee_address": "0x02", "definition": { "exposes": [
+                { "type": "light", "features": [{ "name": "state" }, { "name": "brightness" }, { "name": "color_temp" }] }
+            ] } },
+            { "friendly_name": "Fairy Lights", "ieee_address": "0x03", "definition": { "exposes": [
+                { "type": "light", "features": [{ "name": "state" }] }
+            ] } },
+            { "friendly_name": "Shelf Strip", "ieee_address": "0x04", "definition": { "exposes": [
+                { "type": "light", "endpoint": "l1", "features": [{ "name": "brightness" }] },
+                { "type": "light", "endpoint": "l2", "features": [{ "type": "composite", "name": "color_xy", "features": [] }] }
+            ] } },
+            { "friendly_name": "Motion Sensor", "ieee_address": "0x05", "definition": { "exposes": [
+                { "type": "binary", "name": "occupancy" }
+            ] } }
+        ])
+    }
+
+    fn groups() -> Value {
+        json!([
+            { "friendly_name": "Living Room", "members": [{ "ieee_address": "0x01", "endpoint": 11 }, { "ieee_address": "0x02", "endpoint": 11 }] },
+            { "friendly_name": "Whites", "members": [{ "ieee_address": "0x02", "endpoint": 1 }] },
+            { "friendly_name": "Sensors", "members": [{ "ieee_address": "0x05", "endpoint": 1 }] },
+            { "friendly_name": "Empty", "members": [] }
+        ])
+    }
+
+    fn light(yaml: &str) -> LightConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn reads_lights_and_groups_from_the_bridge() {
+        let discovery = Discovery::from_bridge("zigbee2mqtt", &devices(), &groups());
+
+        let names: Vec<(&str, LightKind)> = discovery.lights.iter().map(|light| (light.name.as_str(), light.kind)).collect();
+        assert_eq!(names, [
+            ("Desk Lamp", LightKind::Device),
+            ("Hallway", LightKind::Device),
+            ("Fairy Lights", LightKind::Device),
+            ("Shelf Strip", LightKind::Device),
+            ("Living Room", LightKind::Group),
+            ("Whites", LightKind::Group),
+        ]);
+        assert!(discovery.lights.iter().all(|light| light.base_topic == "zigbee2mqtt"));
+
+        let capabilities = |name: &str| discovery.find(name, "zigbee2mqtt").unwrap().capabilities;
+        assert_eq!(capabilities("Desk Lamp").describe(), "color (xy, hs), color_temp, brightness");
+        assert_eq!(capabilities("Hallway").describe(), "color_temp, brightness");
+        assert_eq!(capabilities("Fairy Lights").describe(), "on/off");
+        // groups combine what their members can do
+        assert_eq!(capabilities("Living Room").describe(), "color (xy, hs), color_temp, brightness");
+        assert!(!capabilities("Whites").supports_color());
+        assert!(discovery.find("Desk Lamp", "other_instance").is_none());
+    }
+
+    #[test]
+    fn collects_nested_and_multi_endpoint_features() {
+        let mut capabilities = Capabilities::default();
+        collect_features(&json!({ "type": "light", "features": [
+            { "type": "composite", "name": "color_hs", "features": [{ "name": "hue" }] },
+            { "type": "composite", "features": [{ "name": "color_temp" }] }
+        ] }), &mut capabilities);
+        assert!(capabilities.color_hs && capabilities.color_temp);
+        assert!(!capabilities.color_xy && !capabilities.brightness);
+
+        // each endpoint's features count toward the device
+        let strip = &devices()[4];
+        let capabilities = light_capabilities(strip).unwrap();
+        assert!(capabilities.brightness && capabilities.color_xy);
+
+        assert!(light_capabilities(&devices()[0]).is_none());
+        assert!(light_capabilities(&devices()[5]).is_none());
+    }
+
+    #[test]
+    fn verify_reports_missing_lights() {
+        let discovery = Discovery::from_bridge("zigbee2mqtt", &devices(), &groups());
+
+        let found = [
+            light("{ service: Zigbee2MQTT, light_name: Desk Lamp, brightness: 1.0 }"),
+            light("{ service: Zigbee2MQTT, light_name: Living Room, brightness: 1.0 }"),
+            // color temperature only and on/off lights only warn
+            light("{ service: Zigbee2MQTT, light_name: Hallway, brightness: 1.0 }"),
+            light("{ service: Zigbee2MQTT, light_name: Fairy Lights, brightness: 1.0 }"),
+            // raw topics and other services aren't checked
+            light("{ service: Zigbee2MQTT, light_name: Custom, brightness: 1.0, topic: custom/set }"),
+            light("{ service: WLED, light_name: Not In Zigbee, brightness: 1.0 }"),
+        ];
+        assert!(discovery.verify(&found).is_ok());
+
+        let missing = [
+            light("{ service: Zigbee2MQTT, light_name: Desk Lamp, brightness: 1.0 }"),
+            light("{ service: Zigbee2MQTT, light_name: Desk Lmap, brightness: 1.0 }"),
+            light("{ service: Zigbee2MQTT, light_name: Desk Lamp, brightness: 1.0, base_topic: upstairs }"),
+        ];
+        let error = discovery.verify(&missing).unwrap_err().to_string();
+        assert!(error.contains("Desk Lmap (zigbee2mqtt)"), "{}", error);
+        assert!(error.contains("Desk Lamp (upstairs)"), "{}", error);
+    }
+}
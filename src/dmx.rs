@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::lights::{LightConfig, LightService};
+use crate::udp::UdpOutput;
+
+const SLOTS: usize = 512;
+const SACN_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxProtocol {
+    #[default]
+    Sacn,
+    Artnet,
+}
+
+/// Settings shared by every DMX light
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DmxConfig {
+    pub protocol: DmxProtocol,
+    pub host: Option<String>,       // unicast target. by default sACN uses each universe's multicast group and Art-Net broadcasts
+    pub priority: u8,               // sACN priority (0-200). receivers take the highest priority source
+    pub source_name: String,
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        DmxConfig {
+            protocol: DmxProtocol::Sacn,
+            host: None,
+            priority: 100,
+            source_name: "zync".to_string(),
+        }
+    }
+}
+
+impl DmxConfig {
+    fn port(&self) -> u16 {
+        match self.protocol {
+            DmxProtocol::Sacn => SACN_PORT,
+            DmxProtocol::Artnet => ARTNET_PORT,
+        }
+    }
+
+    /// where a universe is sent
+    fn host(&self, universe: u16) -> String {
+        match (&self.host, self.protocol) {
+            (Some(host), _) => host.clone(),
+            (None, DmxProtocol::Sacn) => format!("239.255.{}.{}", universe >> 8, universe & 0xff),
+            (None, DmxProtocol::Artnet) => "255.255.255.255".to_string(),
+        }
+    }
+}
+
+/// Channel layout of one fixture or pixel, e.g. rgb, grb, or rgbw. r, g, b, and w are colors, x is a channel left at 0.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct ChannelOrder(Vec<u8>);
+
+impl TryFrom<String> for ChannelOrder {
+    type Error = String;
+
+    fn try_from(order: String) -> Result<Self, Self::Error> {
+        let channels = order.to_lowercase().bytes().collect::<Vec<u8>>();
+        if channels.is_empty() || channels.iter().any(|channel| !b"rgbwx".contains(channel)) {
+            return Err(format!("Invalid DMX channel order {:?}. Use the letters r, g, b, w, and x", order));
+        }
+        Ok(ChannelOrder(channels))
+    }
+}
+
+impl Default for ChannelOrder {
+    fn default() -> Self {
+        ChannelOrder(b"rgb".to_vec())
+    }
+}
+
+impl ChannelOrder {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// DMX values for a color. With a white channel, the shared part of r, g, and b is moved onto it
+    fn values(&self, [r, g, b]: [u8; 3]) -> impl Iterator<Item = u8> + '_ {
+        let white = if self.0.contains(&b'w') { r.min(g).min(b) } else { 0 };
+        self.0.iter().map(move |channel| match channel {
+            b'r' => r - white,
+            b'g' => g - white,
+            b'b' => b - white,
+            b'w' => white,
+            _ => 0,
+        })
+    }
+}
+
+/// Where a DMX light's channels are. Segmented lights are a row of pixels with consecutive addresses.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DmxFixture {
+    pub universe: u16,
+    pub address: u16,               // first channel, 1-512
+    #[serde(default)]
+    pub channels: ChannelOrder,
+}
+
+impl DmxFixture {
+    /// errors if the fixture's pixels don't fit in its universe, or the universe doesn't exist for the protocol
+    pub fn validate(&self, protocol: DmxProtocol, segments: usize) -> Result<()> {
+        if protocol == DmxProtocol::Sacn && !(1..=63999).contains(&self.universe) {
+            bail!("sACN universe {} is out of range. Universes are 1-63999", self.universe);
+        }
+        let end = (self.address as usize).checked_sub(1).map(|start| start + segments * self.channels.len());
+        if end.is_none_or(|end| end > SLOTS) {
+            bail!("DMX fixture at universe {} address {} with {} pixels of {} channels doesn't fit in 512 channels", self.universe, self.address, segments, self.channels.len());
+        }
+        Ok(())
+    }
+}
+
+struct Universe {
+    data: [u8; SLOTS],
+    sequence: u8,
+    changed: bool,                  // written since it was last sent
+}
+
+/// Universe buffers shared by every DMX light. Lights write their channels during the frame and each universe is sent
+/// once at the end of the frame, so fixtures sharing a universe go out in one packet.
+pub struct DmxOutput {
+    config: DmxConfig,
+    port: u16,                      // the protocol's port. tests point it at a local receiver
+    cid: [u8; 16],
+    universes: Mutex<BTreeMap<u16, Universe>>,
+}
+
+impl DmxOutput {
+    pub fn new(config: DmxConfig, lights: &[LightConfig]) -> Self {
+        let universes = lights.iter()
+            .filter(|light| matches!(light.service, LightService::DMX))
+            .filter_map(|light| light.dmx.as_ref())
+            .map(|fixture| (fixture.universe, Universe { data: [0; SLOTS], sequence: 0, changed: false }))
+            .collect();
+
+        DmxOutput { port: config.port(), config, cid: random_cid(), universes: Mutex::new(universes) }
+    }
+
+    pub fn protocol(&self) -> DmxProtocol {
+        self.config.protocol
+    }
+
+    /// host and port of every universe, to be resolved up front
+    pub fn targets(&self) -> Vec<(String, u16)> {
+        let universes = self.universes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        universes.keys().map(|universe| (self.config.host(*universe), self.port)).collect()
+    }
+
+    /// writes one color per pixel into the fixture's channels
+    pub fn set(&self, fixture: &DmxFixture, colors: &[[u8; 3]]) -> Result<()> {
+        let mut universes = self.universes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let universe = universes.get_mut(&fixture.universe)
+            .with_context(|| format!("DMX universe {} was not set up at startup", fixture.universe))?;
+
+        let start = (fixture.address as usize).checked_sub(1)
+            .with_context(|| format!("DMX addresses start at 1, universe {} has a fixture at 0", fixture.universe))?;
+        let values = colors.iter().flat_map(|color| fixture.channels.values(*color));
+        for (slot, value) in universe.data.iter_mut().skip(start).zip(values) {
+            *slot = value;
+        }
+        universe.changed = true;
+        Ok(())
+    }
+
+    /// sends every universe written this frame. every DMX light flushes, so universes that were already sent are skipped
+    pub fn flush(&self, udp: &UdpOutput) -> Result<()> {
+        let mut universes = self.universes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (number, universe) in universes.iter_mut().filter(|(_, universe)| universe.changed) {
+            universe.changed = false;
+            let packet = match self.config.protocol {
+                DmxProtocol::Sacn => {
+                    universe.sequence = universe.sequence.wrapping_add(1);
+                    self.sacn_packet(*number, universe)
+                }
+                DmxProtocol::Artnet => {
+                    // Art-Net sequence 0 means sequencing is off, so count 1-255
+                    universe.sequence = universe.sequence % 255 + 1;
+                    artnet_packet(*number, universe)
+                }
+            };
+            udp.send(&self.config.host(*number), self.port, &packet)?;
+        }
+        Ok(())
+    }
+
+    /// E1.31 data packet: root layer, framing layer, then the DMP layer with the start code and 512 slots
+    fn sacn_packet(&self, number: u16, universe: &Universe) -> Vec<u8> {
+        let flags_length = |length: usize| (0x7000 | length as u16).to_be_bytes();
+        let mut source_name = [0u8; 64];
+        let name = self.config.source_name.as_bytes();
+        let name_length = name.len().min(63);
+        source_name[..name_length].copy_from_slice(&name[..name_length]);
+
+        let mut packet = Vec::with_capacity(638);
+        // root layer
+        packet.extend_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+        packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+        packet.extend_from_slice(&flags_length(638 - 16));
+        packet.extend_from_slice(&4u32.to_be_bytes());
+        packet.extend_from_slice(&self.cid);
+        // framing layer
+        packet.extend_from_slice(&flags_length(638 - 38));
+        packet.extend_from_slice(&2u32.to_be_bytes());
+        packet.extend_from_slice(&source_name);
+        packet.push(self.config.priority.min(200));
+        packet.extend_from_slice(&[0x00, 0x00]);                   // synchronization address
+        packet.push(universe.sequence);
+        packet.push(0x00);                                          // options
+        packet.extend_from_slice(&number.to_be_bytes());
+        // DMP layer
+        packet.extend_from_slice(&flags_length(638 - 115));
+        packet.extend_from_slice(&[0x02, 0xa1, 0x00, 0x00, 0x00, 0x01]);
+        packet.extend_from_slice(&(SLOTS as u16 + 1).to_be_bytes());
+        packet.push(0x00);                                          // DMX start code
+        packet.extend_from_slice(&universe.data);
+        packet
+    }
+}
+
+/// ArtDmx packet. The universe is the 15 bit port address (net, sub-net, universe)
+fn artnet_packet(number: u16, universe: &Universe) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(18 + SLOTS);
+    packet.extend_from_slice(b"Art-Net\0");
+    packet.extend_from_slice(&0x5000u16.to_le_bytes());
+    packet.extend_from_slice(&14u16.to_be_bytes());
+    packet.push(universe.sequence);
+    packet.push(0x00);                                              // physical port
+    packet.push((number & 0xff) as u8);
+    packet.push(((number >> 8) & 0x7f) as u8);
+    packet.extend_from_slice(&(SLOTS as u16).to_be_bytes());
+    packet.extend_from_slice(&universe.data);
+    packet
+}
+
+/// sACN sources identify themselves with a UUID. a new one each run is fine for a single source
+fn random_cid() -> [u8; 16] {
+    let mut cid = [0u8; 16];
+    for half in cid.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        half.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    cid[6] = (cid[6] & 0x0f) | 0x40;                                // version 4
+    cid[8] = (cid[8] & 0x3f) | 0x80;                                // variant
+    cid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn light(universe: u16, address: u16, channels: &str) -> LightConfig {
+        let yaml = format!("{{ service: DMX, light_name: par, brightness: 1.0, dmx: {{ universe: {}, address: {}, channels: {} }} }}",
+            universe, address, channels);
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    /// DMX output unicasting to a receiver on a free localhost port
+    fn output(protocol: DmxProtocol, lights: &[LightConfig]) -> (DmxOutput, UdpOutput, UdpSocket) {
+        let config = DmxConfig { protocol, host: Some("127.0.0.1".to_string()), priority: 150, source_name: "zync test".to_string() };
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut dmx = DmxOutput::new(config, lights);
+        dmx.port = receiver.local_addr().unwrap().port();
+        let targets = dmx.targets();
+        let udp = UdpOutput::new(targets.iter().map(|(host, port)| (host.as_str(), *port))).unwrap();
+        (dmx, udp, receiver)
+    }
+
+    fn receive(receiver: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0u8; 1024];
+        let length = receiver.recv(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    #[test]
+    fn address_zero_is_rejected() {
+        let par = light(1, 0, "rgb");
+        let fixture = par.dmx.as_ref().unwrap();
+        assert!(fixture.validate(DmxProtocol::Artnet, 1).is_err());
+        assert!(DmxOutput::new(DmxConfig::default(), std::slice::from_ref(&par)).set(fixture, &[[255, 0, 0]]).is_err());
+    }
+
+    #[test]
+    fn fixtures_must_fit_in_the_universe() {
+        assert!(light(1, 1, "rgb").dmx.unwrap().validate(DmxProtocol::Sacn, 170).is_ok());
+        assert!(light(1, 1, "rgb").dmx.unwrap().validate(DmxProtocol::Sacn, 171).is_err());
+        assert!(light(1, 509, "rgbw").dmx.unwrap().validate(DmxProtocol::Sacn, 1).is_ok());
+        assert!(light(1, 510, "rgbw").dmx.unwrap().validate(DmxProtocol::Sacn, 1).is_err());
+    }
+
+    #[test]
+    fn sacn_universe_zero_is_rejected() {
+        let fixture = light(0, 1, "rgb").dmx.unwrap();
+        assert!(fixture.validate(DmxProtocol::Sacn, 1).is_err());
+        assert!(fixture.validate(DmxProtocol::Artnet, 1).is_ok());
+        assert!(light(63999, 1, "rgb").dmx.unwrap().validate(DmxProtocol::Sacn, 1).is_ok());
+        assert!(light(64000, 1, "rgb").dmx.unwrap().validate(DmxProtocol::Sacn, 1).is_err());
+    }
+
+    #[test]
+    fn sacn_packets_follow_e131() {
+        let par = light(7, 10, "rgbw");
+        let (dmx, udp, receiver) = output(DmxProtocol::Sacn, std::slice::from_ref(&par));
+        let fixture = par.dmx.as_ref().unwrap();
+
+        dmx.set(fixture, &[[200, 100, 50]]).unwrap();
+        dmx.flush(&udp).unwrap();
+        let first = receive(&receiver);
+
+        assert_eq!(first.len(), 638);
+        // root layer
+        assert_eq!(first[0..4], [0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&first[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(u16::from_be_bytes([first[16], first[17]]), 0x7000 | 622);
+        assert_eq!(first[18..22], [0, 0, 0, 4]);
+        assert_eq!(first[22..38], dmx.cid);
+        // framing layer
+        assert_eq!(u16::from_be_bytes([first[38], first[39]]), 0x7000 | 600);
+        assert_eq!(first[40..44], [0, 0, 0, 2]);
+        assert_eq!(&first[44..53], b"zync test");
+        assert!(first[53..108].iter().all(|byte| *byte == 0));
+        assert_eq!(first[108], 150);                    // priority
+        assert_eq!(first[113..115], [0, 7]);            // universe
+        // DMP layer
+        assert_eq!(u16::from_be_bytes([first[115], first[116]]), 0x7000 | 523);
+        assert_eq!(first[117..123], [0x02, 0xa1, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(first[123..125], [0x02, 0x01]);      // 513 values
+        assert_eq!(first[125], 0x00);                   // start code
+        // address 10 is slot 9, with the shared 50 moved onto white
+        assert_eq!(first[126 + 9..126 + 13], [150, 50, 0, 50]);
+
+        dmx.set(fixture, &[[0, 0, 0]]).unwrap();
+        dmx.flush(&udp).unwrap();
+        let second = receive(&receiver);
+        assert_eq!(second[111], first[111].wrapping_add(1));
+
+        // nothing is sent for universes that weren't written
+        dmx.flush(&udp).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        assert!(receiver.recv(&mut [0u8; 1024]).is_err());
+    }
+
+    #[test]
+    fn artnet_packets_follow_artdmx() {
+        let par = light(0x1234, 1, "grb");
+        let (dmx, udp, receiver) = output(DmxProtocol::Artnet, std::slice::from_ref(&par));
+        let fixture = par.dmx.as_ref().unwrap();
+
+        let mut sequences = Vec::new();
+        for _ in 0..2 {
+            dmx.set(fixture, &[[10, 20, 30]]).unwrap();
+            dmx.flush(&udp).unwrap();
+            let packet = receive(&receiver);
+
+            assert_eq!(packet.len(), 530);
+            assert_eq!(&packet[0..8], b"Art-Net\0");
+            assert_eq!(packet[8..10], [0x00, 0x50]);    // OpDmx, little endian
+            assert_eq!(packet[10..12], [0, 14]);        // protocol version
+            assert_eq!(packet[13], 0);
+            assert_eq!(packet[14..16], [0x34, 0x12]);   // sub-net and universe, then net
+            assert_eq!(packet[16..18], [0x02, 0x00]);   // 512 slots
+            assert_eq!(packet[18..21], [20, 10, 30]);
+            sequences.push(packet[12]);
+        }
+        assert_eq!(sequences, [1, 2]);
+    }
+}
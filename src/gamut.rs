@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::capture::ZoneColor;
+use crate::color::{rgb_to_hsv, to_xy};
+
+const WHITE_SATURATION: f32 = 0.15;     // colors less saturated than this are sent as color_temp in color_temp mode
+const MIN_MIREDS: f32 = 153.0;          // 6500K, the coolest white most Zigbee bulbs support
+const MAX_MIREDS: f32 = 500.0;          // 2000K, the warmest
+
+/// How colors are sent to a light. Rgb leaves the conversion to Zigbee2MQTT. Xy and hs are converted here, with xy
+/// clamped to the light's gamut. Color_temp sends near white colors as a color temperature and everything else as xy.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    #[default]
+    Rgb,
+    Xy,
+    Hs,
+    ColorTemp,
+}
+
+/// Color gamut triangles of Philips Hue bulbs. A is older LivingColors and LightStrips, B is first generation
+/// Hue bulbs, C is newer Hue and most other color bulbs.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Gamut {
+    A,
+    B,
+    C,
+}
+
+impl Gamut {
+    /// red, green, and blue corners in CIE xy
+    fn triangle(&self) -> [(f32, f32); 3] {
+        match self {
+            Gamut::A => [(0.704, 0.296), (0.2151, 0.7106), (0.138, 0.08)],
+            Gamut::B => [(0.675, 0.322), (0.409, 0.518), (0.167, 0.04)],
+            Gamut::C => [(0.692, 0.308), (0.17, 0.7), (0.153, 0.048)],
+        }
+    }
+
+    /// moves an xy point outside the gamut to the closest color the light can show
+    pub fn clamp(&self, point: (f32, f32)) -> (f32, f32) {
+        let [red, green, blue] = self.triangle();
+        if contains([red, green, blue], point) {
+            return point;
+        }
+
+        [(red, green), (green, blue), (blue, red)]
+            .into_iter()
+            .map(|(start, end)| closest_on_segment(start, end, point))
+            .min_by(|a, b| distance(*a, point).total_cmp(&distance(*b, point)))
+            .unwrap_or(point)
+    }
+}
+
+fn cross(o: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn contains(triangle: [(f32, f32); 3], point: (f32, f32)) -> bool {
+    let [a, b, c] = triangle;
+    let d1 = cross(a, b, point);
+    let d2 = cross(b, c, point);
+    let d3 = cross(c, a, point);
+    let negative = d1 < 0.0 || d2 < 0.0 || d3 < 0.0;
+    let positive = d1 > 0.0 || d2 > 0.0 || d3 > 0.0;
+    !(negative && positive)
+}
+
+fn closest_on_segment(start: (f32, f32), end: (f32, f32), point: (f32, f32)) -> (f32, f32) {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let t = (((point.0 - start.0) * dx + (point.1 - start.1) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+    (start.0 + t * dx, start.1 + t * dy)
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+/// color temperature in mireds of a white point, using McCamy's approximation of correlated color temperature
+fn xy_to_mireds((x, y): (f32, f32)) -> u16 {
+    let n = (x - 0.3320) / (0.1858 - y);
+    let kelvin = 449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33;
+    (1_000_000.0 / kelvin).clamp(MIN_MIREDS, MAX_MIREDS).round() as u16
+}
+
+/// rounded to 4 decimals, which is finer than bulbs resolve and keeps f32 noise out of the payload
+fn xy_json((x, y): (f32, f32)) -> Value {
+    let round = |value: f32| (value as f64 * 10_000.0).round() / 10_000.0;
+    json!({ "x": round(x), "y": round(y) })
+}
+
+impl ColorMode {
+    /// Zigbee2MQTT key and value that set a light to this color, e.g. ("color", {"x": .., "y": ..})
+    pub fn payload(&self, r: u8, g: u8, b: u8, gamut: Option<Gamut>) -> (&'static str, Value) {
+        let xy = || {
+            let xy = to_xy(&ZoneColor::new(r, g, b));
+            gamut.map_or(xy, |gamut| gamut.clamp(xy))
+        };
+
+        match self {
+            ColorMode::Rgb => ("color", json!({ "r": r, "g": g, "b": b })),
+            ColorMode::Xy => ("color", xy_json(xy())),
+            ColorMode::Hs => {
+                let (hue, saturation, _) = rgb_to_hsv(r, g, b);
+                ("color", json!({ "hue": hue.round(), "saturation": (saturation * 100.0).round() }))
+            }
+            ColorMode::ColorTemp => {
+                let (_, saturation, _) = rgb_to_hsv(r, g, b);
+                if saturation < WHITE_SATURATION {
+                    ("color_temp", json!(xy_to_mireds(to_xy(&ZoneColor::new(r, g, b)))))
+                } else {
+                    ("color", xy_json(xy()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMUTS: [Gamut; 3] = [Gamut::A, Gamut::B, Gamut::C];
+    const D65: (f32, f32) = (0.3127, 0.3290);
+
+    /// closest point on the triangle's edges, found by walking each edge in small steps
+    fn brute_force_closest(gamut: Gamut, point: (f32, f32)) -> f32 {
+        let [red, green, blue] = gamut.triangle();
+        [(red, green), (green, blue), (blue, red)].into_iter()
+            .flat_map(|(start, end)| (0..=10_000).map(move |i| {
+                let t = i as f32 / 10_000.0;
+                (start.0 + t * (end.0 - start.0), start.1 + t * (end.1 - start.1))
+            }))
+            .map(|edge| distance(edge, point))
+            .fold(f32::MAX, f32::min)
+    }
+
+    fn on_an_edge(gamut: Gamut, point: (f32, f32)) -> bool {
+        let [red, green, blue] = gamut.triangle();
+        [(red, green), (green, blue), (blue, red)].into_iter()
+            .any(|(start, end)| distance(closest_on_segment(start, end, point), point) < 1e-5)
+    }
+
+    #[test]
+    fn points_inside_the_gamut_are_unchanged() {
+        for gamut in GAMUTS {
+            let [red, green, blue] = gamut.triangle();
+            let centroid = ((red.0 + green.0 + blue.0) / 3.0, (red.1 + green.1 + blue.1) / 3.0);
+            assert_eq!(gamut.clamp(centroid), centroid);
+            // the corners themselves are in gamut
+            assert_eq!(gamut.clamp(red), red);
+        }
+        assert_eq!(Gamut::A.clamp(D65), D65);
+        assert_eq!(Gamut::C.clamp(D65), D65);
+    }
+
+    #[test]
+    fn gamut_b_sits_just_short_of_white() {
+        // gamut B's green to blue edge passes a hair inside D65, so white moves by less than bulbs can resolve
+        let clamped = Gamut::B.clamp(D65);
+        assert!(clamped != D65 && distance(clamped, D65) < 0.001);
+    }
+
+    #[test]
+    fn points_outside_the_gamut_move_to_the_nearest_edge() {
+        // beyond each edge, past each corner, and sRGB's green and blue primaries
+        let outside = [(0.75, 0.3), (0.3, 0.68), (0.1, 0.3), (0.4, 0.02), (0.8, 0.15), (0.05, 0.85), (0.3, 0.6), (0.15, 0.06)];
+        for gamut in GAMUTS {
+            for point in outside {
+                let [red, green, blue] = gamut.triangle();
+                if contains([red, green, blue], point) {
+                    continue;
+                }
+                let clamped = gamut.clamp(point);
+                assert!(on_an_edge(gamut, clamped), "{:?} {:?} -> {:?} is not on an edge", gamut, point, clamped);
+                let nearest = brute_force_closest(gamut, point);
+                assert!(distance(clamped, point) <= nearest + 1e-4, "{:?} {:?} -> {:?} is not the nearest edge point", gamut, point, clamped);
+            }
+        }
+    }
+
+    #[test]
+    fn gamuts_clamp_saturated_green_differently() {
+        // gamut B has the narrowest green, so green moves furthest
+        let green = to_xy(&ZoneColor::new(0, 255, 0));
+        let moved = |gamut: Gamut| distance(gamut.clamp(green), green);
+        assert!(moved(Gamut::B) > moved(Gamut::C));
+        assert_eq!(Gamut::A.clamp(green), green);
+    }
+
+    #[test]
+    fn black_maps_to_the_white_point() {
+        for gamut in [Gamut::A, Gamut::C] {
+            let (key, value) = ColorMode::Xy.payload(0, 0, 0, Some(gamut));
+            assert_eq!(key, "color");
+            assert_eq!(value, json!({ "x": 0.3127, "y": 0.329 }));
+        }
+        let (_, value) = ColorMode::Xy.payload(0, 0, 0, Some(Gamut::B));
+        assert!(value["x"].as_f64().unwrap().is_finite() && value["y"].as_f64().unwrap().is_finite());
+
+        let (key, value) = ColorMode::ColorTemp.payload(0, 0, 0, Some(Gamut::C));
+        assert_eq!(key, "color_temp");
+        let mireds = value.as_u64().unwrap() as f32;
+        assert!((MIN_MIREDS..=MAX_MIREDS).contains(&mireds));
+    }
+}
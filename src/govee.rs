@@ -0,0 +1,60 @@
+use serde::Deserialize;
+use serde_json::json;
+
+pub const PORT: u16 = 4003;
+
+/// Settings for a Govee light with the LAN API. Turn on LAN Control for the device in the Govee app first.
+#[derive(Deserialize, Debug, Clone)]
+pub struct GoveeConfig {
+    pub host: String,               // IP address of the light
+}
+
+/// turns the light on, sent once at startup
+pub fn turn_on() -> Vec<u8> {
+    message("turn", json!({ "value": 1 }))
+}
+
+/// colorwc with the color temperature left at 0, so the rgb color is used
+pub fn color([r, g, b]: [u8; 3]) -> Vec<u8> {
+    message("colorwc", json!({ "color": { "r": r, "g": g, "b": b }, "colorTemInKelvin": 0 }))
+}
+
+/// brightness is 0-255 like in Zigbee2MQTT payloads. Govee takes 1-100
+pub fn brightness(brightness: u8) -> Vec<u8> {
+    let value = (brightness as f32 / 255.0 * 100.0).round().max(1.0) as u8;
+    message("brightness", json!({ "value": value }))
+}
+
+fn message(cmd: &str, data: serde_json::Value) -> Vec<u8> {
+    json!({ "msg": { "cmd": cmd, "data": data } }).to_string().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn parse(message: Vec<u8>) -> Value {
+        serde_json::from_slice(&message).unwrap()
+    }
+
+    #[test]
+    fn color_is_colorwc_without_a_temperature() {
+        assert_eq!(parse(color([255, 128, 0])), json!({
+            "msg": { "cmd": "colorwc", "data": { "color": { "r": 255, "g": 128, "b": 0 }, "colorTemInKelvin": 0 } }
+        }));
+    }
+
+    #[test]
+    fn brightness_is_scaled_to_1_through_100() {
+        assert_eq!(parse(brightness(255))["msg"]["data"]["value"], 100);
+        assert_eq!(parse(brightness(128))["msg"]["data"]["value"], 50);
+        // 0 would turn the light off
+        assert_eq!(parse(brightness(0))["msg"]["data"]["value"], 1);
+    }
+
+    #[test]
+    fn turn_on_message() {
+        assert_eq!(parse(turn_on()), json!({ "msg": { "cmd": "turn", "data": { "value": 1 } } }));
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+use crate::lights::LightConfig;
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);     // a call without a result by then counts as a dropped connection
+
+/// Settings for driving ZHA lights through Home Assistant's WebSocket API
+#[derive(Deserialize, Clone)]
+pub struct HomeAssistantConfig {
+    pub url: String,                // e.g. ws://homeassistant.local:8123/api/websocket
+    pub token: String,              // long-lived access token from your Home Assistant profile page
+    #[serde(default = "default_max_rate")]
+    pub max_rate: f32,              // max service calls per second across all lights. HA and ZHA are slower than raw MQTT
+}
+
+fn default_max_rate() -> f32 { 10.0 }
+
+/// light.turn_on call for one entity
+struct TurnOn {
+    entity_id: String,
+    rgb: [u8; 3],
+    brightness: u8,
+    transition: f32,
+}
+
+/// Handle to the Home Assistant worker thread. Calls are queued and sent at most max_rate per second.
+/// When a light gets a new color before its last one was sent, only the newest is kept.
+pub struct HomeAssistantClient {
+    sender: Sender<TurnOn>,
+}
+
+impl HomeAssistantClient {
+    /// connects and authenticates before starting the worker, so a bad url or token fails at startup
+    pub fn connect(config: HomeAssistantConfig) -> Result<Self> {
+        let socket = open_socket(&config)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || Worker::new(config, socket).run(receiver));
+        Ok(HomeAssistantClient { sender })
+    }
+
+    pub fn turn_on(&self, entity_id: &str, rgb: [u8; 3], brightness: u8, transition: f32) -> Result<()> {
+        let call = TurnOn { entity_id: entity_id.to_string(), rgb, brightness, transition };
+        self.sender.send(call).map_err(|_| anyhow!("Home Assistant worker stopped"))
+    }
+}
+
+/// ZHA lights through Home Assistant
+pub struct HomeAssistantSink {
+    config: LightConfig,
+    client: Arc<HomeAssistantClient>,
+}
+
+impl HomeAssistantSink {
+    pub fn new(config: LightConfig, client: Arc<HomeAssistantClient>) -> Self {
+        HomeAssistantSink { config, client }
+    }
+}
+
+impl LightSink for HomeAssistantSink {
+    /// ZHA lights take a single color. segmented ZHA lights aren't supported, so only the first color is used
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let (rgb, brightness) = self.config.color(&updates[0].color);
+        self.client.turn_on(&entity_id(&self.config.light_name), rgb, brightness, updates[0].transition)
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: false, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+}
+
+/// light_name of a ZHA light is its entity id. the light domain can be left off
+pub fn entity_id(light_name: &str) -> String {
+    if light_name.contains('.') {
+        light_name.to_string()
+    } else {
+        format!("light.{}", light_name)
+    }
+}
+
+fn open_socket(config: &HomeAssistantConfig) -> Result<Socket> {
+    let (mut socket, _) = tungstenite::connect(config.url.as_str())
+        .with_context(|| format!("Failed to connect to Home Assistant at {}", config.url))?;
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?,
+        MaybeTlsStream::Rustls(stream) => stream.sock.set_read_timeout(Some(RESPONSE_TIMEOUT))?,
+        _ => {}
+    }
+
+    // HA opens with auth_required, then answers our token with auth_ok or auth_invalid
+    let greeting = read_json(&mut socket)?;
+    if greeting["type"] != "auth_required" {
+        bail!("Unexpected greeting from Home Assistant: {}", greeting);
+    }
+    send_json(&mut socket, json!({ "type": "auth", "access_token": config.token }))?;
+
+    let response = read_json(&mut socket)?;
+    match response["type"].as_str() {
+        Some("auth_ok") => Ok(socket),
+        Some("auth_invalid") => bail!("Home Assistant rejected the access token: {}", response["message"]),
+        _ => bail!("Unexpected auth response from Home Assistant: {}", response),
+    }
+}
+
+fn send_json(socket: &mut Socket, value: Value) -> Result<()> {
+    socket.send(Message::text(value.to_string())).context("Failed to send to Home Assistant")
+}
+
+/// next text message as json. pings are answered by tungstenite while reading
+fn read_json(socket: &mut Socket) -> Result<Value> {
+    loop {
+        let message = match socket.read() {
+            Err(tungstenite::Error::Io(error)) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                bail!("Home Assistant didn't answer within {:?}", RESPONSE_TIMEOUT)
+            }
+            result => result.context("Failed to read from Home Assistant")?,
+        };
+        match message {
+            Message::Text(text) => return serde_json::from_str(&text).context("Invalid message from Home Assistant"),
+            Message::Close(_) => bail!("Home Assistant closed the connection"),
+            _ => continue,
+        }
+    }
+}
+
+struct Worker {
+    config: HomeAssistantConfig,
+    socket: Option<Socket>,
+    next_id: u64,
+    order: VecDeque<String>,                // entities with a pending call, oldest first
+    pending: HashMap<String, TurnOn>,
+}
+
+impl Worker {
+    fn new(config: HomeAssistantConfig, socket: Socket) -> Self {
+        Worker {
+            config,
+            socket: Some(socket),
+            next_id: 1,
+            order: VecDeque::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn queue(&mut self, call: TurnOn) {
+        if !self.pending.contains_key(&call.entity_id) {
+            self.order.push_back(call.entity_id.clone());
+        }
+        self.pending.insert(call.entity_id.clone(), call);
+    }
+
+    /// runs until the client is dropped
+    fn run(mut self, receiver: Receiver<TurnOn>) {
+        let interval = Duration::from_secs_f32(1.0 / self.config.max_rate.max(0.1));
+        let mut next_send = Instant::now();
+
+        loop {
+            // block while idle, otherwise keep collecting calls until the next send slot
+            if self.order.is_empty() {
+                match receiver.recv() {
+                    Ok(call) => self.queue(call),
+                    Err(_) => return,
+                }
+            }
+            let now = Instant::now();
+            if now < next_send {
+                match receiver.recv_timeout(next_send - now) {
+                    Ok(call) => self.queue(call),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                continue;
+            }
+            while let Ok(call) = receiver.try_recv() {
+                self.queue(call);
+            }
+
+            let Some(entity_id) = self.order.pop_front() else { continue };
+            let Some(call) = self.pending.remove(&entity_id) else { continue };
+            next_send = Instant::now() + interval;
+
+            if let Err(error) = self.call(&call) {
+                println!("Home Assistant error: {:#}", error);
+                self.socket = None;
+                next_send = Instant::now() + RECONNECT_DELAY;
+            }
+        }
+    }
+
+    /// sends one light.turn_on and waits for its result, reconnecting first if the last call failed or timed out
+    fn call(&mut self, call: &TurnOn) -> Result<()> {
+        if self.socket.is_none() {
+            self.socket = Some(open_socket(&self.config)?);
+        }
+        let socket = self.socket.as_mut().context("Not connected to Home Assistant")?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        send_json(socket, json!({
+            "id": id,
+            "type": "call_service",
+            "domain": "light",
+            "service": "turn_on",
+            "service_data": {
+                "rgb_color": call.rgb,
+                "brightness": call.brightness,
+                "transition": (call.transition as f64 * 100.0).round() / 100.0,
+            },
+            "target": { "entity_id": call.entity_id },
+        }))?;
+
+        loop {
+            let message = read_json(socket)?;
+            if message["type"] != "result" || message["id"] != id {
+                continue;
+            }
+            if message["success"] != true {
+                println!("Home Assistant couldn't set {}: {}", call.entity_id, message["error"]["message"]);
+            }
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+
+    const TOKEN: &str = "test-token";
+
+    /// mock Home Assistant that checks the token and answers every call_service with a success result.
+    /// returns its url and the calls it receives
+    fn serve() -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/api/websocket", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut socket = tungstenite::accept(stream.unwrap()).unwrap();
+                let send = |socket: &mut WebSocket<TcpStream>, value: Value| socket.send(Message::text(value.to_string())).unwrap();
+                let read = |socket: &mut WebSocket<TcpStream>| -> Option<Value> {
+                    match socket.read().ok()? {
+                        Message::Text(text) => serde_json::from_str(&text).ok(),
+                        _ => None,
+                    }
+                };
+
+                send(&mut socket, json!({ "type": "auth_required", "ha_version": "2025.1.0" }));
+                let Some(auth) = read(&mut socket) else { continue };
+                if auth["type"] != "auth" || auth["access_token"] != TOKEN {
+                    send(&mut socket, json!({ "type": "auth_invalid", "message": "Invalid access token or password" }));
+                    continue;
+                }
+                send(&mut socket, json!({ "type": "auth_ok", "ha_version": "2025.1.0" }));
+
+                while let Some(call) = read(&mut socket) {
+                    send(&mut socket, json!({ "id": call["id"], "type": "result", "success": true, "result": null }));
+                    if sender.send(call).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    fn config(url: &str, token: &str, max_rate: f32) -> HomeAssistantConfig {
+        HomeAssistantConfig { url: url.to_string(), token: token.to_string(), max_rate }
+    }
+
+    #[test]
+    fn rejects_a_bad_token() {
+        let (url, _) = serve();
+        let error = HomeAssistantClient::connect(config(&url, "wrong", 10.0)).err().unwrap();
+        assert!(format!("{:#}", error).contains("rejected the access token"), "{:#}", error);
+    }
+
+    #[test]
+    fn sends_turn_on_calls() {
+        let (url, calls) = serve();
+        let client = HomeAssistantClient::connect(config(&url, TOKEN, 10.0)).unwrap();
+
+        client.turn_on(&entity_id("desk"), [255, 128, 0], 200, 0.333).unwrap();
+        let call = calls.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(call, json!({
+            "id": 1,
+            "type": "call_service",
+            "domain": "light",
+            "service": "turn_on",
+            "service_data": { "rgb_color": [255, 128, 0], "brightness": 200, "transition": 0.33 },
+            "target": { "entity_id": "light.desk" },
+        }));
+
+        client.turn_on("light.shelf", [0, 0, 255], 10, 1.0).unwrap();
+        let call = calls.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(call["id"], 2);
+        assert_eq!(call["target"]["entity_id"], "light.shelf");
+    }
+
+    #[test]
+    fn coalesces_updates_during_the_rate_limit() {
+        let (url, calls) = serve();
+        let client = HomeAssistantClient::connect(config(&url, TOKEN, 4.0)).unwrap();
+
+        client.turn_on("light.a", [1, 0, 0], 255, 0.0).unwrap();
+        let first = calls.recv_timeout(Duration::from_secs(2)).unwrap();
+        let first_time = Instant::now();
+        assert_eq!(first["service_data"]["rgb_color"], json!([1, 0, 0]));
+
+        // both arrive while the next slot is 250ms away. a is replaced by its newest color and keeps its place in line
+        client.turn_on("light.a", [2, 0, 0], 255, 0.0).unwrap();
+        client.turn_on("light.b", [9, 0, 0], 255, 0.0).unwrap();
+        client.turn_on("light.a", [3, 0, 0], 255, 0.0).unwrap();
+
+        let second = calls.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(first_time.elapsed() >= Duration::from_millis(200), "sent after {:?}", first_time.elapsed());
+        let third = calls.recv_timeout(Duration::from_secs(2)).unwrap();
+        let sent: Vec<(Value, Value)> = [second, third].iter()
+            .map(|call| (call["target"]["entity_id"].clone(), call["service_data"]["rgb_color"].clone()))
+            .collect();
+        assert_eq!(sent, [(json!("light.a"), json!([3, 0, 0])), (json!("light.b"), json!([9, 0, 0]))]);
+        assert!(calls.recv_timeout(Duration::from_millis(600)).is_err());
+    }
+}
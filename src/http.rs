@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use ureq::Agent;
+
+use crate::lights::LightConfig;
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    #[default]
+    Post,
+    Put,
+}
+
+/// Where an HTTP light's colors are sent. Each color is a JSON body like Zigbee2MQTT's set payload.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpLight {
+    pub url: String,                // e.g. http://192.168.1.60/json/light
+    #[serde(default)]
+    pub method: HttpMethod,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,  // extra request headers, e.g. Authorization
+}
+
+/// one JSON body for one light
+struct HttpRequest {
+    light_name: String,
+    light: HttpLight,
+    body: Value,
+}
+
+/// Handle to the HTTP worker thread. Requests go out one at a time so slow devices don't hold up the frame,
+/// and when a light gets a new color before its last one was sent, only the newest is kept.
+pub struct HttpClient {
+    sender: Sender<HttpRequest>,
+}
+
+impl HttpClient {
+    pub fn start() -> Self {
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .build()
+            .into();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || Worker::new(agent).run(receiver));
+        HttpClient { sender }
+    }
+
+    pub fn send(&self, light_name: &str, light: &HttpLight, body: Value) -> Result<()> {
+        let request = HttpRequest { light_name: light_name.to_string(), light: light.clone(), body };
+        self.sender.send(request).map_err(|_| anyhow!("HTTP worker stopped"))
+    }
+}
+
+/// A light set with a JSON request per color change
+pub struct HttpSink {
+    config: LightConfig,
+    client: Arc<HttpClient>,
+}
+
+impl HttpSink {
+    pub fn new(config: LightConfig, client: Arc<HttpClient>) -> Self {
+        HttpSink { config, client }
+    }
+
+    /// brightness and color in the light's color_mode. segmented lights get a segments array with one of these each
+    fn body(&self, updates: &[ZoneUpdate]) -> Value {
+        let color = |update: &ZoneUpdate| {
+            let ([r, g, b], brightness) = self.config.color(&update.color);
+            let (color_key, color_value) = self.config.color_mode.payload(r, g, b, self.config.gamut);
+            let mut color = json!({ "brightness": brightness });
+            color[color_key] = color_value;
+            color
+        };
+        // segments share one transition, so use the fastest
+        let transition = updates.iter().map(|update| update.transition).fold(f32::MAX, f32::min);
+
+        let mut body = match self.config.is_segmented() {
+            true => json!({ "segments": updates.iter().map(color).collect::<Vec<Value>>() }),
+            false => color(&updates[0]),
+        };
+        body["state"] = json!("ON");
+        body["transition"] = json!((transition as f64 * 100.0).round() / 100.0);
+        body
+    }
+}
+
+impl LightSink for HttpSink {
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let light = self.config.http.as_ref()
+            .with_context(|| format!("HTTP light {} has no http section", self.config.light_name))?;
+        self.client.send(&self.config.light_name, light, self.body(updates))
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: true, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+}
+
+struct Worker {
+    agent: Agent,
+    order: VecDeque<String>,                // lights with a pending request, oldest first
+    pending: HashMap<String, HttpRequest>,
+}
+
+impl Worker {
+    fn new(agent: Agent) -> Self {
+        Worker { agent, order: VecDeque::new(), pending: HashMap::new() }
+    }
+
+    fn queue(&mut self, request: HttpRequest) {
+        if !self.pending.contains_key(&request.light_name) {
+            self.order.push_back(request.light_name.clone());
+        }
+        self.pending.insert(request.light_name.clone(), request);
+    }
+
+    /// runs until the client is dropped
+    fn run(mut self, receiver: Receiver<HttpRequest>) {
+        loop {
+            if self.order.is_empty() {
+                match receiver.recv() {
+                    Ok(request) => self.queue(request),
+                    Err(_) => return,
+                }
+            }
+            while let Ok(request) = receiver.try_recv() {
+                self.queue(request);
+            }
+
+            let Some(light_name) = self.order.pop_front() else { continue };
+            let Some(request) = self.pending.remove(&light_name) else { continue };
+            if let Err(error) = self.request(&request) {
+                println!("HTTP error for {}: {:#}", light_name, error);
+            }
+        }
+    }
+
+    fn request(&self, request: &HttpRequest) -> Result<()> {
+        let url = request.light.url.as_str();
+        let mut builder = match request.light.method {
+            HttpMethod::Post => self.agent.post(url),
+            HttpMethod::Put => self.agent.put(url),
+        };
+        for (name, value) in &request.light.headers {
+            builder = builder.header(name, value);
+        }
+        builder
+            .content_type("application/json")
+            .send(request.body.to_string())
+            .with_context(|| format!("Failed to send to {}", url))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    use crate::lights::MessageColor;
+
+    /// request line, headers, and JSON body
+    type Received = (String, HashMap<String, String>, Value);
+
+    /// mock device answering every request with 200. returns its address and the requests it receives
+    fn serve() -> (String, Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let mut headers = HashMap::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let Some((name, value)) = line.trim_end().split_once(": ") else { break };
+                        headers.insert(name.to_lowercase(), value.to_string());
+                    }
+                    let mut body = vec![0u8; headers["content-length"].parse().unwrap()];
+                    reader.read_exact(&mut body).unwrap();
+                    reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+                    sender.send((request_line.trim_end().to_string(), headers, serde_json::from_slice(&body).unwrap())).unwrap();
+                }
+            }
+        });
+        (address, receiver)
+    }
+
+    fn sink(yaml: &str) -> HttpSink {
+        HttpSink::new(serde_yaml::from_str(yaml).unwrap(), Arc::new(HttpClient::start()))
+    }
+
+    fn update(r: u8, g: u8, b: u8, transition: f32) -> ZoneUpdate {
+        ZoneUpdate { color: MessageColor::new(r, g, b, 200), transition }
+    }
+
+    #[test]
+    fn sends_json_with_the_configured_method_and_headers() {
+        let (address, requests) = serve();
+        let mut sink = sink(&format!("{{ service: HTTP, light_name: desk, brightness: 0.5, \
+            http: {{ url: 'http://{}/json/light', method: put, headers: {{ Authorization: Bearer secret }} }} }}", address));
+
+        sink.send(&[update(255, 128, 0, 0.333)]).unwrap();
+        let (request_line, headers, body) = requests.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(request_line, "PUT /json/light HTTP/1.1");
+        assert_eq!(headers["authorization"], "Bearer secret");
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(body, json!({ "state": "ON", "brightness": 100, "transition": 0.33, "color": { "r": 255, "g": 128, "b": 0 } }));
+    }
+
+    #[test]
+    fn segmented_lights_get_one_color_per_segment() {
+        let sink = sink("{ service: HTTP, light_name: strip, brightness: 1.0, segments: [left, right], color_mode: xy, \
+            http: { url: 'http://127.0.0.1:1/' } }");
+        assert_eq!(sink.config.http.as_ref().unwrap().method, HttpMethod::Post);
+
+        let body = sink.body(&[update(255, 0, 0, 0.5), update(0, 0, 255, 0.2)]);
+        assert_eq!(body["state"], "ON");
+        assert_eq!(body["transition"], 0.2);
+        let segments = body["segments"].as_array().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|segment| segment["brightness"] == 200 && segment["color"]["x"].is_number()));
+        assert!(segments[0]["color"]["x"].as_f64() > segments[1]["color"]["x"].as_f64());
+    }
+
+    #[test]
+    fn only_the_newest_color_is_kept_while_a_request_is_in_flight() {
+        let (address, requests) = serve();
+        let mut sink = sink(&format!("{{ service: HTTP, light_name: desk, brightness: 1.0, http: {{ url: 'http://{}/' }} }}", address));
+
+        for value in 0..=200 {
+            sink.send(&[update(value, 0, 0, 0.0)]).unwrap();
+        }
+        let mut received = Vec::new();
+        while let Ok((_, _, body)) = requests.recv_timeout(Duration::from_millis(500)) {
+            received.push(body["color"]["r"].as_u64().unwrap());
+        }
+        assert!(received.len() < 201);
+        assert_eq!(received.last(), Some(&200));
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,723 @@
+use std::io::{self, Read, Write};
+use std::net::UdpSocket;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result, bail};
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVersion};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use ureq::Agent;
+use ureq::tls::TlsConfig;
+
+use crate::capture::ScreenArea;
+use crate::lights::LightConfig;
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+const STREAM_PORT: u16 = 2100;
+const STREAM_CIPHER: &str = "PSK-AES128-GCM-SHA256";
+const DTLS_MTU: u32 = 1400;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const STREAM_RATES: RangeInclusive<u32> = 25..=50;     // the bridge expects 25-50 packets per second
+
+/// Settings for streaming to a Hue bridge with the Entertainment API. The application key and client key come from
+/// pairing with the bridge (POST /api with {"devicetype": "zync", "generateclientkey": true} after pressing the link button).
+#[derive(Deserialize, Clone)]
+pub struct HueConfig {
+    pub bridge: String,                 // bridge IP address
+    pub application_key: String,        // "username" from pairing
+    pub client_key: String,             // "clientkey" from pairing, 32 hex characters
+    #[serde(default = "default_stream_rate")]
+    pub stream_rate: u32,               // packets per second, 25-50. colors are resent at this rate even when unchanged
+}
+
+fn default_stream_rate() -> u32 { 50 }
+
+/// An entertainment channel and its position in the room. x is left (-1) to right (1), y is the back of the
+/// room (-1) to the screen (1), z is the floor (-1) to the ceiling (1).
+#[derive(Debug, Clone, Copy)]
+pub struct HueChannel {
+    pub id: u8,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// An active entertainment configuration with a DTLS stream to the bridge. Colors are set per channel and
+/// resent by the stream thread at the stream rate. Streaming stops when this is dropped.
+pub struct HueEntertainment {
+    rest: HueRest,
+    configuration_id: String,
+    channels: Vec<HueChannel>,
+    colors: Arc<Mutex<Vec<[u16; 3]>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl HueEntertainment {
+    /// activates the named entertainment configuration, creating it if the bridge doesn't have one by that name,
+    /// and starts streaming
+    pub fn start(config: HueConfig, name: &str) -> Result<Self> {
+        Self::start_with(HueRest::new(&config), config, name, STREAM_PORT)
+    }
+
+    /// start with the REST API and stream port given, so tests can point them at a local bridge
+    fn start_with(rest: HueRest, config: HueConfig, name: &str, stream_port: u16) -> Result<Self> {
+        let configuration = match rest.find_configuration(name)? {
+            Some(configuration) => configuration,
+            None => {
+                println!("Creating Hue entertainment configuration {}. Adjust light positions in the Hue app.", name);
+                rest.create_configuration(name)?
+            }
+        };
+        let configuration_id = configuration["id"].as_str().context("Entertainment configuration has no id")?.to_string();
+        let channels = parse_channels(&configuration);
+        if channels.is_empty() {
+            bail!("Hue entertainment configuration {} has no channels", name);
+        }
+
+        rest.set_action(&configuration_id, "start")?;
+
+        let colors = Arc::new(Mutex::new(vec![[0; 3]; channels.len()]));
+        let running = Arc::new(AtomicBool::new(true));
+        // don't leave the configuration active without a stream
+        let stream = connect(&config, stream_port)
+            .inspect_err(|_| { let _ = rest.set_action(&configuration_id, "stop"); })?;
+
+        let mut streamer = Streamer {
+            config,
+            rest: rest.clone(),
+            stream_port,
+            configuration_id: configuration_id.clone(),
+            channel_ids: channels.iter().map(|channel| channel.id).collect(),
+            colors: Arc::clone(&colors),
+            running: Arc::clone(&running),
+        };
+        thread::spawn(move || streamer.run(stream));
+
+        Ok(HueEntertainment { rest, configuration_id, channels, colors, running })
+    }
+
+    pub fn channels(&self) -> &[HueChannel] {
+        &self.channels
+    }
+
+    /// sets 16 bit rgb colors, one per channel in channel order
+    pub fn set_colors(&self, colors: &[[u16; 3]]) {
+        let mut current = self.colors.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (current, color) in current.iter_mut().zip(colors) {
+            *current = *color;
+        }
+    }
+}
+
+impl Drop for HueEntertainment {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.rest.set_action(&self.configuration_id, "stop");
+    }
+}
+
+/// The light streaming to the bridge's entertainment configuration
+pub struct HueSink {
+    config: LightConfig,
+    hue: Arc<HueEntertainment>,
+}
+
+impl HueSink {
+    pub fn new(config: LightConfig, hue: Arc<HueEntertainment>) -> Self {
+        HueSink { config, hue }
+    }
+}
+
+impl LightSink for HueSink {
+    /// one color per entertainment channel. the stream has no transitions or brightness, so brightness is folded into
+    /// 16 bit rgb and the bridge's own smoothing stands in for transitions
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let channels: Vec<[u16; 3]> = updates.iter()
+            .map(|update| self.config.dimmed(&update.color).map(|value| (value * 65535.0).round() as u16))
+            .collect();
+        self.hue.set_colors(&channels);
+        Ok(())
+    }
+
+    /// the streamer thread sends the latest colors at the stream rate, so updates are only needed on changes
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: true, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+}
+
+/// For each channel, the zone closest to it when the screen is laid over the front wall: zone x maps to channel x
+/// and zone height maps to channel z. Screen size is taken from the zones' bounding box. zones must not be empty.
+pub fn nearest_zones(channels: &[HueChannel], zones: &[ScreenArea]) -> Vec<usize> {
+    let width = zones.iter().map(|zone| zone.x + zone.width).max().unwrap_or(1).max(1) as f32;
+    let height = zones.iter().map(|zone| zone.y + zone.height).max().unwrap_or(1).max(1) as f32;
+
+    let positions: Vec<(f32, f32)> = zones.iter()
+        .map(|zone| {
+            let center_x = zone.x as f32 + zone.width as f32 / 2.0;
+            let center_y = zone.y as f32 + zone.height as f32 / 2.0;
+            (2.0 * center_x / width - 1.0, 1.0 - 2.0 * center_y / height)
+        })
+        .collect();
+
+    channels.iter()
+        .map(|channel| {
+            let distance = |(x, z): (f32, f32)| (x - channel.x).hypot(z - channel.z);
+            (0..positions.len())
+                .min_by(|a, b| distance(positions[*a]).total_cmp(&distance(positions[*b])))
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+fn parse_channels(configuration: &Value) -> Vec<HueChannel> {
+    configuration["channels"].as_array().into_iter().flatten()
+        .filter_map(|channel| {
+            let position = &channel["position"];
+            Some(HueChannel {
+                id: channel["channel_id"].as_u64()? as u8,
+                x: position["x"].as_f64().unwrap_or(0.0) as f32,
+                y: position["y"].as_f64().unwrap_or(0.0) as f32,
+                z: position["z"].as_f64().unwrap_or(0.0) as f32,
+            })
+        })
+        .collect()
+}
+
+/// CLIP v2 REST API of the bridge. The bridge uses a self signed certificate, so it isn't verified.
+#[derive(Clone)]
+struct HueRest {
+    agent: Agent,
+    base_url: String,
+    application_key: String,
+}
+
+impl HueRest {
+    fn new(config: &HueConfig) -> Self {
+        let agent: Agent = Agent::config_builder()
+            .tls_config(TlsConfig::builder().disable_verification(true).build())
+            .timeout_global(Some(TIMEOUT))
+            .http_status_as_error(false)
+            .build()
+            .into();
+        HueRest {
+            agent,
+            base_url: format!("https://{}/clip/v2/resource", config.bridge),
+            application_key: config.application_key.clone(),
+        }
+    }
+
+    /// data array of a CLIP v2 response. errors reported by the bridge become an error here
+    fn data(response: ureq::http::Response<ureq::Body>, path: &str) -> Result<Vec<Value>> {
+        let body = response.into_body().read_to_string()
+            .with_context(|| format!("Failed to read Hue bridge response for {}", path))?;
+        let response: Value = serde_json::from_str(&body)
+            .with_context(|| format!("Invalid Hue bridge response for {}", path))?;
+
+        let errors: Vec<&str> = response["errors"].as_array().into_iter().flatten()
+            .filter_map(|error| error["description"].as_str())
+            .collect();
+        if !errors.is_empty() {
+            bail!("Hue bridge error for {}: {}", path, errors.join(", "));
+        }
+        Ok(response["data"].as_array().cloned().unwrap_or_default())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<Value>> {
+        let response = self.agent.get(format!("{}/{}", self.base_url, path))
+            .header("hue-application-key", &self.application_key)
+            .call()
+            .with_context(|| format!("Failed to reach Hue bridge at {}", self.base_url))?;
+        Self::data(response, path)
+    }
+
+    fn send(&self, method: &str, path: &str, body: Value) -> Result<Vec<Value>> {
+        let url = format!("{}/{}", self.base_url, path);
+        let request = match method {
+            "POST" => self.agent.post(url),
+            _ => self.agent.put(url),
+        };
+        let response = request
+            .header("hue-application-key", &self.application_key)
+            .content_type("application/json")
+            .send(body.to_string())
+            .with_context(|| format!("Failed to reach Hue bridge at {}", self.base_url))?;
+        Self::data(response, path)
+    }
+
+    fn find_configuration(&self, name: &str) -> Result<Option<Value>> {
+        let configurations = self.get("entertainment_configuration")?;
+        Ok(configurations.into_iter().find(|configuration| configuration["metadata"]["name"] == name))
+    }
+
+    /// screen configuration with every entertainment capable light, spread left to right at screen height
+    fn create_configuration(&self, name: &str) -> Result<Value> {
+        let services: Vec<String> = self.get("entertainment")?.into_iter()
+            .filter(|service| service["renderer"] == true)
+            .filter_map(|service| service["id"].as_str().map(str::to_string))
+            .collect();
+        if services.is_empty() {
+            bail!("No entertainment capable lights found on the Hue bridge");
+        }
+
+        let spacing = 2.0 / services.len() as f32;
+        let locations: Vec<Value> = services.iter().enumerate()
+            .map(|(i, id)| json!({
+                "service": { "rid": id, "rtype": "entertainment" },
+                "positions": [{ "x": -1.0 + spacing * (i as f32 + 0.5), "y": 0.8, "z": 0.0 }],
+            }))
+            .collect();
+
+        let created = self.send("POST", "entertainment_configuration", json!({
+            "type": "entertainment_configuration",
+            "metadata": { "name": name },
+            "configuration_type": "screen",
+            "locations": { "service_locations": locations },
+        }))?;
+        let id = created.first().and_then(|reference| reference["rid"].as_str())
+            .context("Hue bridge didn't return the new entertainment configuration")?;
+
+        self.get(&format!("entertainment_configuration/{}", id))?
+            .into_iter()
+            .next()
+            .context("New entertainment configuration not found on the Hue bridge")
+    }
+
+    fn set_action(&self, id: &str, action: &str) -> Result<()> {
+        self.send("PUT", &format!("entertainment_configuration/{}", id), json!({ "action": action }))?;
+        Ok(())
+    }
+}
+
+/// connected UDP socket as a stream for openssl. every write is one datagram
+#[derive(Debug)]
+struct UdpStream(UdpSocket);
+
+impl Read for UdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for UdpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// DTLS 1.2 session with the bridge, using the application key as PSK identity and the client key as PSK
+fn connect(config: &HueConfig, port: u16) -> Result<SslStream<UdpStream>> {
+    let psk = decode_hex(&config.client_key).context("Hue client_key must be hex")?;
+    let mut identity = config.application_key.clone().into_bytes();
+    identity.push(0);
+
+    let mut context = SslContext::builder(SslMethod::dtls_client())?;
+    context.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+    context.set_cipher_list(STREAM_CIPHER)?;
+    context.set_options(SslOptions::NO_QUERY_MTU);
+    context.set_psk_client_callback(move |_, _, identity_out, psk_out| {
+        identity_out[..identity.len()].copy_from_slice(&identity);
+        psk_out[..psk.len()].copy_from_slice(&psk);
+        Ok(psk.len())
+    });
+    let context = context.build();
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((config.bridge.as_str(), port))
+        .with_context(|| format!("Failed to reach Hue bridge at {}", config.bridge))?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+
+    let mut ssl = Ssl::new(&context)?;
+    ssl.set_mtu(DTLS_MTU)?;
+    ssl.connect(UdpStream(socket))
+        .map_err(|error| anyhow::anyhow!("DTLS handshake with Hue bridge failed: {}", error))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    // checked first so slicing below can't split a multi-byte character
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        bail!("invalid hex character");
+    }
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of hex characters");
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("invalid hex character"))
+        .collect()
+}
+
+/// sends the current channel colors at the stream rate, reconnecting if the session drops
+struct Streamer {
+    config: HueConfig,
+    rest: HueRest,
+    stream_port: u16,
+    configuration_id: String,
+    channel_ids: Vec<u8>,
+    colors: Arc<Mutex<Vec<[u16; 3]>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Streamer {
+    fn run(&mut self, stream: SslStream<UdpStream>) {
+        let interval = self.interval();
+        let mut stream = Some(stream);
+        let mut sequence: u8 = 0;
+
+        while self.running.load(Ordering::Relaxed) {
+            let Some(session) = stream.as_mut() else {
+                thread::sleep(RECONNECT_DELAY);
+                // the bridge deactivates the configuration when the stream times out, so start it again first
+                stream = self.rest.set_action(&self.configuration_id, "start")
+                    .and_then(|_| connect(&self.config, self.stream_port))
+                    .inspect_err(|error| println!("Hue stream reconnect failed: {:#}", error))
+                    .ok();
+                continue;
+            };
+
+            let message = self.message(sequence);
+            sequence = sequence.wrapping_add(1);
+            if let Err(error) = session.write_all(&message) {
+                println!("Hue stream error: {}", error);
+                stream = None;
+                continue;
+            }
+            thread::sleep(interval);
+        }
+
+        if let Some(mut session) = stream {
+            let _ = session.shutdown();
+        }
+    }
+
+    /// time between packets, with the stream rate held to what the bridge accepts
+    fn interval(&self) -> Duration {
+        let rate = self.config.stream_rate.clamp(*STREAM_RATES.start(), *STREAM_RATES.end());
+        Duration::from_secs(1) / rate
+    }
+
+    /// HueStream v2 message: header, entertainment configuration id, then 16 bit rgb per channel
+    fn message(&self, sequence: u8) -> Vec<u8> {
+        let colors = self.colors.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+
+        let mut message = Vec::with_capacity(52 + 7 * colors.len());
+        message.extend_from_slice(b"HueStream");
+        message.extend_from_slice(&[0x02, 0x00, sequence, 0x00, 0x00, 0x00, 0x00]);     // version 2.0, sequence, reserved, rgb, reserved
+        message.extend_from_slice(self.configuration_id.as_bytes());
+        for (id, [r, g, b]) in self.channel_ids.iter().zip(colors) {
+            message.push(*id);
+            message.extend_from_slice(&r.to_be_bytes());
+            message.extend_from_slice(&g.to_be_bytes());
+            message.extend_from_slice(&b.to_be_bytes());
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    const CONFIGURATION_ID: &str = "1a8d99cc-967b-44f2-9202-43f976c0fa6b";
+    const APPLICATION_KEY: &str = "zync-test-key";
+    const PSK: [u8; 16] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32, 0x10];
+
+    /// method, path below /clip/v2/resource, and JSON body of a request to the mock bridge
+    type Request = (String, String, Value);
+
+    fn config() -> HueConfig {
+        HueConfig {
+            bridge: "127.0.0.1".to_string(),
+            application_key: APPLICATION_KEY.to_string(),
+            client_key: "0123456789abcdeffedcba9876543210".to_string(),
+            stream_rate: 50,
+        }
+    }
+
+    /// self signed certificate like the bridge's
+    fn acceptor() -> SslAcceptor {
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "hue bridge").unwrap();
+        let name = name.build();
+        let mut certificate = X509Builder::new().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        certificate.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&certificate.build()).unwrap();
+        acceptor.build()
+    }
+
+    /// mock bridge serving CLIP v2 over HTTPS. it has two entertainment capable lights and no entertainment
+    /// configurations until one is created. returns the REST client pointed at it and the requests it receives
+    fn rest_bridge() -> (HueRest, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut rest = HueRest::new(&config());
+        rest.base_url = format!("https://{}/clip/v2/resource", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let acceptor = acceptor();
+            let mut configuration: Option<Value> = None;
+            for stream in listener.incoming() {
+                let Ok(stream) = acceptor.accept(stream.unwrap()) else { continue };
+                let mut reader = BufReader::new(stream);
+                loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let mut headers = HashMap::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let Some((name, value)) = line.trim_end().split_once(": ") else { break };
+                        headers.insert(name.to_lowercase(), value.to_string());
+                    }
+                    let mut body = vec![0u8; headers.get("content-length").map_or(0, |length| length.parse().unwrap())];
+                    reader.read_exact(&mut body).unwrap();
+                    let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+
+                    let mut parts = request_line.split(' ');
+                    let method = parts.next().unwrap().to_string();
+                    let path = parts.next().unwrap().trim_start_matches("/clip/v2/resource/").to_string();
+
+                    let data = match (method.as_str(), path.as_str()) {
+                        _ if headers.get("hue-application-key").map(String::as_str) != Some(APPLICATION_KEY) => None,
+                        ("GET", "entertainment_configuration") => Some(json!(configuration.iter().collect::<Vec<_>>())),
+                        ("GET", "entertainment") => Some(json!([
+                            { "id": "light-1", "renderer": true },
+                            { "id": "bridge", "renderer": false },
+                            { "id": "light-2", "renderer": true },
+                        ])),
+                        ("POST", "entertainment_configuration") => {
+                            let channels: Vec<Value> = body["locations"]["service_locations"].as_array().unwrap().iter().enumerate()
+                                .map(|(i, location)| json!({ "channel_id": i, "position": location["positions"][0] }))
+                                .collect();
+                            configuration = Some(json!({ "id": CONFIGURATION_ID, "metadata": body["metadata"], "channels": channels }));
+                            Some(json!([{ "rid": CONFIGURATION_ID, "rtype": "entertainment_configuration" }]))
+                        }
+                        ("GET", path) if path.ends_with(CONFIGURATION_ID) => Some(json!(configuration.iter().collect::<Vec<_>>())),
+                        ("PUT", path) if path.ends_with(CONFIGURATION_ID) => Some(json!([{ "rid": CONFIGURATION_ID }])),
+                        _ => None,
+                    };
+                    let response = match data {
+                        Some(data) => json!({ "errors": [], "data": data }),
+                        None => json!({ "errors": [{ "description": "resource not available" }], "data": [] }),
+                    };
+                    // recorded before answering, so the request is in the channel by the time the call returns
+                    let _ = sender.send((method, path, body));
+                    let response = response.to_string();
+                    let reply = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}", response.len(), response);
+                    if reader.get_mut().write_all(reply.as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        (rest, receiver)
+    }
+
+    /// mock bridge entertainment stream. accepts one DTLS session with the PSK from pairing, returns the first `count`
+    /// messages, then closes
+    fn stream_bridge(count: usize) -> (u16, Receiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_cipher_list(STREAM_CIPHER).unwrap();
+            context.set_psk_server_callback(|_, identity, psk_out| {
+                if identity != Some(APPLICATION_KEY.as_bytes()) {
+                    return Ok(0);
+                }
+                psk_out[..PSK.len()].copy_from_slice(&PSK);
+                Ok(PSK.len())
+            });
+            let context = context.build();
+
+            // the session is with whoever sends the first handshake packet
+            let (_, peer) = socket.peek_from(&mut [0u8; 2048]).unwrap();
+            socket.connect(peer).unwrap();
+            socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+            let mut session = Ssl::new(&context).unwrap().accept(UdpStream(socket)).unwrap();
+            for _ in 0..count {
+                let mut message = [0u8; 2048];
+                let Ok(length) = session.read(&mut message) else { return };
+                if sender.send(message[..length].to_vec()).is_err() {
+                    return;
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    /// actions sent to the entertainment configuration, waiting up to the timeout for the next request each time
+    fn actions(requests: &Receiver<Request>, timeout: Duration) -> Vec<String> {
+        let mut actions = Vec::new();
+        while let Ok((method, _, body)) = requests.recv_timeout(timeout) {
+            if method == "PUT" {
+                actions.push(body["action"].as_str().unwrap().to_string());
+            }
+        }
+        actions
+    }
+
+    fn streamer(channel_ids: Vec<u8>, colors: Vec<[u16; 3]>) -> Streamer {
+        let config = HueConfig { bridge: "127.0.0.1".to_string(), application_key: String::new(), client_key: String::new(), stream_rate: 50 };
+        Streamer {
+            rest: HueRest::new(&config),
+            stream_port: STREAM_PORT,
+            config,
+            configuration_id: CONFIGURATION_ID.to_string(),
+            channel_ids,
+            colors: Arc::new(Mutex::new(colors)),
+            running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    #[test]
+    fn message_follows_the_huestream_v2_layout() {
+        let message = streamer(vec![0, 3], vec![[0xffff, 0x8000, 0x0001], [0x1234, 0, 0xabcd]]).message(7);
+
+        assert_eq!(message.len(), 52 + 2 * 7);
+        assert_eq!(&message[..9], b"HueStream");
+        assert_eq!(message[9..11], [2, 0]);              // version 2.0
+        assert_eq!(message[11], 7);                      // sequence
+        assert_eq!(message[12..14], [0, 0]);
+        assert_eq!(message[14], 0);                      // rgb color space
+        assert_eq!(message[15], 0);
+        assert_eq!(&message[16..52], CONFIGURATION_ID.as_bytes());
+        assert_eq!(message[52..59], [0, 0xff, 0xff, 0x80, 0x00, 0x00, 0x01]);
+        assert_eq!(message[59..66], [3, 0x12, 0x34, 0x00, 0x00, 0xab, 0xcd]);
+    }
+
+    #[test]
+    fn stream_rate_stays_within_what_the_bridge_accepts() {
+        let interval = |stream_rate: u32| {
+            let mut streamer = streamer(Vec::new(), Vec::new());
+            streamer.config.stream_rate = stream_rate;
+            streamer.interval()
+        };
+        assert_eq!(interval(1), Duration::from_millis(40));
+        assert_eq!(interval(25), Duration::from_millis(40));
+        assert_eq!(interval(50), Duration::from_millis(20));
+        assert_eq!(interval(120), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn channels_pick_the_nearest_zone() {
+        let zones = [
+            ScreenArea { x: 0, y: 0, width: 960, height: 540 },         // top left
+            ScreenArea { x: 960, y: 0, width: 960, height: 540 },       // top right
+            ScreenArea { x: 0, y: 540, width: 1920, height: 540 },      // bottom
+        ];
+        let channels = [
+            HueChannel { id: 0, x: -0.8, y: 1.0, z: 0.6 },
+            HueChannel { id: 1, x: 0.9, y: 1.0, z: 0.5 },
+            HueChannel { id: 2, x: 0.1, y: -1.0, z: -0.7 },
+        ];
+        assert_eq!(nearest_zones(&channels, &zones), [0, 1, 2]);
+    }
+
+    #[test]
+    fn client_key_must_be_hex() {
+        assert_eq!(decode_hex("0aFf").unwrap(), [0x0a, 0xff]);
+        assert!(decode_hex("0a0").is_err());
+        assert!(decode_hex("zz").is_err());
+        // a multi-byte character straddling a pair is an error, not a panic
+        assert!(decode_hex("aé0").is_err());
+    }
+
+    #[test]
+    fn start_creates_a_configuration_and_streams_to_it() {
+        let (rest, requests) = rest_bridge();
+        let (stream_port, messages) = stream_bridge(1000);
+
+        let hue = HueEntertainment::start_with(rest, config(), "zync", stream_port).unwrap();
+        let xs: Vec<f32> = hue.channels().iter().map(|channel| channel.x).collect();
+        assert_eq!(xs, [-0.5, 0.5]);
+
+        let (method, path, _) = requests.recv().unwrap();
+        assert_eq!((method.as_str(), path.as_str()), ("GET", "entertainment_configuration"));
+        assert_eq!(requests.recv().unwrap().1, "entertainment");
+        let (method, _, body) = requests.recv().unwrap();
+        assert_eq!(method, "POST");
+        assert_eq!(body["metadata"]["name"], "zync");
+        assert_eq!(body["locations"]["service_locations"][1]["service"]["rid"], "light-2");
+        assert_eq!(requests.recv().unwrap().1, format!("entertainment_configuration/{}", CONFIGURATION_ID));
+        assert_eq!(requests.recv().unwrap().2, json!({ "action": "start" }));
+
+        hue.set_colors(&[[0xffff, 0, 0], [0, 0, 0x1234]]);
+        let colored = loop {
+            let message = messages.recv_timeout(TIMEOUT).unwrap();
+            assert_eq!(&message[..9], b"HueStream");
+            assert_eq!(&message[16..52], CONFIGURATION_ID.as_bytes());
+            if message[53..55] == [0xff, 0xff] {
+                break message;
+            }
+        };
+        assert_eq!(colored[52..], [0, 0xff, 0xff, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0x12, 0x34]);
+
+        drop(hue);
+        assert_eq!(actions(&requests, Duration::from_millis(100)), ["stop"]);
+    }
+
+    #[test]
+    fn failed_connect_stops_the_configuration() {
+        let (rest, requests) = rest_bridge();
+        // nothing listens on a port that was just freed
+        let stream_port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        assert!(HueEntertainment::start_with(rest, config(), "zync", stream_port).is_err());
+        assert_eq!(actions(&requests, Duration::from_millis(100)), ["start", "stop"]);
+    }
+
+    #[test]
+    fn reconnect_starts_the_configuration_again() {
+        let (rest, requests) = rest_bridge();
+        let (stream_port, messages) = stream_bridge(1);
+
+        let hue = HueEntertainment::start_with(rest, config(), "zync", stream_port).unwrap();
+        messages.recv_timeout(TIMEOUT).unwrap();
+        // the session closes after one message, so the streamer reconnects once its writes fail
+        let mut starts = 0;
+        while starts < 2 {
+            let (method, _, body) = requests.recv_timeout(RECONNECT_DELAY * 3).unwrap();
+            if method == "PUT" {
+                assert_eq!(body["action"], "start");
+                starts += 1;
+            }
+        }
+        drop(hue);
+    }
+}
@@ -0,0 +1,227 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+
+use crate::capture::ZoneConfig;
+
+/// How far a border zone reaches into the screen. Either a percent of the screen ("15%") or a pixel count ("120").
+#[derive(Debug, Clone, Copy)]
+pub enum ZoneDepth {
+    Percent(f32),
+    Pixels(u32),
+}
+
+impl ZoneDepth {
+    /// resolves the depth to pixels against the screen dimension the zone reaches into
+    fn to_pixels(self, dimension: u32) -> u32 {
+        let pixels = match self {
+            ZoneDepth::Percent(percent) => (dimension as f32 * percent / 100.0).round() as u32,
+            ZoneDepth::Pixels(pixels) => pixels,
+        };
+        pixels.clamp(1, dimension)
+    }
+}
+
+impl FromStr for ZoneDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent: f32 = percent.trim().parse().map_err(|_| format!("Invalid percent depth: {}", s))?;
+                if percent <= 0.0 || percent > 50.0 {
+                    return Err(format!("Depth must be between 0% and 50%, got {}", s));
+                }
+                Ok(ZoneDepth::Percent(percent))
+            }
+            None => {
+                let pixels: u32 = s.parse().map_err(|_| format!("Invalid depth: {}. Use pixels (120) or a percent (15%)", s))?;
+                if pixels == 0 {
+                    return Err("Depth must be greater than 0".to_string());
+                }
+                Ok(ZoneDepth::Pixels(pixels))
+            }
+        }
+    }
+}
+
+/// Physical arrangement of lights around the screen. Counts are the number of lights along each edge.
+pub struct BorderLayout {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub depth: ZoneDepth,
+}
+
+impl BorderLayout {
+    pub fn zone_count(&self) -> usize {
+        (self.top + self.right + self.bottom + self.left) as usize
+    }
+
+    /// Spreads zones evenly around the screen border in clockwise order starting from the top left corner,
+    /// and assigns them to lights in the order they are given. Top and bottom zones span the full width;
+    /// left and right zones fill the space between them.
+    pub fn generate(&self, width: u32, height: u32, light_names: &[String]) -> Result<Vec<ZoneConfig>> {
+        let count = self.zone_count();
+        if count == 0 {
+            bail!("Layout has no zones. Set at least one of --top, --right, --bottom, or --left.");
+        }
+        if light_names.len() < count {
+            bail!("Layout needs {} lights but config only defines {}. Add lights or reduce the zone counts.", count, light_names.len());
+        }
+        if light_names.len() > count {
+            println!("Note: {} lights in config are not used by this layout.", light_names.len() - count);
+        }
+
+        let horizontal_depth = self.depth.to_pixels(height);
+        let vertical_depth = self.depth.to_pixels(width);
+
+        if self.top > 0 && self.bottom > 0 && 2 * horizontal_depth > height {
+            bail!("Depth is too large for the top and bottom zones to fit without overlapping.");
+        }
+
+        // side zones sit between the top and bottom bands when those exist
+        let side_start = if self.top > 0 { horizontal_depth } else { 0 };
+        let side_end = if self.bottom > 0 { height - horizontal_depth } else { height };
+        if side_end <= side_start && (self.left > 0 || self.right > 0) {
+            bail!("Depth is too large to fit left and right zones between the top and bottom zones.");
+        }
+
+        let mut rects: Vec<(String, u32, u32, u32, u32)> = Vec::with_capacity(count);
+
+        // top: left to right
+        for (i, (start, size)) in split(0, width, self.top).into_iter().enumerate() {
+            rects.push((format!("top_{}", i + 1), start, 0, size, horizontal_depth));
+        }
+        // right: top to bottom
+        for (i, (start, size)) in split(side_start, side_end, self.right).into_iter().enumerate() {
+            rects.push((format!("right_{}", i + 1), width - vertical_depth, start, vertical_depth, size));
+        }
+        // bottom: right to left
+        for (i, (start, size)) in split(0, width, self.bottom).into_iter().rev().enumerate() {
+            rects.push((format!("bottom_{}", i + 1), start, height - horizontal_depth, size, horizontal_depth));
+        }
+        // left: bottom to top
+        for (i, (start, size)) in split(side_start, side_end, self.left).into_iter().rev().enumerate() {
+            rects.push((format!("left_{}", i + 1), 0, start, vertical_depth, size));
+        }
+
+        let zones = rects.into_iter()
+            .zip(light_names)
+            .map(|((name, x, y, w, h), light_name)| ZoneConfig::new(Some(name), x, y, w, h, light_name.clone()))
+            .collect();
+
+        Ok(zones)
+    }
+}
+
+/// splits the range start..end into n evenly sized (start, size) segments. Remainders are spread so the segments cover the full range.
+fn split(start: u32, end: u32, n: u32) -> Vec<(u32, u32)> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let length = end - start;
+    (0..n)
+        .map(|i| {
+            let a = start + (length as u64 * i as u64 / n as u64) as u32;
+            let b = start + (length as u64 * (i as u64 + 1) / n as u64) as u32;
+            (a, b - a)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("light_{}", i)).collect()
+    }
+
+    /// (name, x, y, width, height, light_name) of each generated zone
+    fn rects(zones: &[ZoneConfig]) -> Vec<(String, u32, u32, u32, u32, String)> {
+        zones.iter()
+            .map(|zone| {
+                let value = serde_json::to_value(zone).unwrap();
+                let number = |key: &str| value[key].as_u64().unwrap() as u32;
+                (value["name"].as_str().unwrap().to_string(), number("x"), number("y"), number("width"), number("height"),
+                    value["light_name"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zones_cover_the_border_without_gaps_or_overlaps() {
+        let (width, height, depth) = (101, 57, 10);
+        let layout = BorderLayout { top: 3, right: 2, bottom: 4, left: 2, depth: ZoneDepth::Pixels(depth) };
+        let zones = layout.generate(width, height, &names(layout.zone_count())).unwrap();
+
+        let mut coverage = vec![0u8; (width * height) as usize];
+        for (_, x, y, w, h, _) in rects(&zones) {
+            for row in y..y + h {
+                for column in x..x + w {
+                    coverage[(row * width + column) as usize] += 1;
+                }
+            }
+        }
+
+        for row in 0..height {
+            for column in 0..width {
+                let border = row < depth || row >= height - depth || column < depth || column >= width - depth;
+                assert_eq!(coverage[(row * width + column) as usize], border as u8, "pixel ({}, {})", column, row);
+            }
+        }
+    }
+
+    #[test]
+    fn zones_run_clockwise_from_the_top_left() {
+        let layout = BorderLayout { top: 3, right: 2, bottom: 3, left: 2, depth: ZoneDepth::Percent(15.0) };
+        let zones = rects(&layout.generate(1920, 1080, &names(10)).unwrap());
+
+        let order: Vec<&str> = zones.iter().map(|zone| zone.0.as_str()).collect();
+        assert_eq!(order, ["top_1", "top_2", "top_3", "right_1", "right_2", "bottom_1", "bottom_2", "bottom_3", "left_1", "left_2"]);
+        let lights: Vec<&str> = zones.iter().map(|zone| zone.5.as_str()).collect();
+        assert_eq!(lights, names(10));
+
+        let (top, rest) = zones.split_at(3);
+        let (right, rest) = rest.split_at(2);
+        let (bottom, left) = rest.split_at(3);
+        assert!(top.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert!(right.windows(2).all(|pair| pair[0].2 < pair[1].2));
+        assert!(bottom.windows(2).all(|pair| pair[0].1 > pair[1].1));
+        assert!(left.windows(2).all(|pair| pair[0].2 > pair[1].2));
+        assert!(right.iter().all(|zone| zone.1 + zone.3 == 1920));
+        assert!(bottom.iter().all(|zone| zone.2 + zone.4 == 1080));
+    }
+
+    #[test]
+    fn remainder_pixels_are_spread_across_zones() {
+        let segments = split(0, 1921, 4);
+        assert_eq!(segments, [(0, 480), (480, 480), (960, 480), (1440, 481)]);
+
+        let segments = split(7, 107, 3);
+        assert_eq!(segments.iter().map(|(_, size)| size).sum::<u32>(), 100);
+        assert!(segments.windows(2).all(|pair| pair[0].0 + pair[0].1 == pair[1].0));
+        assert!(segments.iter().all(|(_, size)| (33..=34).contains(size)));
+    }
+
+    #[test]
+    fn rejects_layouts_that_dont_fit() {
+        let layout = BorderLayout { top: 2, right: 1, bottom: 2, left: 1, depth: ZoneDepth::Percent(15.0) };
+        assert!(layout.generate(1920, 1080, &names(5)).is_err());
+
+        let layout = BorderLayout { top: 1, right: 1, bottom: 1, left: 1, depth: ZoneDepth::Pixels(600) };
+        assert!(layout.generate(1920, 1080, &names(4)).is_err());
+
+        let layout = BorderLayout { top: 0, right: 0, bottom: 0, left: 0, depth: ZoneDepth::Pixels(10) };
+        assert!(layout.generate(1920, 1080, &names(4)).is_err());
+
+        // top and bottom bands overlapping with no side zones
+        let layout = BorderLayout { top: 1, right: 0, bottom: 1, left: 0, depth: ZoneDepth::Pixels(600) };
+        assert!(layout.generate(1920, 1080, &names(2)).is_err());
+        let layout = BorderLayout { top: 1, right: 0, bottom: 1, left: 0, depth: ZoneDepth::Pixels(540) };
+        assert!(layout.generate(1920, 1080, &names(2)).is_ok());
+        assert!(split(600, 480, 0).is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use image::RgbaImage;
+use serde::Deserialize;
+
+use crate::capture::ScreenArea;
+
+/// Settings for detecting black bars (letterbox and pillarbox) so zones sample the active picture instead of the bars.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LetterboxConfig {
+    pub enabled: bool,
+    pub threshold: u8,          // max brightness (0-255) a pixel can have and still count as black
+    pub window: usize,          // frames a bar has to be present in before zones move into the picture
+    pub tolerance: f32,         // change in bar size (fraction of the screen) needed before zones move again
+    pub min_content: f32,       // fraction of a row/column that has to be non-black to count as picture. keeps subtitles from breaking detection
+}
+
+impl Default for LetterboxConfig {
+    fn default() -> Self {
+        LetterboxConfig {
+            enabled: false,
+            threshold: 16,
+            window: 30,
+            tolerance: 0.02,
+            min_content: 0.1,
+        }
+    }
+}
+
+/// size of the black bars on each side of a frame in pixels
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+struct Bars {
+    top: u32,
+    bottom: u32,
+    left: u32,
+    right: u32,
+}
+
+impl Bars {
+    fn min(&self, other: &Bars) -> Bars {
+        Bars {
+            top: self.top.min(other.top),
+            bottom: self.bottom.min(other.bottom),
+            left: self.left.min(other.left),
+            right: self.right.min(other.right),
+        }
+    }
+}
+
+/// Detects black bars over a rolling window of frames. Bars only count once they've been present for the whole window,
+/// and the active area only moves when bars change by more than the tolerance, so dark scenes don't make zones flicker.
+pub struct LetterboxDetector {
+    config: LetterboxConfig,
+    history: VecDeque<Bars>,
+    current: Bars,
+    frame_size: (u32, u32),
+}
+
+impl LetterboxDetector {
+    pub fn new(config: LetterboxConfig) -> Self {
+        LetterboxDetector {
+            history: VecDeque::with_capacity(config.window),
+            config,
+            current: Bars::default(),
+            frame_size: (0, 0),
+        }
+    }
+
+    /// Updates detection with a new frame and returns the area of the frame zones should sample from.
+    pub fn update(&mut self, frame: &RgbaImage, downsample: u8) -> ScreenArea {
+        // start over if the frame size changes, old bars don't apply anymore
+        if frame.dimensions() != self.frame_size {
+            self.frame_size = frame.dimensions();
+            self.history.clear();
+            self.current = Bars::default();
+        }
+
+        // fully black frames (fades, loading screens) say nothing about the bars, so they're skipped
+        if let Some(bars) = self.detect(frame, downsample.max(1) as u32) {
+            if self.history.len() == self.config.window.max(1) {
+                self.history.pop_front();
+            }
+            self.history.push_back(bars);
+        }
+
+        if self.history.len() >= self.config.window.max(1) {
+            let candidate = self.history.iter()
+                .fold(self.history[0], |acc, bars| acc.min(bars));
+
+            if self.exceeds_tolerance(&candidate) {
+                println!("Black bars changed: top {} bottom {} left {} right {}",
+                    candidate.top, candidate.bottom, candidate.left, candidate.right);
+                self.current = candidate;
+            }
+        }
+
+        let (width, height) = self.frame_size;
+        ScreenArea {
+            x: self.current.left,
+            y: self.current.top,
+            width: width - self.current.left - self.current.right,
+            height: height - self.current.top - self.current.bottom,
+        }
+    }
+
+    fn exceeds_tolerance(&self, candidate: &Bars) -> bool {
+        let (width, height) = self.frame_size;
+        let vertical = (self.config.tolerance * height as f32) as u32;
+        let horizontal = (self.config.tolerance * width as f32) as u32;
+
+        candidate.top.abs_diff(self.current.top) > vertical
+            || candidate.bottom.abs_diff(self.current.bottom) > vertical
+            || candidate.left.abs_diff(self.current.left) > horizontal
+            || candidate.right.abs_diff(self.current.right) > horizontal
+    }
+
+    /// measures bars in a single frame by scanning rows and columns in from each edge until one has picture in it
+    fn detect(&self, frame: &RgbaImage, step: u32) -> Option<Bars> {
+        let (width, height) = frame.dimensions();
+
+        let top = (0..height).step_by(step as usize)
+            .find(|&y| self.row_has_content(frame, y, step))?;
+        let bottom = (0..height).rev().step_by(step as usize)
+            .find(|&y| self.row_has_content(frame, y, step))?;
+        let left = (0..width).step_by(step as usize)
+            .find(|&x| self.column_has_content(frame, x, top, bottom, step))?;
+        let right = (0..width).rev().step_by(step as usize)
+            .find(|&x| self.column_has_content(frame, x, top, bottom, step))?;
+
+        // leave at least half the frame, anything more is a dark scene rather than bars
+        if bottom - top < height / 2 || right - left < width / 2 {
+            return None;
+        }
+
+        Some(Bars {
+            top,
+            bottom: height - 1 - bottom,
+            left,
+            right: width - 1 - right,
+        })
+    }
+
+    fn row_has_content(&self, frame: &RgbaImage, y: u32, step: u32) -> bool {
+        let pixels = (0..frame.width()).step_by(step as usize)
+            .map(|x| frame.get_pixel(x, y));
+        self.has_content(pixels)
+    }
+
+    fn column_has_content(&self, frame: &RgbaImage, x: u32, top: u32, bottom: u32, step: u32) -> bool {
+        let pixels = (top..=bottom).step_by(step as usize)
+            .map(|y| frame.get_pixel(x, y));
+        self.has_content(pixels)
+    }
+
+    fn has_content<'p>(&self, pixels: impl Iterator<Item = &'p image::Rgba<u8>>) -> bool {
+        let mut total = 0u32;
+        let mut bright = 0u32;
+        for pixel in pixels {
+            let luma = (299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32) / 1000;
+            if luma > self.config.threshold as u32 {
+                bright += 1;
+            }
+            total += 1;
+        }
+        total > 0 && bright as f32 >= self.config.min_content * total as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const WIDTH: u32 = 384;
+    const HEIGHT: u32 = 216;
+
+    /// a grey picture with black bars of the given sizes around it
+    fn frame(top: u32, bottom: u32, left: u32, right: u32) -> RgbaImage {
+        RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+            let picture = y >= top && y < HEIGHT - bottom && x >= left && x < WIDTH - right;
+            if picture { Rgba([120, 110, 100, 255]) } else { Rgba([4, 4, 4, 255]) }
+        })
+    }
+
+    fn detector(window: usize) -> LetterboxDetector {
+        LetterboxDetector::new(LetterboxConfig { enabled: true, window, ..LetterboxConfig::default() })
+    }
+
+    fn area(x: u32, y: u32, width: u32, height: u32) -> ScreenArea {
+        ScreenArea { x, y, width, height }
+    }
+
+    #[test]
+    fn detects_scope_letterbox() {
+        // 2.39:1 in 16:9 leaves a 161 pixel tall picture
+        let mut detector = detector(3);
+        let frame = frame(28, 27, 0, 0);
+        let mut result = ScreenArea::full(&frame);
+        for _ in 0..3 {
+            result = detector.update(&frame, 1);
+        }
+        assert_eq!(result, area(0, 28, WIDTH, 161));
+    }
+
+    #[test]
+    fn detects_pillarbox() {
+        // 4:3 in 16:9
+        let mut detector = detector(3);
+        let frame = frame(0, 0, 48, 48);
+        let mut result = ScreenArea::full(&frame);
+        for _ in 0..3 {
+            result = detector.update(&frame, 1);
+        }
+        assert_eq!(result, area(48, 0, 288, HEIGHT));
+    }
+
+    #[test]
+    fn full_frame_has_no_bars() {
+        let mut detector = detector(3);
+        let frame = frame(0, 0, 0, 0);
+        for _ in 0..5 {
+            assert_eq!(detector.update(&frame, 1), ScreenArea::full(&frame));
+        }
+    }
+
+    #[test]
+    fn dark_scene_is_not_read_as_bars() {
+        // a small bright object in an otherwise black frame
+        let mut detector = detector(3);
+        let dark = frame(90, 90, 170, 170);
+        for _ in 0..5 {
+            assert_eq!(detector.update(&dark, 1), ScreenArea::full(&dark));
+        }
+
+        // fully black frames don't clear bars that were already found
+        let letterbox = frame(28, 27, 0, 0);
+        let black = RgbaImage::from_pixel(WIDTH, HEIGHT, Rgba([0, 0, 0, 255]));
+        for _ in 0..3 {
+            detector.update(&letterbox, 1);
+        }
+        for _ in 0..5 {
+            assert_eq!(detector.update(&black, 1), area(0, 28, WIDTH, 161));
+        }
+    }
+
+    #[test]
+    fn bars_need_the_whole_window() {
+        let mut detector = detector(4);
+        let letterbox = frame(28, 27, 0, 0);
+        for _ in 0..3 {
+            assert_eq!(detector.update(&letterbox, 1), ScreenArea::full(&letterbox));
+        }
+        assert_eq!(detector.update(&letterbox, 1), area(0, 28, WIDTH, 161));
+
+        // one frame of full picture keeps the bars until it leaves the window
+        let full = frame(0, 0, 0, 0);
+        for _ in 0..4 {
+            assert_eq!(detector.update(&full, 1), ScreenArea::full(&full));
+        }
+        for _ in 0..3 {
+            assert_eq!(detector.update(&letterbox, 1), ScreenArea::full(&letterbox));
+        }
+        assert_eq!(detector.update(&letterbox, 1), area(0, 28, WIDTH, 161));
+    }
+
+    #[test]
+    fn small_changes_stay_within_tolerance() {
+        // 2% of 216 is 4 pixels
+        let mut detector = detector(2);
+        let letterbox = frame(28, 27, 0, 0);
+        for _ in 0..2 {
+            detector.update(&letterbox, 1);
+        }
+
+        let shifted = frame(25, 27, 0, 0);
+        for _ in 0..2 {
+            assert_eq!(detector.update(&shifted, 1), area(0, 28, WIDTH, 161));
+        }
+
+        let moved = frame(20, 27, 0, 0);
+        for _ in 0..2 {
+            detector.update(&moved, 1);
+        }
+        assert_eq!(detector.update(&moved, 1), area(0, 20, WIDTH, 169));
+    }
+}
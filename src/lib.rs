@@ -0,0 +1,27 @@
+pub mod aesthetics;
+pub mod calibration;
+pub mod capture;
+pub mod cli;
+pub mod color;
+pub mod config;
+pub mod discovery;
+pub mod dmx;
+pub mod gamut;
+pub mod govee;
+pub mod homeassistant;
+pub mod http;
+pub mod hue;
+pub mod layout;
+pub mod letterbox;
+pub mod lifx;
+pub mod lights;
+pub mod mqtt;
+pub mod openrgb;
+pub mod scenecut;
+pub mod sink;
+pub mod smoothing;
+pub mod sync;
+pub mod udp;
+pub mod weighting;
+pub mod wled;
+pub mod yeelight;
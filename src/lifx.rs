@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::color::rgb_to_hsv;
+use crate::lights::{LightConfig, LightService};
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+use crate::wled::split_leds;
+
+/// bulb MAC, where the reply came from, and its payload
+type Reply = ([u8; 6], SocketAddr, Vec<u8>);
+
+const PORT: u16 = 56700;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+const KELVIN: u16 = 3500;                       // only used by bulbs for unsaturated colors
+const MAX_ZONES_PER_PACKET: usize = 82;
+
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const SET_POWER: u16 = 21;
+const GET_LABEL: u16 = 23;
+const STATE_LABEL: u16 = 25;
+const GET: u16 = 101;
+const SET_COLOR: u16 = 102;
+const STATE: u16 = 107;
+const SET_LIGHT_POWER: u16 = 117;
+const SET_EXTENDED_COLOR_ZONES: u16 = 510;
+const GET_EXTENDED_COLOR_ZONES: u16 = 511;
+const STATE_EXTENDED_COLOR_ZONES: u16 = 512;
+
+/// How a LIFX light is found. By default the light_name is the bulb's label from the LIFX app.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LifxLight {
+    pub mac: Option<String>,        // address the bulb by MAC (d0:73:d5:12:34:56) instead of its label
+    pub host: Option<String>,       // bulb IP, for networks where discovery broadcasts don't reach it
+    /// zones per segment in segment order for Z strips and Beams. leave empty to split the zones evenly
+    #[serde(default)]
+    pub segment_zones: Vec<u16>,
+}
+
+/// LIFX color: hue, saturation, and brightness scaled to 0-65535, and a color temperature in kelvin
+#[derive(Debug, Clone, Copy)]
+pub struct Hsbk {
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+    pub kelvin: u16,
+}
+
+impl Hsbk {
+    /// hue and saturation from the rgb color. brightness is the light's brightness, like in Zigbee2MQTT payloads
+    pub fn from_rgb([r, g, b]: [u8; 3], brightness: u8) -> Self {
+        let (hue, saturation, _) = rgb_to_hsv(r, g, b);
+        Hsbk {
+            hue: (hue / 360.0 * 65535.0).round() as u16,
+            saturation: (saturation * 65535.0).round() as u16,
+            brightness: (brightness as f32 / 255.0 * 65535.0).round() as u16,
+            kelvin: KELVIN,
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let value = |i: usize| Some(u16::from_le_bytes(bytes.get(i * 2..i * 2 + 2)?.try_into().ok()?));
+        Some(Hsbk { hue: value(0)?, saturation: value(1)?, brightness: value(2)?, kelvin: value(3)? })
+    }
+
+    fn write(&self, packet: &mut Vec<u8>) {
+        for value in [self.hue, self.saturation, self.brightness, self.kelvin] {
+            packet.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Device {
+    mac: [u8; 6],
+    address: SocketAddr,
+    zones: u16,                     // 0 for bulbs without multizone
+}
+
+/// LIFX bulbs and strips on the LAN, found at startup with GetService broadcasts
+pub struct Lifx {
+    socket: UdpSocket,
+    source: u32,
+    sequence: AtomicU8,
+    devices: HashMap<String, Device>,   // by light_name
+}
+
+impl Lifx {
+    /// finds every LIFX light in the config and turns them on. errors if a light doesn't answer
+    pub fn discover(lights: &[LightConfig]) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open UDP socket")?;
+        socket.set_broadcast(true)?;
+        // any non zero source makes bulbs answer to our address instead of broadcasting
+        let mut lifx = Lifx { socket, source: std::process::id().max(2), sequence: AtomicU8::new(0), devices: HashMap::new() };
+
+        let lights: Vec<&LightConfig> = lights.iter()
+            .filter(|light| matches!(light.service, LightService::LIFX))
+            .collect();
+
+        // GetService goes to the broadcast address and to bulbs with a configured host
+        let mut hosts = vec!["255.255.255.255".to_string()];
+        hosts.extend(lights.iter().filter_map(|light| light.lifx.as_ref()?.host.clone()));
+        for host in &hosts {
+            lifx.socket.send_to(&lifx.packet(None, GET_SERVICE, &[], true), (host.as_str(), PORT))
+                .with_context(|| format!("Failed to send LIFX discovery to {}", host))?;
+        }
+        let mut found: HashMap<[u8; 6], SocketAddr> = HashMap::new();
+        for (mac, from, payload) in lifx.collect(STATE_SERVICE, usize::MAX)? {
+            // service 1 is UDP. the payload has its port
+            if payload.len() >= 5 && payload[0] == 1 {
+                let port = u32::from_le_bytes(payload[1..5].try_into()?) as u16;
+                found.insert(mac, SocketAddr::new(from.ip(), port));
+            }
+        }
+
+        for (mac, address) in &found {
+            lifx.send_to(*mac, *address, GET_LABEL, &[])?;
+        }
+        let labels: HashMap<[u8; 6], String> = lifx.collect(STATE_LABEL, found.len())?.into_iter()
+            .map(|(mac, _, payload)| {
+                let label = payload.iter().take(32).take_while(|byte| **byte != 0).copied().collect::<Vec<u8>>();
+                (mac, String::from_utf8_lossy(&label).into_owned())
+            })
+            .collect();
+
+        for light in &lights {
+            let settings = light.lifx.clone().unwrap_or_default();
+            let mac = match &settings.mac {
+                Some(mac) => Some(parse_mac(mac)?),
+                None => labels.iter().find(|(_, label)| **label == light.light_name).map(|(mac, _)| *mac),
+            };
+            let Some((mac, address)) = mac.and_then(|mac| Some((mac, *found.get(&mac)?))) else {
+                bail!("LIFX light {} didn't answer discovery. Check its label or set its mac and host", light.light_name);
+            };
+            lifx.devices.insert(light.light_name.clone(), Device { mac, address, zones: 0 });
+        }
+
+        // strips report their zone count. single zone bulbs don't answer
+        let strips: Vec<Device> = lights.iter()
+            .filter(|light| light.is_segmented())
+            .filter_map(|light| lifx.devices.get(&light.light_name).cloned())
+            .collect();
+        for device in &strips {
+            lifx.send_to(device.mac, device.address, GET_EXTENDED_COLOR_ZONES, &[])?;
+        }
+        for (mac, _, payload) in lifx.collect(STATE_EXTENDED_COLOR_ZONES, strips.len())? {
+            let zones = u16::from_le_bytes(payload.get(..2).context("Short LIFX zone state")?.try_into()?);
+            lifx.devices.values_mut().filter(|device| device.mac == mac).for_each(|device| device.zones = zones);
+        }
+
+        for device in lifx.devices.values() {
+            lifx.send_to(device.mac, device.address, SET_POWER, &u16::MAX.to_le_bytes())?;
+        }
+        Ok(lifx)
+    }
+
+    fn device(&self, light_name: &str) -> Result<&Device> {
+        self.devices.get(light_name)
+            .with_context(|| format!("LIFX light {} was not found at startup", light_name))
+    }
+
+    /// zone index range of each segment. errors if the light has no zones, like regular bulbs
+    pub fn segment_ranges(&self, light: &LightConfig, segments: usize) -> Result<Vec<Range<usize>>> {
+        let device = self.device(&light.light_name)?;
+        if device.zones == 0 {
+            bail!("LIFX light {} has segments but doesn't support extended multizone", light.light_name);
+        }
+        let segment_zones = light.lifx.as_ref().map(|settings| settings.segment_zones.clone()).unwrap_or_default();
+        split_leds(device.zones as usize, segments, &segment_zones)
+            .with_context(|| format!("LIFX light {}", light.light_name))
+    }
+
+    /// SetColor with the transition as its duration
+    pub fn set_color(&self, light_name: &str, color: Hsbk, transition: f32) -> Result<()> {
+        let device = self.device(light_name)?;
+        let mut payload = vec![0u8];
+        color.write(&mut payload);
+        payload.extend_from_slice(&duration_ms(transition).to_le_bytes());
+        self.send_to(device.mac, device.address, SET_COLOR, &payload)
+    }
+
+    /// SetExtendedColorZones with each segment's color spread over its zones. strips with more than 82 zones
+    /// get several packets and only the last applies them
+    pub fn set_zones(&self, light: &LightConfig, colors: &[Hsbk], transition: f32) -> Result<()> {
+        let device = self.device(&light.light_name)?;
+        let ranges = self.segment_ranges(light, colors.len())?;
+
+        let mut zones = vec![Hsbk { hue: 0, saturation: 0, brightness: 0, kelvin: KELVIN }; device.zones as usize];
+        for (range, color) in ranges.into_iter().zip(colors) {
+            zones[range].fill(*color);
+        }
+
+        let chunks = zones.chunks(MAX_ZONES_PER_PACKET).count();
+        for (i, chunk) in zones.chunks(MAX_ZONES_PER_PACKET).enumerate() {
+            let mut payload = Vec::with_capacity(8 + MAX_ZONES_PER_PACKET * 8);
+            payload.extend_from_slice(&duration_ms(transition).to_le_bytes());
+            payload.push(u8::from(i + 1 == chunks));                            // apply
+            payload.extend_from_slice(&((i * MAX_ZONES_PER_PACKET) as u16).to_le_bytes());
+            payload.push(chunk.len() as u8);
+            chunk.iter().for_each(|color| color.write(&mut payload));
+            payload.resize(8 + MAX_ZONES_PER_PACKET * 8, 0);                    // the color array is always 82 long
+            self.send_to(device.mac, device.address, SET_EXTENDED_COLOR_ZONES, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// the light's color and power level (0 or 65535)
+    pub fn state(&self, light_name: &str) -> Result<Option<(Hsbk, u16)>> {
+        let device = self.device(light_name)?;
+        self.send_to(device.mac, device.address, GET, &[])?;
+        let state = self.collect(STATE, 1)?.into_iter()
+            .find(|(mac, _, _)| *mac == device.mac)
+            .and_then(|(_, _, payload)| {
+                let power = u16::from_le_bytes(payload.get(10..12)?.try_into().ok()?);
+                Some((Hsbk::read(&payload)?, power))
+            });
+        Ok(state)
+    }
+
+    /// SetLightPower, fading over the transition
+    pub fn set_power(&self, light_name: &str, level: u16, transition: f32) -> Result<()> {
+        let device = self.device(light_name)?;
+        let mut payload = level.to_le_bytes().to_vec();
+        payload.extend_from_slice(&duration_ms(transition).to_le_bytes());
+        self.send_to(device.mac, device.address, SET_LIGHT_POWER, &payload)
+    }
+
+    fn send_to(&self, mac: [u8; 6], address: SocketAddr, packet_type: u16, payload: &[u8]) -> Result<()> {
+        // only requests need a response. set messages are fire and forget
+        let response = matches!(packet_type, GET_SERVICE | GET_LABEL | GET_EXTENDED_COLOR_ZONES | GET);
+        self.socket.send_to(&self.packet(Some(mac), packet_type, payload, response), address)
+            .with_context(|| format!("Failed to send to LIFX light at {}", address))?;
+        Ok(())
+    }
+
+    /// header: frame (size, protocol, source), frame address (target, flags, sequence), then the message type.
+    /// messages without a target are tagged so every bulb handles them
+    fn packet(&self, target: Option<[u8; 6]>, packet_type: u16, payload: &[u8], response: bool) -> Vec<u8> {
+        let protocol: u16 = 1024 | 0x1000 | if target.is_none() { 0x2000 } else { 0 };
+        let mut packet = Vec::with_capacity(36 + payload.len());
+        packet.extend_from_slice(&((36 + payload.len()) as u16).to_le_bytes());
+        packet.extend_from_slice(&protocol.to_le_bytes());
+        packet.extend_from_slice(&self.source.to_le_bytes());
+        packet.extend_from_slice(&target.unwrap_or_default());
+        packet.extend_from_slice(&[0; 8]);                                      // target padding and reserved
+        packet.push(u8::from(response));
+        packet.push(self.sequence.fetch_add(1, Ordering::Relaxed));
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&packet_type.to_le_bytes());
+        packet.extend_from_slice(&[0; 2]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// replies of one type until every expected bulb answered or the discovery timeout, one per bulb
+    fn collect(&self, packet_type: u16, expected: usize) -> Result<Vec<Reply>> {
+        let mut replies: HashMap<[u8; 6], (SocketAddr, Vec<u8>)> = HashMap::new();
+        let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+        let mut buffer = [0u8; 1024];
+
+        while replies.len() < expected {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let Ok((length, from)) = self.socket.recv_from(&mut buffer) else { break };
+            let packet = &buffer[..length];
+            if length < 36
+                || u32::from_le_bytes(packet[4..8].try_into()?) != self.source
+                || u16::from_le_bytes(packet[32..34].try_into()?) != packet_type
+            {
+                continue;
+            }
+            let mac: [u8; 6] = packet[8..14].try_into()?;
+            replies.insert(mac, (from, packet[36..].to_vec()));
+        }
+        Ok(replies.into_iter().map(|(mac, (from, payload))| (mac, from, payload)).collect())
+    }
+}
+
+/// A LIFX bulb or multizone strip
+pub struct LifxSink {
+    config: LightConfig,
+    lifx: Arc<Lifx>,
+    snapshot: Option<(Hsbk, u16)>,
+}
+
+impl LifxSink {
+    pub fn new(config: LightConfig, lifx: Arc<Lifx>) -> Self {
+        LifxSink { config, lifx, snapshot: None }
+    }
+}
+
+impl LightSink for LifxSink {
+    /// SetColor for bulbs, or one color per segment for multizone strips. transitions become the message duration
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let colors: Vec<Hsbk> = updates.iter()
+            .map(|update| {
+                let (rgb, brightness) = self.config.color(&update.color);
+                Hsbk::from_rgb(rgb, brightness)
+            })
+            .collect();
+        let transition = updates[0].transition;
+        match self.config.is_segmented() {
+            true => self.lifx.set_zones(&self.config, &colors, transition),
+            false => self.lifx.set_color(&self.config.light_name, colors[0], transition),
+        }
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: true, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+
+    fn snapshot(&mut self) -> Result<()> {
+        self.snapshot = self.lifx.state(&self.config.light_name)?;
+        if self.snapshot.is_none() {
+            println!("{} didn't report its state. It won't be restored", self.config.light_name);
+        }
+        Ok(())
+    }
+
+    /// strips come back as a single color
+    fn restore(&mut self) -> Result<()> {
+        let Some((color, power)) = self.snapshot else { return Ok(()) };
+        self.lifx.set_color(&self.config.light_name, color, 1.0)?;
+        self.lifx.set_power(&self.config.light_name, power, 1.0)
+    }
+}
+
+fn duration_ms(transition: f32) -> u32 {
+    (transition.max(0.0) * 1000.0).round() as u32
+}
+
+fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let bytes = mac.split([':', '-'])
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()
+        .and_then(|bytes| <[u8; 6]>::try_from(bytes).ok());
+    bytes.with_context(|| format!("Invalid MAC address {}", mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xd0, 0x73, 0xd5, 0x12, 0x34, 0x56];
+
+    /// a client on localhost with one device, and the socket standing in for that device
+    fn lifx(zones: u16) -> (Lifx, UdpSocket) {
+        let bulb = UdpSocket::bind("127.0.0.1:0").unwrap();
+        bulb.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let device = Device { mac: MAC, address: bulb.local_addr().unwrap(), zones };
+        let lifx = Lifx {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            source: 0x12345678,
+            sequence: AtomicU8::new(0),
+            devices: HashMap::from([("Strip".to_string(), device)]),
+        };
+        (lifx, bulb)
+    }
+
+    fn receive(bulb: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0u8; 1024];
+        let length = bulb.recv(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    fn u16_at(packet: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes(packet[i..i + 2].try_into().unwrap())
+    }
+
+    fn hsbk_at(packet: &[u8], i: usize) -> [u16; 4] {
+        [u16_at(packet, i), u16_at(packet, i + 2), u16_at(packet, i + 4), u16_at(packet, i + 6)]
+    }
+
+    #[test]
+    fn header_follows_the_lan_protocol() {
+        let (lifx, _) = lifx(0);
+
+        let broadcast = lifx.packet(None, GET_SERVICE, &[], true);
+        assert_eq!(broadcast.len(), 36);
+        assert_eq!(u16_at(&broadcast, 0), 36);                      // size
+        assert_eq!(u16_at(&broadcast, 2), 0x3400);                  // protocol 1024, addressable, tagged
+        assert_eq!(broadcast[4..8], 0x12345678u32.to_le_bytes());   // source
+        assert_eq!(broadcast[8..16], [0; 8]);                       // every bulb
+        assert_eq!(broadcast[16..22], [0; 6]);
+        assert_eq!(broadcast[22], 1);                               // response required
+        assert_eq!(broadcast[23], 0);                               // sequence
+        assert_eq!(broadcast[24..32], [0; 8]);
+        assert_eq!(u16_at(&broadcast, 32), GET_SERVICE);
+        assert_eq!(broadcast[34..36], [0; 2]);
+
+        let targeted = lifx.packet(Some(MAC), SET_POWER, &u16::MAX.to_le_bytes(), false);
+        assert_eq!(u16_at(&targeted, 0), 38);
+        assert_eq!(u16_at(&targeted, 2), 0x1400);                   // not tagged
+        assert_eq!(targeted[8..14], MAC);
+        assert_eq!(targeted[14..16], [0; 2]);
+        assert_eq!(targeted[22], 0);
+        assert_eq!(targeted[23], 1);
+        assert_eq!(u16_at(&targeted, 32), SET_POWER);
+        assert_eq!(targeted[36..], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn set_color_payload() {
+        let (lifx, bulb) = lifx(0);
+        let color = Hsbk::from_rgb([0, 0, 255], 128);
+        lifx.set_color("Strip", color, 0.25).unwrap();
+
+        let packet = receive(&bulb);
+        assert_eq!(packet.len(), 36 + 13);
+        assert_eq!(u16_at(&packet, 0), 49);
+        assert_eq!(packet[8..14], MAC);
+        assert_eq!(u16_at(&packet, 32), SET_COLOR);
+        assert_eq!(packet[36], 0);                                  // reserved
+        assert_eq!(hsbk_at(&packet, 37), [43690, 65535, 32896, KELVIN]);
+        assert_eq!(packet[45..49], 250u32.to_le_bytes());           // duration in ms
+    }
+
+    #[test]
+    fn long_strips_take_several_extended_zone_messages() {
+        let (lifx, bulb) = lifx(120);
+        let light: LightConfig = serde_yaml::from_str(
+            "{ service: LIFX, light_name: Strip, brightness: 1.0, segments: [left, middle, right] }").unwrap();
+        let red = Hsbk::from_rgb([255, 0, 0], 255);
+        let green = Hsbk::from_rgb([0, 255, 0], 255);
+        let blue = Hsbk::from_rgb([0, 0, 255], 255);
+        lifx.set_zones(&light, &[red, green, blue], 0.1).unwrap();
+
+        let mut zones = Vec::new();
+        for (index, count, apply) in [(0u16, 82u8, 0u8), (82, 38, 1)] {
+            let packet = receive(&bulb);
+            assert_eq!(packet.len(), 36 + 8 + 82 * 8);
+            assert_eq!(u16_at(&packet, 0) as usize, packet.len());
+            assert_eq!(u16_at(&packet, 32), SET_EXTENDED_COLOR_ZONES);
+            assert_eq!(packet[36..40], 100u32.to_le_bytes());       // duration
+            assert_eq!(packet[40], apply);                          // only the last message applies
+            assert_eq!(u16_at(&packet, 41), index);
+            assert_eq!(packet[43], count);
+            let colors = &packet[44..];
+            zones.extend((0..count as usize).map(|i| hsbk_at(colors, i * 8)));
+            assert!(colors[count as usize * 8..].iter().all(|byte| *byte == 0));
+        }
+
+        let expected = |color: Hsbk| [color.hue, color.saturation, color.brightness, color.kelvin];
+        assert_eq!(zones.len(), 120);
+        assert!(zones[..40].iter().all(|zone| *zone == expected(red)));
+        assert!(zones[40..80].iter().all(|zone| *zone == expected(green)));
+        assert!(zones[80..].iter().all(|zone| *zone == expected(blue)));
+    }
+
+    #[test]
+    fn bulbs_without_zones_reject_segments() {
+        let (lifx, _) = lifx(0);
+        let light: LightConfig = serde_yaml::from_str(
+            "{ service: LIFX, light_name: Strip, brightness: 1.0, segments: [left, right] }").unwrap();
+        assert!(lifx.segment_ranges(&light, 2).is_err());
+    }
+}
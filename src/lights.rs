@@ -1,24 +1,63 @@
 #![allow(dead_code, unused_imports, unused_variables)]
 
-use rumqttc::{Client, QoS};
-use serde_json::json;
-use anyhow::{Result, Context};
+use std::collections::HashMap;
+use std::sync::Arc;
+use rumqttc::Client;
+use anyhow::{Result, Context, bail};
 use serde::Deserialize;
 
+use crate::aesthetics::Aesthetics;
+use crate::calibration::Calibration;
 use crate::capture::ZoneColor;
+use crate::gamut::{ColorMode, Gamut};
+use crate::config::AppConfig;
+use crate::govee::{self, GoveeConfig};
+use crate::mqtt::{MqttSink, MqttStates};
+use crate::sink::LightSink;
+use crate::dmx::{DmxFixture, DmxOutput};
+use crate::homeassistant::{HomeAssistantClient, HomeAssistantSink};
+use crate::http::{HttpClient, HttpLight, HttpSink};
+use crate::hue::{HueEntertainment, HueSink};
+use crate::lifx::{Lifx, LifxLight, LifxSink};
+use crate::openrgb::{OpenRgbClient, OpenRgbLight, OpenRgbSink};
+use crate::udp::{UdpOutput, UdpSink};
+use crate::wled::WledConfig;
+use crate::yeelight::{Yeelight, YeelightConfig, YeelightSink};
 
-
-const BRIGHTNESS_COLOR_THRESH: u8 = 25;
-const MIN_BRIGHTNESS: u8 = 1;
-const BRIGHTNESS_EXP: f32 = 1.3;
-const BRIGHTNESS_FACTOR: f32 = 1.1;
+pub const DEFAULT_BASE_TOPIC: &str = "zigbee2mqtt";
 
 //this is used to format the payload for various services. HueAPI isn't zigbee but including it as I am interested in making it in scope as the application adds different connection types beyond MQTT
 #[derive(Deserialize, Debug, Clone)]
 pub enum LightService {
     Zigbee2MQTT,
     ZHA,
-    HueAPI
+    HueAPI,
+    WLED,
+    DMX,
+    OpenRGB,
+    LIFX,
+    Yeelight,
+    Govee,
+    Tasmota,
+    ESPHome,
+    HTTP,
+}
+
+impl LightService {
+    /// lights that go through the broker in the mqtt section
+    pub fn uses_mqtt(&self) -> bool {
+        matches!(self, LightService::Zigbee2MQTT | LightService::Tasmota | LightService::ESPHome)
+    }
+}
+
+/// How colors for segmented lights are sent. Gradient uses the `gradient` array of Hue Gradient lights,
+/// endpoints sets each segment through its `state_lN`/`color_lN`/`brightness_lN` keys (multi-channel LED controllers).
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentMode {
+    #[default]
+    Gradient,
+    Endpoints,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -26,81 +65,259 @@ pub struct LightConfig {
     pub service: LightService,
     pub light_name: String,
     pub brightness: f32,
+    /// ordered zone names mapped onto the light's segments, e.g. left to right for a strip behind the monitor.
+    /// leave empty for regular lights.
+    #[serde(default)]
+    pub segments: Vec<String>,
+    #[serde(default)]
+    pub segment_mode: SegmentMode,
+    /// color correction for this light's bulb model. set it up with `zync calibrate <light>`
+    #[serde(default)]
+    pub calibration: Calibration,
+    /// how colors are sent: rgb, xy, hs, or color_temp (xy, with near white colors sent as a color temperature)
+    #[serde(default)]
+    pub color_mode: ColorMode,
+    /// the bulb's gamut (A, B, or C) for clamping xy colors it can't show. leave unset to send unclamped xy
+    pub gamut: Option<Gamut>,
+    /// Zigbee2MQTT base topic of the instance this light is on. defaults to the zigbee2mqtt section's base_topic
+    pub base_topic: Option<String>,
+    /// raw topic to publish to instead of <base_topic>/<light_name>/set
+    pub topic: Option<String>,
+    /// strip settings for WLED lights
+    pub wled: Option<WledConfig>,
+    /// universe, start address, and channel order for DMX lights
+    pub dmx: Option<DmxFixture>,
+    /// device index, zone, and LEDs per segment for OpenRGB lights
+    pub openrgb: Option<OpenRgbLight>,
+    /// MAC, host, and zones per segment for LIFX lights
+    pub lifx: Option<LifxLight>,
+    /// host and port for Yeelight lights
+    pub yeelight: Option<YeelightConfig>,
+    /// host for Govee lights
+    pub govee: Option<GoveeConfig>,
+    /// url, method, and headers for HTTP lights
+    pub http: Option<HttpLight>,
 }
 
+impl LightConfig {
+    pub fn is_segmented(&self) -> bool {
+        !self.segments.is_empty()
+    }
+
+    pub fn base_topic(&self) -> &str {
+        self.base_topic.as_deref().unwrap_or(DEFAULT_BASE_TOPIC)
+    }
+
+    pub fn scale_brightness(&self, brightness: u8) -> u8 {
+        (self.brightness * brightness as f32).min(255.0) as u8
+    }
+
+    /// calibrated rgb and the brightness scaled by the light's brightness setting
+    pub fn color(&self, color: &MessageColor) -> ([u8; 3], u8) {
+        let color = color.calibrated(&self.calibration);
+        ([color.r, color.g, color.b], self.scale_brightness(color.brightness))
+    }
+
+    /// calibrated rgb (0-1) with brightness folded in, for outputs that have no separate brightness
+    pub fn dimmed(&self, color: &MessageColor) -> [f32; 3] {
+        let (rgb, brightness) = self.color(color);
+        rgb.map(|value| value as f32 / 255.0 * (brightness as f32 / 255.0))
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct MessageColor {r: u8, g: u8, b: u8, brightness: u8}
 
 impl MessageColor {
     pub fn new(r: u8, g: u8, b: u8, brightness: u8) -> Self {
         Self { r, g, b, brightness }
     }
+
+    /// color after the light's calibration is applied. brightness is left alone
+    fn calibrated(&self, calibration: &Calibration) -> Self {
+        let (r, g, b) = calibration.apply(self.r, self.g, self.b);
+        Self::new(r, g, b, self.brightness)
+    }
 }
 
-impl From<ZoneColor> for MessageColor {
-    fn from(sample: ZoneColor) -> Self {
+impl MessageColor {
+    /// Converts a screen sample to a light command. Brightness follows the screen through the aesthetics brightness curve,
+    /// and near black samples fade toward the dark color so lights don't show muddy dim colors.
+    pub fn from_sample(sample: ZoneColor, aesthetics: &Aesthetics) -> Self {
         let brightness = (0.299 * sample.r as f32) + (0.587  * sample.g as f32) + (0.114 * sample.b as f32);
 
         let normalized = brightness / 255.0;
-        let amplified = normalized.powf(0.7);
+        let amplified = normalized.powf(aesthetics.brightness_curve) * aesthetics.brightness_factor;
+
+        let final_brightness = (amplified * 255.0).min(255.0).max(aesthetics.min_brightness as f32) as u8;
 
-        let final_brightness = (amplified * 255.0).min(255.0).max(MIN_BRIGHTNESS as f32) as u8;
+        let (sample_r, sample_g, sample_b) = saturate(&sample, aesthetics.saturation_exp);
 
-        let (r, g, b) = if final_brightness < BRIGHTNESS_COLOR_THRESH {
-            let mix_factor = 1.0 - (final_brightness as f32 / BRIGHTNESS_COLOR_THRESH as f32);
+        let (r, g, b) = if final_brightness < aesthetics.dark_threshold {
+            let mix_factor = 1.0 - (final_brightness as f32 / aesthetics.dark_threshold as f32);
+            let [dark_r, dark_g, dark_b] = aesthetics.dark_color;
 
-            let r = (sample.r as f32 * (1.0 - mix_factor) + 250.0 * mix_factor) as u8;
-            let g = (sample.g as f32 * (1.0 - mix_factor) + 210.0 * mix_factor) as u8;
-            let b = (sample.b as f32 * (1.0 - mix_factor) + 190.0 * mix_factor) as u8;
+            let r = (sample_r * (1.0 - mix_factor) + dark_r as f32 * mix_factor) as u8;
+            let g = (sample_g * (1.0 - mix_factor) + dark_g as f32 * mix_factor) as u8;
+            let b = (sample_b * (1.0 - mix_factor) + dark_b as f32 * mix_factor) as u8;
 
             (r, g, b)
         } else {
-           (sample.r, sample.g, sample.b)
+           (sample_r as u8, sample_g as u8, sample_b as u8)
         };
 
         Self::new(r, g, b, final_brightness)
     }
 }
 
-pub struct LightController <'a> {config: LightConfig, client: &'a Client}
-
-impl<'a> LightController<'a> {
-    pub fn new(config: LightConfig, client: &'a Client) -> Self {
-        LightController { config, client }
-    }
-    pub fn get_light_name (&self) -> String {
-        self.config.light_name.clone()
+/// pushes each channel away from the brightest one. exp of 1 leaves the color unchanged
+fn saturate(sample: &ZoneColor, exp: f32) -> (f32, f32, f32) {
+    let (r, g, b) = (sample.r as f32, sample.g as f32, sample.b as f32);
+    let max = r.max(g).max(b);
+    if exp == 1.0 || max == 0.0 {
+        return (r, g, b);
     }
-    fn get_topic (&self) -> String {
-        match self.config.service {
-            LightService::Zigbee2MQTT => format!("zigbee2mqtt/{}/set", self.config.light_name),
-            LightService::ZHA => format!("zigbee2mqtt/{}/set", self.config.light_name), //placeholder for now - just Z2M
-            LightService::HueAPI => format!("zigbee2mqtt/{}/set", self.config.light_name), //placeholder for now - just Z2M
+    let channel = |value: f32| max * (value / max).powf(exp);
+    (channel(r), channel(g), channel(b))
+}
+
+/// Clients lights are driven through, shared by the sinks of every light on them. Home Assistant, the Hue bridge, and
+/// the other network backends are only connected when a light uses them.
+pub struct Backends {
+    pub mqtt: Option<Client>,
+    pub mqtt_states: MqttStates,
+    pub home_assistant: Option<Arc<HomeAssistantClient>>,
+    pub hue: Option<Arc<HueEntertainment>>,
+    pub udp: Option<Arc<UdpOutput>>,
+    pub dmx: Option<Arc<DmxOutput>>,
+    pub openrgb: Option<Arc<OpenRgbClient>>,
+    pub lifx: Option<Arc<Lifx>>,
+    pub yeelight: HashMap<String, Arc<Yeelight>>,   // music mode connections by light_name
+    pub http: Option<Arc<HttpClient>>,
+}
+
+impl Backends {
+    /// starts the backends used by lights. other configured lights are left alone
+    pub fn new(mqtt: Option<Client>, config: &AppConfig, lights: &[LightConfig]) -> Result<Self> {
+        let home_assistant = match &config.home_assistant {
+            _ if !lights.iter().any(|light| matches!(light.service, LightService::ZHA)) => None,
+            Some(home_assistant) => Some(Arc::new(HomeAssistantClient::connect(home_assistant.clone())?)),
+            None => bail!("ZHA lights need a home_assistant section with the url and access token"),
+        };
+
+        // the bridge streams one entertainment configuration at a time
+        let hue_lights: Vec<&LightConfig> = lights.iter()
+            .filter(|light| matches!(light.service, LightService::HueAPI))
+            .collect();
+        let hue = match (hue_lights.as_slice(), &config.hue) {
+            ([], _) => None,
+            ([light], Some(hue)) => Some(Arc::new(HueEntertainment::start(hue.clone(), &light.light_name)?)),
+            ([_], None) => bail!("HueAPI lights need a hue section with the bridge address and keys"),
+            _ => bail!("Only one HueAPI light (entertainment configuration) can stream at a time"),
+        };
+
+        let mut udp_targets = Vec::new();
+        for light in lights {
+            match (&light.service, &light.wled) {
+                (LightService::WLED, Some(wled)) => udp_targets.push((wled.host.as_str(), wled.port)),
+                (LightService::WLED, None) => bail!("WLED light {} needs a wled section with the host and LED count", light.light_name),
+                _ => {}
+            }
+            match (&light.service, &light.govee) {
+                (LightService::Govee, Some(govee_light)) => udp_targets.push((govee_light.host.as_str(), govee::PORT)),
+                (LightService::Govee, None) => bail!("Govee light {} needs a govee section with the host", light.light_name),
+                _ => {}
+            }
+            if matches!(light.service, LightService::DMX) && light.dmx.is_none() {
+                bail!("DMX light {} needs a dmx section with the universe and address", light.light_name);
+            }
         }
-    }
 
-    fn format_payload(&self, color: MessageColor, transition: f32) -> Vec<u8>{
-        let brightness: u8 = (self.config.brightness * color.brightness as f32).min(255.0) as u8;
+        let dmx = match &config.dmx {
+            _ if !lights.iter().any(|light| matches!(light.service, LightService::DMX)) => None,
+            Some(dmx) => Some(Arc::new(DmxOutput::new(dmx.clone(), lights))),
+            None => Some(Arc::new(DmxOutput::new(Default::default(), lights))),
+        };
+        let dmx_targets = dmx.as_ref().map(|dmx| dmx.targets()).unwrap_or_default();
+        udp_targets.extend(dmx_targets.iter().map(|(host, port)| (host.as_str(), *port)));
+        let udp = match udp_targets.is_empty() {
+            true => None,
+            false => Some(Arc::new(UdpOutput::new(udp_targets)?)),
+        };
+
+        let openrgb = match lights.iter().any(|light| matches!(light.service, LightService::OpenRGB)) {
+            true => {
+                let client = OpenRgbClient::connect(&config.openrgb.clone().unwrap_or_default())?;
+                client.take_control(lights)?;
+                Some(Arc::new(client))
+            }
+            false => None,
+        };
 
-        let payload = json!({
-            "color": {
-                "r": color.r,
-                "g": color.g,
-                "b": color.b
-            },
-            "brightness": brightness,
-            "transition": transition
-            });
+        for light in lights.iter().filter(|light| matches!(light.service, LightService::Govee)) {
+            if let (Some(udp), Some(govee_light)) = (&udp, &light.govee) {
+                udp.send(&govee_light.host, govee::PORT, &govee::turn_on())?;
+            }
+        }
 
-        payload.to_string().into_bytes()
-    }
+        let mut yeelight = HashMap::new();
+        for light in lights.iter().filter(|light| matches!(light.service, LightService::Yeelight)) {
+            let settings = light.yeelight.as_ref()
+                .with_context(|| format!("Yeelight light {} needs a yeelight section with the host", light.light_name))?;
+            yeelight.insert(light.light_name.clone(), Arc::new(Yeelight::connect(settings)?));
+        }
 
-    pub fn set_light(&self, color: MessageColor, transition: Option<f32>) -> Result<()> {
-        let t = transition.unwrap_or(0.0);
-        let light = self.get_topic();
-        let payload = self.format_payload(color, t);
+        let lifx = match lights.iter().any(|light| matches!(light.service, LightService::LIFX)) {
+            true => Some(Arc::new(Lifx::discover(lights)?)),
+            false => None,
+        };
 
-        self.client.try_publish(&light, QoS::AtMostOnce, false, payload)
-            .with_context(|| format!("Failed to publish to topic {}", light))?;
-        Ok(())
+        if let Some(light) = lights.iter().find(|light| matches!(light.service, LightService::HTTP) && light.http.is_none()) {
+            bail!("HTTP light {} needs an http section with the url", light.light_name);
+        }
+        let http = lights.iter()
+            .any(|light| matches!(light.service, LightService::HTTP))
+            .then(|| Arc::new(HttpClient::start()));
+
+        Ok(Backends { mqtt, mqtt_states: MqttStates::default(), home_assistant, hue, udp, dmx, openrgb, lifx, yeelight, http })
+    }
+
+    /// output for one light, holding the shared client of its backend
+    pub fn sink(&self, config: LightConfig) -> Result<Box<dyn LightSink>> {
+        let sink: Box<dyn LightSink> = match config.service {
+            LightService::Zigbee2MQTT | LightService::Tasmota | LightService::ESPHome => {
+                let mqtt = self.mqtt.clone().context("MQTT is not connected")?;
+                Box::new(MqttSink::new(config, mqtt, self.mqtt_states.clone()))
+            }
+            LightService::ZHA => {
+                let client = self.home_assistant.clone().context("Home Assistant is not connected")?;
+                Box::new(HomeAssistantSink::new(config, client))
+            }
+            LightService::HueAPI => {
+                let hue = self.hue.clone().context("Hue bridge is not connected")?;
+                Box::new(HueSink::new(config, hue))
+            }
+            LightService::WLED | LightService::Govee | LightService::DMX => {
+                let udp = self.udp.clone().context("UDP output is not open")?;
+                Box::new(UdpSink::new(config, udp, self.dmx.clone()))
+            }
+            LightService::OpenRGB => {
+                let openrgb = self.openrgb.clone().context("OpenRGB is not connected")?;
+                Box::new(OpenRgbSink::new(config, openrgb))
+            }
+            LightService::LIFX => {
+                let lifx = self.lifx.clone().context("LIFX lights were not discovered")?;
+                Box::new(LifxSink::new(config, lifx))
+            }
+            LightService::Yeelight => {
+                let yeelight = self.yeelight.get(&config.light_name).cloned().context("Yeelight is not connected")?;
+                Box::new(YeelightSink::new(config, yeelight))
+            }
+            LightService::HTTP => {
+                let http = self.http.clone().context("HTTP client is not running")?;
+                Box::new(HttpSink::new(config, http))
+            }
+        };
+        Ok(sink)
     }
 }
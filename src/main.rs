@@ -1,76 +1,428 @@
+use std::io::{self, BufRead, Write};
 use std::thread;
-use anyhow::Result;
-use rumqttc::Client;
-use std::collections::HashMap;
+use std::time::Duration;
+use std::sync::atomic::Ordering;
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::collections::{HashMap, HashSet};
 
-use crate::capture::{ZoneConfig, ZoneSampler, new_screen};
-use crate::config::AppConfig;
-use crate::lights::*;
-use crate::sync::{AdaptiveRate, SyncEngine, ZonePair};
-mod config;
-mod lights;
-mod capture;
-mod sync;
+use zync_lights::calibration::REFERENCE_COLORS;
+use zync_lights::capture::{ZoneConfig, ZoneSampler, new_screen};
+use zync_lights::cli::{CalibrateArgs, Cli, Command, GenerateArgs, LightsCommand, ZonesCommand};
+use zync_lights::config::AppConfig;
+use zync_lights::discovery::{DISCOVERY_TIMEOUT, Discovery, LightKind};
+use zync_lights::hue::nearest_zones;
+use zync_lights::layout::BorderLayout;
+use zync_lights::letterbox::LetterboxDetector;
+use zync_lights::openrgb::OpenRgbClient;
+use zync_lights::scenecut::SceneCutDetector;
+use zync_lights::sink::ZoneUpdate;
+use zync_lights::lights::*;
+use zync_lights::sync::{AdaptiveRate, SyncEngine, ZonePair, ZoneWeight};
 
+const SHUTDOWN_DELAY: Duration = Duration::from_millis(500);     // restored MQTT states are sent by the notification thread
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        None => run_sync(),
+        Some(Command::Zones { command: ZonesCommand::Generate(args) }) => generate_zones(args),
+        Some(Command::Lights { command: LightsCommand::List { all } }) => list_lights(all),
+        Some(Command::Lights { command: LightsCommand::Openrgb }) => list_openrgb_devices(),
+        Some(Command::Calibrate(args)) => calibrate(args),
+    }
+}
+
+fn run_sync() -> Result<()> {
 
     // Load configuratoin and initialize all objects to pass into sync engine
     let config = AppConfig::load()?;
-    let (client, mut connection) = config.mqtt.create_client()?;
+    let (client, mut connection) = config.mqtt_client(&config.lights)?.unzip();
+
+    // check the configured lights against what Zigbee2MQTT knows about. sync still runs if the bridge doesn't answer.
+    // setups without Zigbee2MQTT lights skip this instead of waiting on a bridge that isn't there
+    let base_topics = config.verified_base_topics();
+    if !base_topics.is_empty()
+        && let (Some(client), Some(connection)) = (&client, &mut connection)
+    {
+        match Discovery::discover(client, connection, &base_topics, DISCOVERY_TIMEOUT) {
+            Ok(discovery) => discovery.verify(&config.lights)?,
+            Err(error) => println!("Skipping light check: {:#}", error),
+        }
+    }
+
     let adaptive_rate = AdaptiveRate::new_from_fps(
                             config.performance.max_fps,
                             config.performance.max_delay,
                             config.performance.percent_thread_work,
     );
-    let zone_map = extract_zones_and_lights(config.lights, config.zones, &client)?;
+    let backends = Backends::new(client, &config, &config.lights)?;
+    let (zone_samplers, zone_map) = extract_zones_and_lights(config.lights, config.zones, config.linear_light, &backends)?;
     let screen = new_screen()?;
+    let letterbox = config.letterbox.enabled.then(|| LetterboxDetector::new(config.letterbox));
+    let scene_cut = config.scene_cut.enabled.then(|| SceneCutDetector::new(config.scene_cut));
 
     // create SyncEngine -- this is the main loop that runs the program
-    let mut engine = SyncEngine::new(screen, zone_map, adaptive_rate, config.performance, config.downsample_factor);
+    let mut engine = SyncEngine::new(screen, zone_samplers, zone_map, adaptive_rate, config.performance, config.downsample_factor, config.aesthetics.resolve())
+        .with_letterbox(letterbox)
+        .with_scene_cut(scene_cut);
 
-    // start notification thread
-    thread::spawn(move || {
-        for _notification in connection.iter().enumerate() {
-            // println!("Notification = {:?}", notification);
-        }
-    });
+    // start notification thread. it also collects the light states saved before syncing
+    if let Some(connection) = connection {
+        let states = backends.mqtt_states.clone();
+        thread::spawn(move || states.listen(connection));
+    }
+
+    // Ctrl+C stops the engine, which puts lights back the way they were
+    let stop = engine.stop_handle();
+    ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))?;
 
     // start main thread
-    engine.run()?;
+    let result = engine.run();
+
+    // give the notification thread time to send the restored states, also when syncing stopped on an error
+    thread::sleep(SHUTDOWN_DELAY);
+    result
+}
+
+/// builds border zones from the light arrangement passed on the command line and writes them into the config
+fn generate_zones(args: GenerateArgs) -> Result<()> {
+    let config = AppConfig::load()?;
+    let light_names: Vec<String> = config.lights.iter().map(|light| light.light_name.clone()).collect();
+
+    let layout = BorderLayout {
+        top: args.top,
+        right: args.right,
+        bottom: args.bottom,
+        left: args.left,
+        depth: args.depth,
+    };
+    let zones = layout.generate(args.width, args.height, &light_names)?;
+
+    if args.print {
+        print!("{}", serde_yaml::to_string(&zones)?);
+        return Ok(());
+    }
+
+    AppConfig::write_zones(&zones)?;
+    println!("Wrote {} zones to {:?}", zones.len(), AppConfig::path()?);
     Ok(())
 }
 
-fn extract_zones_and_lights(
-    lights: Vec<LightConfig>,
-    zones: Vec<ZoneConfig>,
-    client: &Client,
-) -> Result<Vec<ZonePair<'_>>>{
+/// prints the lights and groups Zigbee2MQTT publishes on its bridge topics
+fn list_lights(all: bool) -> Result<()> {
+    let config = AppConfig::load()?;
+    let mqtt = config.mqtt.as_ref().context("Listing lights needs an mqtt section with the broker Zigbee2MQTT is on")?;
+    let (client, mut connection) = mqtt.create_client()?;
+    let base_topics = config.base_topics();
+    let discovery = Discovery::discover(&client, &mut connection, &base_topics, DISCOVERY_TIMEOUT)?;
 
-    //initialize LightController instances and assemble in light_controllers hashmap
-    let mut light_controllers = HashMap::new();
+    let (shown, hidden): (Vec<_>, Vec<_>) = discovery.lights.iter()
+        .partition(|light| all || light.capabilities.supports_color());
 
-    for light_config in lights {
-        let light_controller = LightController::new(light_config, client);
-        light_controllers.insert(light_controller.get_light_name(), light_controller);
+    for light in shown {
+        let kind = match light.kind {
+            LightKind::Device => "light",
+            LightKind::Group => "group",
+        };
+        // only show which instance a light is on when there is more than one
+        if base_topics.len() > 1 {
+            println!("{:<32} {:<24} {:<6} {}", light.name, light.base_topic, kind, light.capabilities.describe());
+        } else {
+            println!("{:<32} {:<6} {}", light.name, kind, light.capabilities.describe());
+        }
+    }
+    if !hidden.is_empty() {
+        println!("{} lights without color support hidden. Use --all to show them.", hidden.len());
     }
+    Ok(())
+}
+
+/// prints the devices on the OpenRGB SDK server with their zones and LED counts
+fn list_openrgb_devices() -> Result<()> {
+    let config = AppConfig::load()?;
+    let client = OpenRgbClient::connect(&config.openrgb.unwrap_or_default())?;
+
+    for (index, controller) in client.controllers().iter().enumerate() {
+        println!("{:<3} {:<40} {} LEDs", index, controller.name, controller.leds);
+        for zone in &controller.zones {
+            println!("      {:<38} {} LEDs", zone.name, zone.leds.len());
+        }
+    }
+    Ok(())
+}
+
+/// steps a light through reference colors, letting the user tune its gains and gamma until it matches the reference light
+fn calibrate(args: CalibrateArgs) -> Result<()> {
+    let config = AppConfig::load()?;
+    let find_light = |name: &str| {
+        config.lights.iter()
+            .find(|light| light.light_name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Light {} not found in config", name))
+    };
+    let light_config = find_light(&args.light)?;
+    let reference_config = args.reference.as_deref().map(find_light).transpose()?;
+
+    // segmented lights show the reference color on every segment
+    let light_segments = light_config.segments.len().max(1);
+    let reference_segments = reference_config.as_ref().map_or(1, |config| config.segments.len().max(1));
+    let mut calibration = light_config.calibration;
+
+    // only the light being calibrated and its reference are started
+    let calibrated_lights: Vec<LightConfig> = std::iter::once(light_config.clone()).chain(reference_config.clone()).collect();
+    let (client, connection) = config.mqtt_client(&calibrated_lights)?.unzip();
+    if let Some(mut connection) = connection {
+        thread::spawn(move || {
+            for _notification in connection.iter().enumerate() {}
+        });
+    }
+    let backends = Backends::new(client, &config, &calibrated_lights)?;
+    let mut light = backends.sink(light_config)?;
+    let mut reference = reference_config.map(|config| backends.sink(config)).transpose()?;
+
+    println!("Calibrating {}. Commands:", args.light);
+    println!("  <enter>          next reference color");
+    println!("  r|g|b <gain>     set a channel gain, e.g. \"b 0.85\"");
+    println!("  gamma <value>    set gamma, e.g. \"gamma 1.2\"");
+    println!("  save             write the calibration to the config and exit");
+    println!("  quit             exit without saving");
+
+    let mut input = io::stdin().lock().lines();
+    let mut step = 0;
+    loop {
+        let (color_name, [r, g, b]) = REFERENCE_COLORS[step % REFERENCE_COLORS.len()];
+        let updates = |segments: usize| (0..segments)
+            .map(|_| ZoneUpdate { color: MessageColor::new(r, g, b, 255), transition: 0.0 })
+            .collect::<Vec<_>>();
+        light.set_calibration(calibration);
+        light.send(&updates(light_segments))?;
+        light.flush()?;
+        if let Some(reference) = &mut reference {
+            reference.send(&updates(reference_segments))?;
+            reference.flush()?;
+        }
+
+        print!("{} | gains {:?} gamma {} > ", color_name, calibration.gains, calibration.gamma);
+        io::stdout().flush()?;
+        let Some(line) = input.next().transpose()? else { break };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => step += 1,
+            ["save"] => {
+                AppConfig::write_calibration(&args.light, &calibration)?;
+                println!("Wrote calibration for {} to {:?}", args.light, AppConfig::path()?);
+                break;
+            }
+            ["quit"] => break,
+            [setting, value] => {
+                let Ok(value) = value.parse::<f32>() else {
+                    println!("Not a number: {}", value);
+                    continue;
+                };
+                match *setting {
+                    "r" => calibration.gains[0] = value,
+                    "g" => calibration.gains[1] = value,
+                    "b" => calibration.gains[2] = value,
+                    "gamma" => calibration.gamma = value,
+                    _ => println!("Unknown setting: {}", setting),
+                }
+            }
+            _ => println!("Unknown command: {}", line),
+        }
+    }
+    Ok(())
+}
+
+/// Collects the zones that drive each light. A zone can drive several lights and a light can blend several zones.
+/// Zones listed in a light's segments, or picked from by a Hue entertainment light, don't need lights of their own.
+fn collect_light_zones(zone_samplers: &[ZoneSampler], lights: &[LightConfig]) -> Result<HashMap<String, Vec<ZoneWeight>>> {
+    let hue_uses_all_zones = lights.iter().any(|light| matches!(light.service, LightService::HueAPI) && !light.is_segmented());
+    let segment_zones: HashSet<&str> = lights.iter()
+        .flat_map(|light| light.segments.iter().map(String::as_str))
+        .collect();
+
+    let mut light_zones: HashMap<String, Vec<ZoneWeight>> = HashMap::new();
+
+    for (index, zone) in zone_samplers.iter().enumerate() {
+        let zone_lights = zone.get_lights();
+        let segment_only = zone.get_name().is_some_and(|name| segment_zones.contains(name.as_str()));
+        if zone_lights.is_empty() && !hue_uses_all_zones && !segment_only {
+            bail!("Zone {} has no lights. Set light_name or lights.", zone.get_label());
+        }
+
+        for zone_light in zone_lights {
+            match lights.iter().find(|light| light.light_name == zone_light.light_name) {
+                None => bail!("Zone {} references unknown light: {}", zone.get_label(), zone_light.light_name),
+                Some(light) if light.is_segmented() => bail!("Zone {} references segmented light {}. List the zone in the light's segments instead.", zone.get_label(), light.light_name),
+                Some(light) if matches!(light.service, LightService::HueAPI) => bail!("Zone {} references Hue entertainment light {}. Its channels are matched to zones by position, or list zones in its segments.", zone.get_label(), light.light_name),
+                Some(_) => {}
+            }
+            if zone_light.weight <= 0.0 {
+                bail!("Zone {} has a non-positive weight for light {}", zone.get_label(), zone_light.light_name);
+            }
+            light_zones.entry(zone_light.light_name)
+                .or_default()
+                .push(ZoneWeight { zone: index, weight: zone_light.weight });
+        }
+    }
+    Ok(light_zones)
+}
+
+fn extract_zones_and_lights(
+    lights: Vec<LightConfig>,
+    zones: Vec<ZoneConfig>,
+    linear_light: bool,
+    backends: &Backends,
+) -> Result<(Vec<ZoneSampler>, Vec<ZonePair>)>{
 
     //initialize ZoneSample instances, and assemble into zone_samplers vector
     let mut zone_samplers: Vec<ZoneSampler> = Vec::new();
 
     for zone in zones {
-        let zone_sampler = ZoneSampler::new(zone)?;
+        let zone_sampler = ZoneSampler::new(zone, linear_light)?;
         zone_samplers.push(zone_sampler);
     }
 
-    //iterate through zone_samplers, look up associated light_controller, and push into zone_map<ZonePair> vector
+    let mut light_zones = collect_light_zones(&zone_samplers, &lights)?;
+
+    //create a sink for every light with zones and pair them in the order lights are configured
     let mut zone_map: Vec<ZonePair> = Vec::new();
 
-    for zone in zone_samplers {
-        let light_controller = light_controllers.remove(&zone.get_light_name())
-            .ok_or_else(|| anyhow::anyhow!("Zone references unknown light: {}", &zone.get_light_name()))?;
-        let pair = ZonePair::new(zone, light_controller, None);
-        zone_map.push(pair);
+    for light_config in lights {
+        //segmented lights take one named zone per segment, in order
+        if light_config.is_segmented() {
+            let mut segments = Vec::new();
+            for zone_name in &light_config.segments {
+                let index = zone_samplers.iter()
+                    .position(|zone| zone.get_name().as_deref() == Some(zone_name.as_str()))
+                    .ok_or_else(|| anyhow::anyhow!("Light {} references unknown zone: {}", light_config.light_name, zone_name))?;
+                segments.push(vec![ZoneWeight { zone: index, weight: 1.0 }]);
+            }
+            if let (LightService::HueAPI, Some(hue)) = (&light_config.service, &backends.hue)
+                && segments.len() != hue.channels().len()
+            {
+                bail!("Light {} lists {} segments but its entertainment configuration has {} channels", light_config.light_name, segments.len(), hue.channels().len());
+            }
+            if let Some(wled) = &light_config.wled {
+                wled.segment_ranges(segments.len())?;
+            }
+            if let Some(dmx) = &light_config.dmx
+                && let Some(output) = &backends.dmx
+            {
+                dmx.validate(output.protocol(), segments.len())?;
+            }
+            if let Some(openrgb) = &backends.openrgb
+                && matches!(light_config.service, LightService::OpenRGB)
+            {
+                openrgb.segment_ranges(&light_config, segments.len())?;
+            }
+            if let Some(lifx) = &backends.lifx
+                && matches!(light_config.service, LightService::LIFX)
+            {
+                lifx.segment_ranges(&light_config, segments.len())?;
+            }
+            let name = light_config.light_name.clone();
+            let sink = backends.sink(light_config)?;
+            if !sink.capabilities().segments {
+                println!("Light {} can't show segments. Only its first segment is used", name);
+            }
+            zone_map.push(ZonePair::new(segments, sink, None));
+            continue;
+        }
+
+        //Hue entertainment channels take the zone nearest to their position in the room
+        if let (LightService::HueAPI, Some(hue)) = (&light_config.service, &backends.hue) {
+            if zone_samplers.is_empty() {
+                bail!("Hue entertainment light {} has no zones to pick from. Add zones to the config.", light_config.light_name);
+            }
+            let areas: Vec<_> = zone_samplers.iter().map(|zone| zone.get_area()).collect();
+            let segments = nearest_zones(hue.channels(), &areas).into_iter()
+                .map(|zone| vec![ZoneWeight { zone, weight: 1.0 }])
+                .collect();
+            zone_map.push(ZonePair::new(segments, backends.sink(light_config)?, None));
+            continue;
+        }
+
+        match light_zones.remove(&light_config.light_name) {
+            Some(weights) => {
+                if let Some(wled) = &light_config.wled {
+                    wled.segment_ranges(1)?;
+                }
+                if let Some(dmx) = &light_config.dmx
+                    && let Some(output) = &backends.dmx
+                {
+                    dmx.validate(output.protocol(), 1)?;
+                }
+                if let Some(openrgb) = &backends.openrgb
+                    && matches!(light_config.service, LightService::OpenRGB)
+                {
+                    openrgb.segment_ranges(&light_config, 1)?;
+                }
+                zone_map.push(ZonePair::new(vec![weights], backends.sink(light_config)?, None));
+            }
+            None => println!("Light {} is not used by any zone", light_config.light_name),
+        }
+    }
+    Ok((zone_samplers, zone_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zones(yaml: &str) -> Vec<ZoneSampler> {
+        let configs: Vec<ZoneConfig> = serde_yaml::from_str(yaml).unwrap();
+        configs.into_iter().map(|config| ZoneSampler::new(config, false).unwrap()).collect()
+    }
+
+    fn lights(yaml: &str) -> Vec<LightConfig> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn segment_zones_dont_need_lights() {
+        let zones = zones("
+            - { name: left, x: 0, y: 0, width: 960, height: 1080 }
+            - { name: right, x: 960, y: 0, width: 960, height: 1080 }
+            - { name: top, x: 0, y: 0, width: 1920, height: 100, light_name: lamp }");
+        let lights = lights("
+            - { service: Zigbee2MQTT, light_name: gradient, brightness: 1.0, segments: [left, right] }
+            - { service: Zigbee2MQTT, light_name: lamp, brightness: 1.0 }");
+
+        let light_zones = collect_light_zones(&zones, &lights).unwrap();
+        assert_eq!(light_zones.len(), 1);
+        assert_eq!(light_zones["lamp"].iter().map(|weight| weight.zone).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn zones_pair_with_lights_many_to_many() {
+        let zones = zones("
+            - { x: 0, y: 0, width: 960, height: 1080, light_name: left_lamp, lights: [{ light_name: tv, weight: 2.0 }] }
+            - { x: 960, y: 0, width: 960, height: 1080, lights: [{ light_name: tv }, { light_name: right_lamp }] }");
+        let lights = lights("
+            - { service: Zigbee2MQTT, light_name: tv, brightness: 1.0 }
+            - { service: Zigbee2MQTT, light_name: left_lamp, brightness: 1.0 }
+            - { service: Zigbee2MQTT, light_name: right_lamp, brightness: 1.0 }");
+
+        let light_zones = collect_light_zones(&zones, &lights).unwrap();
+        let weights = |light: &str| light_zones[light].iter().map(|weight| (weight.zone, weight.weight)).collect::<Vec<_>>();
+        assert_eq!(weights("tv"), [(0, 2.0), (1, 1.0)]);
+        assert_eq!(weights("left_lamp"), [(0, 1.0)]);
+        assert_eq!(weights("right_lamp"), [(1, 1.0)]);
+    }
+
+    #[test]
+    fn zones_without_lights_or_segments_are_rejected() {
+        let zones = zones("
+            - { name: left, x: 0, y: 0, width: 960, height: 1080 }
+            - { name: stray, x: 960, y: 0, width: 960, height: 1080 }");
+        let lights = lights("
+            - { service: Zigbee2MQTT, light_name: gradient, brightness: 1.0, segments: [left] }");
+
+        let error = collect_light_zones(&zones, &lights).unwrap_err();
+        assert!(error.to_string().contains("stray"), "{}", error);
     }
-    Ok(zone_map)
 }
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use rumqttc::{Client, Connection, Event, Packet, QoS};
+use serde_json::{Value, json};
+
+use crate::lights::{LightConfig, LightService, MessageColor, SegmentMode};
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// JSON messages received on subscribed topics, first message per topic. Filled by the thread running the MQTT
+/// connection, so light states can be read while the engine publishes.
+#[derive(Clone, Default)]
+pub struct MqttStates(Arc<Mutex<HashMap<String, Value>>>);
+
+impl MqttStates {
+    /// runs the MQTT connection until the client is dropped
+    pub fn listen(&self, mut connection: Connection) {
+        for notification in connection.iter() {
+            if let Ok(Event::Incoming(Packet::Publish(publish))) = notification
+                && let Ok(state) = serde_json::from_slice::<Value>(&publish.payload)
+            {
+                let mut states = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                states.entry(publish.topic.clone()).or_insert(state);
+            }
+        }
+    }
+
+    fn get(&self, topic: &str) -> Option<Value> {
+        let states = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        states.get(topic).cloned()
+    }
+}
+
+/// Zigbee2MQTT, Tasmota, and ESPHome lights, published through the broker in the mqtt section
+pub struct MqttSink {
+    config: LightConfig,
+    client: Client,
+    states: MqttStates,
+    snapshot: Option<Value>,
+}
+
+impl MqttSink {
+    pub fn new(config: LightConfig, client: Client, states: MqttStates) -> Self {
+        MqttSink { config, client, states, snapshot: None }
+    }
+
+    fn topic(&self) -> String {
+        if let Some(topic) = &self.config.topic {
+            return topic.clone();
+        }
+        match self.config.service {
+            LightService::Tasmota => format!("cmnd/{}/Color", self.config.light_name),
+            // ESPHome light_name is <node name>/<light object id>. a bare node name is used for both
+            LightService::ESPHome => match self.config.light_name.split_once('/') {
+                Some((node, light)) => format!("{}/light/{}/command", node, light),
+                None => format!("{}/light/{}/command", self.config.light_name, self.config.light_name),
+            },
+            _ => format!("{}/{}/set", self.config.base_topic(), self.config.light_name),
+        }
+    }
+
+    /// formats the payload for a light. Segmented lights get one color per segment, regular lights use the first color.
+    fn payload(&self, colors: &[MessageColor], transition: f32) -> Vec<u8> {
+        match self.config.service {
+            LightService::Tasmota => return self.tasmota_payload(&colors[0]),
+            LightService::ESPHome => return self.esphome_payload(&colors[0], transition),
+            _ => {}
+        }
+
+        if self.config.is_segmented() {
+            return self.segment_payload(colors, transition);
+        }
+
+        let ([r, g, b], brightness) = self.config.color(&colors[0]);
+        let (color_key, color_value) = self.config.color_mode.payload(r, g, b, self.config.gamut);
+
+        let mut payload = json!({
+            "brightness": brightness,
+            "transition": transition
+            });
+        payload[color_key] = color_value;
+
+        payload.to_string().into_bytes()
+    }
+
+    /// Tasmota's Color command takes r,g,b and sets its dimmer from the brightest channel, so brightness is folded in.
+    /// fades are Tasmota's own Fade and Speed settings
+    fn tasmota_payload(&self, color: &MessageColor) -> Vec<u8> {
+        let [r, g, b] = self.config.dimmed(color).map(|value| (value * 255.0).round() as u8);
+        format!("{},{},{}", r, g, b).into_bytes()
+    }
+
+    /// ESPHome's MQTT JSON light schema
+    fn esphome_payload(&self, color: &MessageColor, transition: f32) -> Vec<u8> {
+        let ([r, g, b], brightness) = self.config.color(color);
+        json!({
+            "state": "ON",
+            "color": { "r": r, "g": g, "b": b },
+            "brightness": brightness,
+            "transition": transition
+        }).to_string().into_bytes()
+    }
+
+    fn segment_payload(&self, colors: &[MessageColor], transition: f32) -> Vec<u8> {
+        let colors: Vec<([u8; 3], u8)> = colors.iter().map(|color| self.config.color(color)).collect();
+        let payload = match self.config.segment_mode {
+            // gradient lights only take one brightness, so use the brightest segment
+            SegmentMode::Gradient => {
+                let brightness = colors.iter().map(|(_, brightness)| *brightness).max().unwrap_or_default();
+                json!({
+                    "gradient": colors.iter().map(|([r, g, b], _)| format!("#{:02x}{:02x}{:02x}", r, g, b)).collect::<Vec<String>>(),
+                    "brightness": brightness,
+                    "transition": transition
+                })
+            }
+            SegmentMode::Endpoints => {
+                let mut payload = json!({ "transition": transition });
+                for (i, ([r, g, b], brightness)) in colors.iter().enumerate() {
+                    let endpoint = i + 1;
+                    payload[format!("state_l{}", endpoint)] = json!("ON");
+                    let (color_key, color_value) = self.config.color_mode.payload(*r, *g, *b, self.config.gamut);
+                    payload[format!("{}_l{}", color_key, endpoint)] = color_value;
+                    payload[format!("brightness_l{}", endpoint)] = json!(brightness);
+                }
+                payload
+            }
+        };
+
+        payload.to_string().into_bytes()
+    }
+
+    /// Zigbee2MQTT publishes a light's state on <base_topic>/<light_name>. lights with a raw topic have no known state topic
+    fn state_topic(&self) -> Option<String> {
+        match (&self.config.service, &self.config.topic) {
+            (LightService::Zigbee2MQTT, None) => Some(format!("{}/{}", self.config.base_topic(), self.config.light_name)),
+            _ => None,
+        }
+    }
+}
+
+impl LightSink for MqttSink {
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let colors: Vec<MessageColor> = updates.iter().map(|update| update.color).collect();
+        // segmented lights share one transition, so use the fastest
+        let transition = updates.iter().map(|update| update.transition).fold(f32::MAX, f32::min);
+        let topic = self.topic();
+
+        self.client.try_publish(&topic, QoS::AtMostOnce, false, self.payload(&colors, transition))
+            .with_context(|| format!("Failed to publish to topic {}", topic))?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            segments: matches!(self.config.service, LightService::Zigbee2MQTT),
+            every_frame: false,
+        }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+
+    /// asks Zigbee2MQTT for the light's state and waits for it on the state topic
+    fn snapshot(&mut self) -> Result<()> {
+        let Some(state_topic) = self.state_topic() else { return Ok(()) };
+        self.client.subscribe(state_topic.as_str(), QoS::AtMostOnce)?;
+        let request = json!({ "state": "", "brightness": "", "color": "", "color_temp": "" });
+        self.client.publish(format!("{}/get", state_topic), QoS::AtMostOnce, false, request.to_string())?;
+
+        let deadline = Instant::now() + SNAPSHOT_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(state) = self.states.get(&state_topic) {
+                self.snapshot = Some(state);
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        println!("{} didn't report its state. It won't be restored", self.config.light_name);
+        Ok(())
+    }
+
+    /// sets the saved on/off state, brightness, and color or color temperature
+    fn restore(&mut self) -> Result<()> {
+        let (Some(state), Some(state_topic)) = (&self.snapshot, self.state_topic()) else { return Ok(()) };
+
+        let mut payload = json!({ "state": state["state"], "transition": 1 });
+        if state["state"] == "ON" {
+            payload["brightness"] = state["brightness"].clone();
+            match state["color_mode"].as_str() {
+                Some("color_temp") => payload["color_temp"] = state["color_temp"].clone(),
+                _ if state["color"].is_object() => payload["color"] = state["color"].clone(),
+                _ => {}
+            }
+        }
+        self.client.publish(format!("{}/set", state_topic), QoS::AtMostOnce, false, payload.to_string())
+            .with_context(|| format!("Failed to restore {}", self.config.light_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::MqttOptions;
+
+    /// the client is never polled, so nothing connects to the broker
+    fn sink(yaml: &str) -> MqttSink {
+        let (client, _connection) = Client::new(MqttOptions::new("test", "127.0.0.1", 1883), 10);
+        MqttSink::new(serde_yaml::from_str(yaml).unwrap(), client, MqttStates::default())
+    }
+
+    #[test]
+    fn zigbee2mqtt_lights_use_their_base_topic() {
+        assert_eq!(sink("{ service: Zigbee2MQTT, light_name: lamp, brightness: 1.0 }").topic(), "zigbee2mqtt/lamp/set");
+        let sink = sink("{ service: Zigbee2MQTT, light_name: lamp, brightness: 1.0, base_topic: upstairs }");
+        assert_eq!(sink.topic(), "upstairs/lamp/set");
+        assert_eq!(sink.state_topic().as_deref(), Some("upstairs/lamp"));
+    }
+
+    #[test]
+    fn raw_topic_overrides_the_generated_one() {
+        let sink = sink("{ service: Zigbee2MQTT, light_name: lamp, brightness: 1.0, base_topic: upstairs, topic: custom/lamp/cmd }");
+        assert_eq!(sink.topic(), "custom/lamp/cmd");
+        // there's no known state topic to snapshot from
+        assert_eq!(sink.state_topic(), None);
+    }
+
+    #[test]
+    fn tasmota_gets_r_g_b_on_its_color_command() {
+        let sink = sink("{ service: Tasmota, light_name: shelf, brightness: 1.0 }");
+        assert_eq!(sink.topic(), "cmnd/shelf/Color");
+        // brightness is folded into the channels
+        let payload = sink.payload(&[MessageColor::new(255, 128, 0, 128)], 0.5);
+        assert_eq!(String::from_utf8(payload).unwrap(), "128,64,0");
+    }
+
+    #[test]
+    fn esphome_topic_splits_node_and_light() {
+        assert_eq!(sink("{ service: ESPHome, light_name: desk/strip, brightness: 1.0 }").topic(), "desk/light/strip/command");
+        assert_eq!(sink("{ service: ESPHome, light_name: desk, brightness: 1.0 }").topic(), "desk/light/desk/command");
+    }
+
+    #[test]
+    fn esphome_gets_the_json_light_schema() {
+        let sink = sink("{ service: ESPHome, light_name: desk, brightness: 0.5 }");
+        let payload: Value = serde_json::from_slice(&sink.payload(&[MessageColor::new(255, 128, 0, 200)], 0.25)).unwrap();
+        assert_eq!(payload, json!({ "state": "ON", "color": { "r": 255, "g": 128, "b": 0 }, "brightness": 100, "transition": 0.25 }));
+    }
+}
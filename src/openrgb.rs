@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::lights::{LightConfig, LightService};
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+use crate::wled::split_leds;
+
+const MAGIC: &[u8; 4] = b"ORGB";
+const PROTOCOL_VERSION: u32 = 1;                // newest controller data layout we parse. the server sends older layouts on request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const VERSION_TIMEOUT: Duration = Duration::from_secs(1);   // servers older than protocol 1 never answer the version request
+
+const REQUEST_CONTROLLER_COUNT: u32 = 0;
+const REQUEST_CONTROLLER_DATA: u32 = 1;
+const REQUEST_PROTOCOL_VERSION: u32 = 40;
+const SET_CLIENT_NAME: u32 = 50;
+const UPDATE_LEDS: u32 = 1050;
+const SET_CUSTOM_MODE: u32 = 1100;
+
+/// Connection to an OpenRGB SDK server. Start the server in OpenRGB's SDK Server tab or with `openrgb --server`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OpenRgbConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_name: String,        // shown in OpenRGB's list of SDK clients
+}
+
+impl Default for OpenRgbConfig {
+    fn default() -> Self {
+        OpenRgbConfig {
+            host: "127.0.0.1".to_string(),
+            port: 6742,
+            client_name: "zync".to_string(),
+        }
+    }
+}
+
+/// Which LEDs of an OpenRGB device a light drives. The light_name is the device name.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OpenRgbLight {
+    pub device: Option<usize>,      // device index from `zync lights openrgb`, for identical devices sharing a name
+    pub zone: Option<String>,       // zone of the device, e.g. "Keyboard" or "Fan 1". leave unset to use every LED
+    /// LEDs per segment in segment order. leave empty to split the LEDs evenly between segments
+    #[serde(default)]
+    pub segment_leds: Vec<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub name: String,
+    pub leds: Range<usize>,         // LED indexes within the device
+}
+
+#[derive(Debug, Clone)]
+pub struct Controller {
+    pub name: String,
+    pub zones: Vec<Zone>,
+    pub leds: usize,
+}
+
+/// OpenRGB SDK client. Lights write their LEDs into per device buffers during the frame, and devices that changed
+/// get one UpdateLEDs packet when the frame is flushed.
+pub struct OpenRgbClient {
+    stream: Mutex<TcpStream>,
+    controllers: Vec<Controller>,
+    buffers: Mutex<BTreeMap<usize, DeviceBuffer>>,
+}
+
+struct DeviceBuffer {
+    colors: Vec<[u8; 3]>,
+    changed: bool,
+}
+
+impl OpenRgbClient {
+    /// connects and lists the server's devices
+    pub fn connect(config: &OpenRgbConfig) -> Result<Self> {
+        let mut stream = TcpStream::connect((config.host.as_str(), config.port))
+            .with_context(|| format!("Failed to connect to OpenRGB at {}:{}", config.host, config.port))?;
+        stream.set_nodelay(true)?;
+
+        let mut name = config.client_name.clone().into_bytes();
+        name.push(0);
+        write_packet(&mut stream, 0, SET_CLIENT_NAME, &name)?;
+
+        // servers answer with their own version and we use the older of the two
+        write_packet(&mut stream, 0, REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_le_bytes())?;
+        stream.set_read_timeout(Some(VERSION_TIMEOUT))?;
+        let version = match read_response(&mut stream, REQUEST_PROTOCOL_VERSION) {
+            Ok(data) => Reader::new(&data).u32()?.min(PROTOCOL_VERSION),
+            Err(error) if is_timeout(&error) => 0,
+            Err(error) => return Err(error),
+        };
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+
+        write_packet(&mut stream, 0, REQUEST_CONTROLLER_COUNT, &[])?;
+        let count = Reader::new(&read_response(&mut stream, REQUEST_CONTROLLER_COUNT)?).u32()?;
+
+        let request = match version {
+            0 => Vec::new(),
+            _ => version.to_le_bytes().to_vec(),
+        };
+        let mut controllers = Vec::new();
+        for index in 0..count {
+            write_packet(&mut stream, index, REQUEST_CONTROLLER_DATA, &request)?;
+            let data = read_response(&mut stream, REQUEST_CONTROLLER_DATA)?;
+            controllers.push(parse_controller(&data, version)
+                .with_context(|| format!("Invalid data for OpenRGB device {}", index))?);
+        }
+
+        Ok(OpenRgbClient { stream: Mutex::new(stream), controllers, buffers: Mutex::new(BTreeMap::new()) })
+    }
+
+    pub fn controllers(&self) -> &[Controller] {
+        &self.controllers
+    }
+
+    /// switches the devices used by OpenRGB lights to direct control. errors if a device isn't on the server
+    pub fn take_control(&self, lights: &[LightConfig]) -> Result<()> {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for light in lights.iter().filter(|light| matches!(light.service, LightService::OpenRGB)) {
+            let index = self.find(light)?;
+            if buffers.contains_key(&index) {
+                continue;
+            }
+            let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            write_packet(&mut stream, index as u32, SET_CUSTOM_MODE, &[])?;
+            buffers.insert(index, DeviceBuffer { colors: vec![[0; 3]; self.controllers[index].leds], changed: true });
+        }
+        Ok(())
+    }
+
+    /// index of a light's device
+    fn find(&self, light: &LightConfig) -> Result<usize> {
+        let settings = light.openrgb.clone().unwrap_or_default();
+        let index = match settings.device {
+            Some(index) => Some(index).filter(|index| self.controllers.get(*index).is_some_and(|controller| controller.name == light.light_name)),
+            None => self.controllers.iter().position(|controller| controller.name == light.light_name),
+        };
+        index.with_context(|| format!("OpenRGB has no device named {}. List devices with `zync lights openrgb`", light.light_name))
+    }
+
+    /// device index and LED ranges for each of a light's segments
+    pub fn segment_ranges(&self, light: &LightConfig, segments: usize) -> Result<(usize, Vec<Range<usize>>)> {
+        let settings = light.openrgb.clone().unwrap_or_default();
+        let index = self.find(light)?;
+        let controller = &self.controllers[index];
+
+        let leds = match &settings.zone {
+            Some(name) => controller.zones.iter()
+                .find(|zone| &zone.name == name)
+                .map(|zone| zone.leds.clone())
+                .with_context(|| format!("OpenRGB device {} has no zone named {}", controller.name, name))?,
+            None => 0..controller.leds,
+        };
+        let ranges = split_leds(leds.len(), segments, &settings.segment_leds)
+            .with_context(|| format!("OpenRGB light {}", light.light_name))?
+            .into_iter()
+            .map(|range| range.start + leds.start..range.end + leds.start)
+            .collect();
+        Ok((index, ranges))
+    }
+
+    /// writes one color per segment into the device's buffer
+    pub fn set(&self, light: &LightConfig, colors: &[[u8; 3]]) -> Result<()> {
+        let (index, ranges) = self.segment_ranges(light, colors.len())?;
+        let mut buffers = self.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let buffer = buffers.get_mut(&index)
+            .with_context(|| format!("OpenRGB device {} was not set up at startup", light.light_name))?;
+
+        for (range, color) in ranges.into_iter().zip(colors) {
+            buffer.colors[range].fill(*color);
+        }
+        buffer.changed = true;
+        Ok(())
+    }
+
+    /// sends every device whose LEDs changed this frame
+    pub fn flush(&self) -> Result<()> {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        discard_pending(&mut stream)?;
+        for (index, buffer) in buffers.iter_mut().filter(|(_, buffer)| buffer.changed) {
+            write_packet(&mut stream, *index as u32, UPDATE_LEDS, &update_leds(&buffer.colors))
+                .context("Failed to send to OpenRGB")?;
+            buffer.changed = false;
+        }
+        Ok(())
+    }
+}
+
+/// A device or one of its zones on the OpenRGB server
+pub struct OpenRgbSink {
+    config: LightConfig,
+    client: Arc<OpenRgbClient>,
+}
+
+impl OpenRgbSink {
+    pub fn new(config: LightConfig, client: Arc<OpenRgbClient>) -> Self {
+        OpenRgbSink { config, client }
+    }
+}
+
+impl LightSink for OpenRgbSink {
+    /// one color per segment, spread over the segment's LEDs. the device is sent when the frame is flushed
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let colors: Vec<[u8; 3]> = updates.iter()
+            .map(|update| self.config.dimmed(&update.color).map(|value| (value * 255.0).round() as u8))
+            .collect();
+        self.client.set(&self.config, &colors)
+    }
+
+    /// every OpenRGB light flushes, but each changed device is only sent once per frame
+    fn flush(&mut self) -> Result<()> {
+        self.client.flush()
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: true, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+}
+
+/// UpdateLEDs data: its own size, the color count, then each color as r, g, b, and a padding byte
+fn update_leds(colors: &[[u8; 3]]) -> Vec<u8> {
+    let size = 4 + 2 + colors.len() * 4;
+    let mut data = Vec::with_capacity(size);
+    data.extend_from_slice(&(size as u32).to_le_bytes());
+    data.extend_from_slice(&(colors.len() as u16).to_le_bytes());
+    for [r, g, b] in colors {
+        data.extend_from_slice(&[*r, *g, *b, 0]);
+    }
+    data
+}
+
+/// header is the magic, device index, packet id, and data size, little endian
+fn write_packet(stream: &mut TcpStream, device: u32, packet_id: u32, data: &[u8]) -> Result<()> {
+    let mut packet = Vec::with_capacity(16 + data.len());
+    packet.extend_from_slice(MAGIC);
+    packet.extend_from_slice(&device.to_le_bytes());
+    packet.extend_from_slice(&packet_id.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+    stream.write_all(&packet).context("Failed to send to OpenRGB")
+}
+
+/// data of the next packet with this id. the server also sends notifications like device list updates, which are skipped
+fn read_response(stream: &mut TcpStream, packet_id: u32) -> Result<Vec<u8>> {
+    loop {
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header).context("Failed to read from OpenRGB")?;
+        if &header[..4] != MAGIC {
+            bail!("Invalid packet from OpenRGB");
+        }
+        let mut reader = Reader::new(&header[4..]);
+        let (_device, id, size) = (reader.u32()?, reader.u32()?, reader.u32()?);
+
+        let mut data = vec![0u8; size as usize];
+        stream.read_exact(&mut data).context("Failed to read from OpenRGB")?;
+        if id == packet_id {
+            return Ok(data);
+        }
+    }
+}
+
+/// Throws away whatever the server sent since the last frame. Nothing is read after connecting, so notifications
+/// would otherwise pile up until the server blocks on a full socket.
+fn discard_pending(stream: &mut TcpStream) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut buffer = [0u8; 4096];
+    let result = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break Err(anyhow::anyhow!("OpenRGB closed the connection")),
+            Ok(_) => continue,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => break Ok(()),
+            Err(error) => break Err(error).context("Failed to read from OpenRGB"),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>()
+        .is_some_and(|error| matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// device name and zones from controller data. modes and LED names are skipped
+fn parse_controller(data: &[u8], version: u32) -> Result<Controller> {
+    let mut reader = Reader::new(data);
+    reader.u32()?;                                  // data size
+    reader.u32()?;                                  // device type
+    let name = reader.string()?;
+    if version >= 1 {
+        reader.string()?;                           // vendor
+    }
+    for _ in 0..4 {
+        reader.string()?;                           // description, version, serial, location
+    }
+
+    let modes = reader.u16()?;
+    reader.u32()?;                                  // active mode
+    for _ in 0..modes {
+        reader.string()?;
+        reader.skip(9 * 4)?;                        // value, flags, speed and color limits, speed, direction, color mode
+        let colors = reader.u16()?;
+        reader.skip(colors as usize * 4)?;
+    }
+
+    let zone_count = reader.u16()?;
+    let mut zones = Vec::new();
+    let mut start = 0;
+    for _ in 0..zone_count {
+        let zone_name = reader.string()?;
+        reader.skip(3 * 4)?;                        // type, min and max LEDs
+        let leds = reader.u32()? as usize;
+        let matrix = reader.u16()?;
+        reader.skip(matrix as usize)?;
+        zones.push(Zone { name: zone_name, leds: start..start + leds });
+        start += leds;
+    }
+
+    let leds = reader.u16()? as usize;
+    Ok(Controller { name, zones, leds })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            bail!("Packet ended early");
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Result<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// length prefixed and null terminated
+    fn string(&mut self) -> Result<String> {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        Ok(String::from_utf8_lossy(bytes.strip_suffix(&[0]).unwrap_or(bytes)).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    const DEVICE_LIST_UPDATED: u32 = 100;
+
+    fn string(data: &mut Vec<u8>, value: &str) {
+        data.extend_from_slice(&(value.len() as u16 + 1).to_le_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+
+    /// protocol 1 controller data with one mode and a 6 LED and a 2 LED zone
+    fn controller_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&5u32.to_le_bytes());            // device type
+        for value in ["Test Keyboard", "Vendor", "Description", "1.0", "serial", "HID: /dev/hidraw0"] {
+            string(&mut data, value);
+        }
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        string(&mut data, "Direct");
+        data.extend_from_slice(&[0; 36]);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        for (name, leds, matrix) in [("Keyboard", 6u32, 0u16), ("Logo", 2, 12)] {
+            string(&mut data, name);
+            data.extend_from_slice(&[0; 12]);
+            data.extend_from_slice(&leds.to_le_bytes());
+            data.extend_from_slice(&matrix.to_le_bytes());
+            data.extend(std::iter::repeat_n(0, matrix as usize));
+        }
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);                       // LEDs and colors, not parsed
+        let size = data.len() as u32 + 4;
+        [size.to_le_bytes().to_vec(), data].concat()
+    }
+
+    /// raw header and data of the next packet
+    fn read_raw(stream: &mut TcpStream) -> ([u8; 16], Vec<u8>) {
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header).unwrap();
+        let mut data = vec![0u8; u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize];
+        stream.read_exact(&mut data).unwrap();
+        (header, data)
+    }
+
+    fn packet_id(header: &[u8; 16]) -> u32 {
+        u32::from_le_bytes(header[8..12].try_into().unwrap())
+    }
+
+    /// answers the connect handshake, then forwards every packet the client sends. each packet is followed by a
+    /// device list notification the client never reads
+    fn serve(listener: TcpListener, packets: mpsc::Sender<([u8; 16], Vec<u8>)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let (header, data) = read_raw(&mut stream);
+        assert_eq!(packet_id(&header), SET_CLIENT_NAME);
+        assert_eq!(data, b"zync test\0");
+
+        let (header, data) = read_raw(&mut stream);
+        assert_eq!(packet_id(&header), REQUEST_PROTOCOL_VERSION);
+        assert_eq!(data, PROTOCOL_VERSION.to_le_bytes());
+        write_packet(&mut stream, 0, REQUEST_PROTOCOL_VERSION, &4u32.to_le_bytes()).unwrap();
+
+        let (header, _) = read_raw(&mut stream);
+        assert_eq!(packet_id(&header), REQUEST_CONTROLLER_COUNT);
+        write_packet(&mut stream, 0, DEVICE_LIST_UPDATED, &[]).unwrap();
+        write_packet(&mut stream, 0, REQUEST_CONTROLLER_COUNT, &1u32.to_le_bytes()).unwrap();
+
+        let (header, data) = read_raw(&mut stream);
+        assert_eq!(packet_id(&header), REQUEST_CONTROLLER_DATA);
+        assert_eq!(data, 1u32.to_le_bytes());
+        write_packet(&mut stream, 0, REQUEST_CONTROLLER_DATA, &controller_data()).unwrap();
+
+        let mut header = [0u8; 16];
+        while stream.read_exact(&mut header).is_ok() {
+            let mut data = vec![0u8; u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize];
+            stream.read_exact(&mut data).unwrap();
+            write_packet(&mut stream, 0, DEVICE_LIST_UPDATED, &[0; 64]).unwrap();
+            packets.send((header, data)).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_controller_data() {
+        let controller = parse_controller(&controller_data(), 1).unwrap();
+        assert_eq!(controller.name, "Test Keyboard");
+        assert_eq!(controller.leds, 8);
+        let zones: Vec<(&str, Range<usize>)> = controller.zones.iter().map(|zone| (zone.name.as_str(), zone.leds.clone())).collect();
+        assert_eq!(zones, [("Keyboard", 0..6), ("Logo", 6..8)]);
+
+        assert!(parse_controller(&controller_data()[..40], 1).is_err());
+    }
+
+    #[test]
+    fn talks_to_an_sdk_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, packets) = mpsc::channel();
+        let server = thread::spawn(move || serve(listener, sender));
+
+        let config = OpenRgbConfig { host: "127.0.0.1".to_string(), port, client_name: "zync test".to_string() };
+        let client = OpenRgbClient::connect(&config).unwrap();
+        assert_eq!(client.controllers().len(), 1);
+
+        let light: LightConfig = serde_yaml::from_str(
+            "{ service: OpenRGB, light_name: Test Keyboard, brightness: 1.0, openrgb: { zone: Keyboard } }").unwrap();
+        client.take_control(std::slice::from_ref(&light)).unwrap();
+        let (header, data) = packets.recv().unwrap();
+        assert_eq!(&header[..4], MAGIC);
+        assert_eq!(header[4..8], 0u32.to_le_bytes());
+        assert_eq!(packet_id(&header), SET_CUSTOM_MODE);
+        assert_eq!(header[12..16], 0u32.to_le_bytes());
+        assert!(data.is_empty());
+
+        client.set(&light, &[[255, 0, 0], [0, 0, 255]]).unwrap();
+        client.flush().unwrap();
+        let (header, data) = packets.recv().unwrap();
+        assert_eq!(&header[..4], MAGIC);
+        assert_eq!(packet_id(&header), UPDATE_LEDS);
+        assert_eq!(header[12..16], 38u32.to_le_bytes());
+        assert_eq!(data[..4], 38u32.to_le_bytes());
+        assert_eq!(data[4..6], 8u16.to_le_bytes());
+        let leds: Vec<&[u8]> = data[6..].chunks(4).collect();
+        assert_eq!(leds[..3], [[255, 0, 0, 0]; 3]);
+        assert_eq!(leds[3..6], [[0, 0, 255, 0]; 3]);
+        assert_eq!(leds[6..], [[0; 4]; 2]);
+
+        // notifications piling up between frames don't stall flushing, and are read off without blocking
+        client.set(&light, &[[0, 255, 0], [0, 255, 0]]).unwrap();
+        client.flush().unwrap();
+        assert_eq!(packet_id(&packets.recv().unwrap().0), UPDATE_LEDS);
+        let mut stream = client.stream.lock().unwrap();
+        discard_pending(&mut stream).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        assert_eq!(stream.read(&mut [0u8; 16]).unwrap_err().kind(), ErrorKind::WouldBlock);
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        drop(stream);
+        server.join().unwrap();
+    }
+}
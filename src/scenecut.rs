@@ -0,0 +1,111 @@
+use image::RgbaImage;
+use serde::Deserialize;
+
+const BINS_PER_CHANNEL: usize = 4;      // 4x4x4 color histogram. coarse enough to ignore motion, fine enough to see a new scene
+
+/// Settings for detecting hard cuts, where every light should snap to the new scene together
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SceneCutConfig {
+    pub enabled: bool,
+    pub threshold: f32,         // fraction of the frame's color histogram that has to change (0-1) to count as a cut
+}
+
+impl Default for SceneCutConfig {
+    fn default() -> Self {
+        SceneCutConfig {
+            enabled: false,
+            threshold: 0.5,
+        }
+    }
+}
+
+/// Compares whole frame color histograms between frames. A cut is when the histograms overlap by less than
+/// 1 - threshold, which catches scene changes without reacting to motion within a scene.
+pub struct SceneCutDetector {
+    config: SceneCutConfig,
+    previous: Option<Vec<f32>>,
+}
+
+impl SceneCutDetector {
+    pub fn new(config: SceneCutConfig) -> Self {
+        SceneCutDetector { config, previous: None }
+    }
+
+    /// returns true if this frame is a hard cut from the previous one
+    pub fn update(&mut self, frame: &RgbaImage, downsample: u8) -> bool {
+        let histogram = histogram(frame, downsample.max(1) as usize);
+
+        let cut = match &self.previous {
+            None => false,
+            Some(previous) => {
+                let overlap: f32 = previous.iter().zip(&histogram).map(|(a, b)| a.min(*b)).sum();
+                1.0 - overlap > self.config.threshold
+            }
+        };
+
+        self.previous = Some(histogram);
+        cut
+    }
+}
+
+/// normalized color histogram of every downsample-th pixel in both directions
+fn histogram(frame: &RgbaImage, step: usize) -> Vec<f32> {
+    let shift = 8 - BINS_PER_CHANNEL.trailing_zeros();
+    let mut counts = vec![0u32; BINS_PER_CHANNEL.pow(3)];
+    let mut total = 0u32;
+
+    let row_bytes = frame.width() as usize * 4;
+    for row in frame.as_raw().chunks_exact(row_bytes).step_by(step) {
+        for pixel in row.chunks_exact(4).step_by(step) {
+            let r = (pixel[0] >> shift) as usize;
+            let g = (pixel[1] >> shift) as usize;
+            let b = (pixel[2] >> shift) as usize;
+            counts[(r * BINS_PER_CHANNEL + g) * BINS_PER_CHANNEL + b] += 1;
+            total += 1;
+        }
+    }
+
+    let total = total.max(1) as f32;
+    counts.into_iter().map(|count| count as f32 / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn detector() -> SceneCutDetector {
+        SceneCutDetector::new(SceneCutConfig { enabled: true, ..SceneCutConfig::default() })
+    }
+
+    /// a dark blue background with a bright square at x
+    fn scene(x: u32) -> RgbaImage {
+        RgbaImage::from_fn(320, 180, |px, py| {
+            if (x..x + 40).contains(&px) && (70..110).contains(&py) { Rgba([240, 200, 40, 255]) } else { Rgba([20, 30, 90, 255]) }
+        })
+    }
+
+    #[test]
+    fn first_frame_is_never_a_cut() {
+        let mut detector = detector();
+        assert!(!detector.update(&RgbaImage::from_pixel(320, 180, Rgba([255, 0, 0, 255])), 1));
+    }
+
+    #[test]
+    fn full_frame_color_change_is_a_cut() {
+        let mut detector = detector();
+        detector.update(&scene(20), 1);
+        assert!(detector.update(&RgbaImage::from_pixel(320, 180, Rgba([200, 60, 20, 255])), 1));
+        // staying on the new scene isn't another cut
+        assert!(!detector.update(&RgbaImage::from_pixel(320, 180, Rgba([200, 60, 20, 255])), 1));
+    }
+
+    #[test]
+    fn moving_object_is_not_a_cut() {
+        let mut detector = detector();
+        for x in (0..280).step_by(20) {
+            assert!(!detector.update(&scene(x), 2), "object at {}", x);
+        }
+    }
+}
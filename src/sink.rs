@@ -0,0 +1,49 @@
+use anyhow::Result;
+
+use crate::calibration::Calibration;
+use crate::lights::{LightConfig, MessageColor};
+
+/// New color for one segment of a light. Regular lights get a single update, segmented lights one per segment in order.
+pub struct ZoneUpdate {
+    pub color: MessageColor,
+    pub transition: f32,            // seconds. outputs without transitions ignore it
+}
+
+/// What a light output can do, so the engine knows how to drive it
+#[derive(Debug, Clone, Copy)]
+pub struct SinkCapabilities {
+    pub segments: bool,             // takes one color per segment. others only use the first update
+    pub every_frame: bool,          // streaming outputs need every frame, not just frames where the color changed
+}
+
+/// A light output. Each light gets its own sink, which holds shared clients for its backend.
+/// The engine sends each light's updates during the frame and flushes every sink once the frame is done,
+/// so outputs that share a packet or connection can batch them.
+pub trait LightSink: Send {
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()>;
+
+    /// sends anything buffered during the frame
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> SinkCapabilities;
+
+    /// the light's settings, which the sink formats its updates with
+    fn config_mut(&mut self) -> &mut LightConfig;
+
+    /// replaces the light's calibration, e.g. while `zync calibrate` adjusts it
+    fn set_calibration(&mut self, calibration: Calibration) {
+        self.config_mut().calibration = calibration;
+    }
+
+    /// saves the light's current state before syncing starts. outputs that can't read their state keep nothing
+    fn snapshot(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// puts back the state saved by snapshot when syncing stops
+    fn restore(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
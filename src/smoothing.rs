@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use serde::{Deserialize, Serialize};
+
+use crate::capture::ZoneColor;
+
+/// Temporal filter applied to a zone's samples before they reach the lights. Smooths out flicker from
+/// muzzle flashes, strobing UIs, and similar content that would otherwise spam light changes.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "filter", rename_all = "snake_case")]
+pub enum SmoothingConfig {
+    /// exponential moving average. lower alpha is smoother but slower to follow changes
+    Ema {
+        #[serde(default = "default_alpha")]
+        alpha: f32,
+    },
+    /// one euro filter: smooth while colors are steady, responsive when they move quickly.
+    /// (Casiez, Roussel, Vogel. "1€ Filter", CHI 2012)
+    OneEuro {
+        #[serde(default = "default_min_cutoff")]
+        min_cutoff: f32,        // cutoff frequency in Hz when colors are steady. lower is smoother
+        #[serde(default = "default_beta")]
+        beta: f32,              // how quickly the cutoff rises with speed of change. higher follows fast changes better
+        #[serde(default = "default_d_cutoff")]
+        d_cutoff: f32,          // cutoff frequency in Hz for the speed estimate
+    },
+    /// per channel median of the last N frames. removes single frame flashes entirely
+    Median {
+        #[serde(default = "default_frames")]
+        frames: usize,
+    },
+}
+
+fn default_alpha() -> f32 { 0.3 }
+fn default_min_cutoff() -> f32 { 1.0 }
+fn default_beta() -> f32 { 0.005 }
+fn default_d_cutoff() -> f32 { 1.0 }
+fn default_frames() -> usize { 5 }
+
+/// Filter state for one zone. Channels are kept as floats between frames so slow filters don't get stuck on rounding.
+pub struct Smoother {
+    config: SmoothingConfig,
+    value: Option<[f32; 3]>,
+    speed: [f32; 3],
+    history: VecDeque<ZoneColor>,
+}
+
+impl Smoother {
+    pub fn new(config: SmoothingConfig) -> Self {
+        Smoother {
+            config,
+            value: None,
+            speed: [0.0; 3],
+            history: VecDeque::new(),
+        }
+    }
+
+    /// filters a new sample. dt is the time since the previous sample in seconds.
+    pub fn filter(&mut self, sample: ZoneColor, dt: f32) -> ZoneColor {
+        let input = [sample.r as f32, sample.g as f32, sample.b as f32];
+
+        match self.config {
+            SmoothingConfig::Ema { alpha } => {
+                let alpha = alpha.clamp(0.0, 1.0);
+                let value = match self.value {
+                    None => input,
+                    Some(value) => [0, 1, 2].map(|c| value[c] + alpha * (input[c] - value[c])),
+                };
+                self.value = Some(value);
+                to_color(value)
+            }
+            SmoothingConfig::OneEuro { min_cutoff, beta, d_cutoff } => {
+                let dt = dt.max(0.001);
+                let value = match self.value {
+                    None => input,
+                    Some(value) => {
+                        let mut filtered = value;
+                        for c in 0..3 {
+                            let speed = (input[c] - value[c]) / dt;
+                            self.speed[c] += smoothing_factor(d_cutoff, dt) * (speed - self.speed[c]);
+                            let cutoff = min_cutoff + beta * self.speed[c].abs();
+                            filtered[c] = value[c] + smoothing_factor(cutoff, dt) * (input[c] - value[c]);
+                        }
+                        filtered
+                    }
+                };
+                self.value = Some(value);
+                to_color(value)
+            }
+            SmoothingConfig::Median { frames } => {
+                if self.history.len() >= frames.max(1) {
+                    self.history.pop_front();
+                }
+                self.history.push_back(sample);
+
+                let median = |channel: fn(&ZoneColor) -> u8| {
+                    let mut values: Vec<u8> = self.history.iter().map(channel).collect();
+                    values.sort_unstable();
+                    values[values.len() / 2]
+                };
+                ZoneColor::new(median(|color| color.r), median(|color| color.g), median(|color| color.b))
+            }
+        }
+    }
+
+    /// drops filter state so the next sample passes straight through
+    pub fn reset(&mut self) {
+        self.value = None;
+        self.speed = [0.0; 3];
+        self.history.clear();
+    }
+}
+
+/// exponential smoothing factor for a low pass filter with the given cutoff frequency
+fn smoothing_factor(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * PI * cutoff.max(0.001));
+    1.0 / (1.0 + tau / dt)
+}
+
+fn to_color(value: [f32; 3]) -> ZoneColor {
+    let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    ZoneColor::new(channel(value[0]), channel(value[1]), channel(value[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 30.0;
+
+    fn grey(value: u8) -> ZoneColor {
+        ZoneColor::new(value, value, value)
+    }
+
+    fn one_euro() -> Smoother {
+        Smoother::new(SmoothingConfig::OneEuro { min_cutoff: default_min_cutoff(), beta: default_beta(), d_cutoff: default_d_cutoff() })
+    }
+
+    #[test]
+    fn first_sample_passes_through() {
+        for config in [SmoothingConfig::Ema { alpha: 0.3 }, one_euro().config, SmoothingConfig::Median { frames: 5 }] {
+            assert_eq!(Smoother::new(config).filter(ZoneColor::new(10, 120, 240), DT), ZoneColor::new(10, 120, 240));
+        }
+    }
+
+    #[test]
+    fn ema_converges_to_a_steady_input() {
+        let mut smoother = Smoother::new(SmoothingConfig::Ema { alpha: 0.3 });
+        smoother.filter(grey(0), DT);
+
+        let outputs: Vec<u8> = (0..30).map(|_| smoother.filter(grey(200), DT).r).collect();
+        assert_eq!(outputs[0], 60);
+        assert!(outputs.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(outputs[29], 200);
+    }
+
+    #[test]
+    fn one_euro_follows_fast_steps() {
+        let mut smoother = one_euro();
+        smoother.filter(grey(0), DT);
+
+        let outputs: Vec<u8> = (0..3).map(|_| smoother.filter(grey(255), DT).r).collect();
+        assert!(outputs[0] > 128, "first frame after the step: {}", outputs[0]);
+        assert!(outputs[2] > 230, "third frame after the step: {}", outputs[2]);
+    }
+
+    #[test]
+    fn one_euro_damps_jitter() {
+        let mut smoother = one_euro();
+        let outputs: Vec<u8> = (0..60)
+            .map(|frame| smoother.filter(grey(if frame % 2 == 0 { 124 } else { 132 }), DT).r)
+            .collect();
+
+        // input swings by 8, the output settles within a couple of steps of the middle
+        for output in &outputs[10..] {
+            assert!((126..=130).contains(output), "output {}", output);
+        }
+    }
+
+    #[test]
+    fn median_rejects_single_frame_spikes() {
+        let mut smoother = Smoother::new(SmoothingConfig::Median { frames: 5 });
+        for _ in 0..5 {
+            smoother.filter(grey(50), DT);
+        }
+        assert_eq!(smoother.filter(grey(255), DT), grey(50));
+        for _ in 0..5 {
+            assert_eq!(smoother.filter(grey(50), DT), grey(50));
+        }
+
+        // a change that lasts passes once it fills half the window
+        let outputs: Vec<u8> = (0..3).map(|_| smoother.filter(grey(200), DT).r).collect();
+        assert_eq!(outputs, [50, 50, 200]);
+    }
+
+    #[test]
+    fn reset_passes_the_next_sample_through() {
+        let mut smoother = Smoother::new(SmoothingConfig::Ema { alpha: 0.1 });
+        smoother.filter(grey(0), DT);
+        smoother.reset();
+        assert_eq!(smoother.filter(grey(200), DT), grey(200));
+    }
+}
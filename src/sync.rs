@@ -1,38 +1,77 @@
 use std::time::{Duration, Instant};
 use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde::Deserialize;
 use anyhow::{Result};
 use chrono::Local;
 
-use crate::capture::{ScreenCapture, ZoneColor, ZoneSampler};
-use crate::lights::{MessageColor, LightController};
+use crate::aesthetics::Aesthetics;
+use crate::capture::{ScreenArea, ScreenCapture, ZoneColor, ZoneSampler, sample_zones};
+use crate::color::ColorMetric;
+use crate::letterbox::LetterboxDetector;
+use crate::scenecut::SceneCutDetector;
+use crate::smoothing::Smoother;
+use crate::lights::MessageColor;
+use crate::sink::{LightSink, ZoneUpdate};
 
 const FRAME_RECOVERY_RATE: f32 = 0.2;
 const FRAME_RECOVERY_BUFFER: u16 = 5;
 const FRAME_THROTTLE_RATE: u64 = 10;
-const TRANSITION_SOFTNESS: f32 = 0.4;
-const TRANSITION_MIN: f32 = 0.02;
-const TRANSITION_MAX: f32 = 1.0;
 
 #[derive(Deserialize)]
 pub struct PerformanceConfig {
     pub max_fps: u64,
     pub max_delay: u64,
-    pub refresh_threshold: u8,
+    pub refresh_threshold: f32,
     pub percent_thread_work: f32,
     pub fps_reporting: u64,
+    #[serde(default)]
+    pub color_metric: ColorMetric,
+    #[serde(default)]
+    pub parallel_sampling: bool,
 }
 
-/// This is handles a zone and its cooresponding lights. Defined here to maintain independence between light and capture modules.
-pub struct ZonePair<'a>{
-    zone: ZoneSampler,
-    zone_light: LightController<'a>,
-    previous_sample: Option<ZoneColor>,
+/// A zone's contribution to a light. zone is the index of the ZoneSampler in the engine's zone list.
+#[derive(Debug)]
+pub struct ZoneWeight {
+    pub zone: usize,
+    pub weight: f32,
 }
 
-impl<'a> ZonePair<'a> {
-    pub fn new (zone: ZoneSampler, zone_light: LightController<'a>, previous_sample: Option<ZoneColor>) -> Self {
-        ZonePair {zone, zone_light, previous_sample}
+/// This is handles a light and the zones that drive it. Defined here to maintain independence between light and capture modules.
+/// Zones can drive several lights, so samplers live in SyncEngine and pairs reference them by index.
+/// Regular lights have a single segment; segmented lights (gradient strips) have one per segment in order.
+pub struct ZonePair {
+    segments: Vec<Vec<ZoneWeight>>,
+    zone_light: Box<dyn LightSink>,
+    previous_sample: Option<Vec<ZoneColor>>,
+    failing: bool,                  // the last update failed. further errors aren't logged until it works again
+}
+
+impl ZonePair {
+    pub fn new (segments: Vec<Vec<ZoneWeight>>, zone_light: Box<dyn LightSink>, previous_sample: Option<Vec<ZoneColor>>) -> Self {
+        ZonePair {segments, zone_light, previous_sample, failing: false}
+    }
+
+    /// logs a failed update unless the light was already failing, so an unreachable light doesn't flood the output
+    fn report(&mut self, error: &anyhow::Error) {
+        if !self.failing {
+            println!("Failed to update light, retrying every frame: {:#}", error);
+        }
+        self.failing = true;
+    }
+
+    /// blends this light's zones from the samples taken this frame, one color per segment
+    fn blend(&self, samples: &[ZoneColor]) -> Vec<ZoneColor> {
+        self.segments.iter()
+            .map(|zones| {
+                let weighted: Vec<(ZoneColor, f32)> = zones.iter()
+                    .map(|zone| (samples[zone.zone], zone.weight))
+                    .collect();
+                ZoneColor::blend(&weighted)
+            })
+            .collect()
     }
 }
 
@@ -144,34 +183,69 @@ impl AdaptiveRate {
 
 }
 
-pub struct SyncEngine<'a> {
+pub struct SyncEngine {
     screen: Box<dyn ScreenCapture>,
-    zones: Vec<ZonePair<'a>>,
+    samplers: Vec<ZoneSampler>,
+    zones: Vec<ZonePair>,
     rate: AdaptiveRate,
     config: PerformanceConfig,
     downsample: u8,
+    aesthetics: Aesthetics,
     interval_samples: Vec<u64>,
     last_report_time: Instant,
+    letterbox: Option<LetterboxDetector>,
+    scene_cut: Option<SceneCutDetector>,
+    frame_size: (u32, u32),
+    smoothers: Vec<Option<Smoother>>,
+    last_frame_time: Option<Instant>,
+    stop: Arc<AtomicBool>,
 }
 
-impl<'a> SyncEngine<'a> {
-    pub fn new(screen: Box<dyn ScreenCapture>, zones: Vec<ZonePair<'a>>, rate: AdaptiveRate, config: PerformanceConfig, downsample: u8) -> Self {
+impl SyncEngine {
+    pub fn new(screen: Box<dyn ScreenCapture>, samplers: Vec<ZoneSampler>, zones: Vec<ZonePair>, rate: AdaptiveRate, config: PerformanceConfig, downsample: u8, aesthetics: Aesthetics) -> Self {
+        let smoothers = samplers.iter().map(|zone| zone.smoother()).collect();
+
         SyncEngine {
             screen,
+            samplers,
             zones,
             rate,
             config,
             downsample,
+            aesthetics,
             interval_samples: Vec::new(),
             last_report_time: Instant::now(), //defining on creation as default value. updates when Run() starts.
+            letterbox: None,
+            scene_cut: None,
+            frame_size: (0, 0),
+            smoothers,
+            last_frame_time: None,
+            stop: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn calculate_transition(sample: &ZoneColor, previous: &ZoneColor) -> f32 {
-        let distance = sample.compare_sample(previous);
-        let norm_distance = (distance / 441.0).min(1.0);
+    /// moves zones into the active picture when black bars are detected
+    pub fn with_letterbox(mut self, letterbox: Option<LetterboxDetector>) -> Self {
+        self.letterbox = letterbox;
+        self
+    }
+
+    /// snaps all lights to the new scene on hard cuts
+    pub fn with_scene_cut(mut self, scene_cut: Option<SceneCutDetector>) -> Self {
+        self.scene_cut = scene_cut;
+        self
+    }
+
+    /// set it to stop syncing after the current frame, e.g. from a Ctrl+C handler
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    pub fn calculate_transition(sample: &ZoneColor, previous: &ZoneColor, metric: ColorMetric, aesthetics: &Aesthetics) -> f32 {
+        let distance = sample.compare_sample(previous, metric);
+        let norm_distance = (distance / metric.max_distance()).min(1.0);
 
-        TRANSITION_MAX - norm_distance.powf(TRANSITION_SOFTNESS) * (TRANSITION_MAX - TRANSITION_MIN)
+        aesthetics.transition_max - norm_distance.powf(aesthetics.transition_softness) * (aesthetics.transition_max - aesthetics.transition_min)
 
     }
 
@@ -189,44 +263,273 @@ impl<'a> SyncEngine<'a> {
         }
     }
 
+    /// saves every light's state, syncs until stopped, then puts the saved states back
     pub fn run(&mut self) -> Result<()>{
+        for area in &mut self.zones {
+            if let Err(error) = area.zone_light.snapshot() {
+                println!("Failed to save light state: {:#}", error);
+            }
+        }
+
+        let result = self.sync();
+
+        for area in &mut self.zones {
+            if let Err(error) = area.zone_light.restore() {
+                println!("Failed to restore light state: {:#}", error);
+            }
+        }
+        result
+    }
+
+    fn sync(&mut self) -> Result<()> {
         self.last_report_time = Instant::now();
 
-        loop {
+        while !self.stop.load(Ordering::Relaxed) {
             let now = Instant::now();
             let frame = self.screen.capture_frame()?;
 
-            for area in &mut self.zones {
+            // check zones against the frame on the first frame and whenever the resolution or captured window changes
+            if frame.dimensions() != self.frame_size {
+                if self.frame_size != (0, 0) {
+                    println!("Frame size changed to {}x{}", frame.width(), frame.height());
+                }
+                for zone in &mut self.samplers {
+                    zone.resolve(frame.width(), frame.height())?;
+                }
+                self.frame_size = frame.dimensions();
+            }
 
-                // grab screen
-                let sample = area.zone.sample(&frame, self.downsample)?;
+            // move zones into the picture if black bars are detected
+            let area = match &mut self.letterbox {
+                Some(detector) => detector.update(&frame, self.downsample),
+                None => ScreenArea::full(&frame),
+            };
+
+            // hard cuts snap every light to the new scene at once, skipping smoothing and the refresh threshold
+            let cut = match &mut self.scene_cut {
+                Some(detector) => detector.update(&frame, self.downsample),
+                None => false,
+            };
+            if cut {
+                self.smoothers.iter_mut().flatten().for_each(Smoother::reset);
+            }
+
+            // sample every zone once, then blend samples for each light
+            let mut samples = sample_zones(&self.samplers, &frame, self.downsample, &area, self.config.parallel_sampling)?;
+
+            // temporal smoothing per zone, before samples are blended and compared against what the lights show
+            let dt = self.last_frame_time.map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+            self.last_frame_time = Some(now);
+            for (sample, smoother) in samples.iter_mut().zip(&mut self.smoothers) {
+                if let Some(smoother) = smoother {
+                    *sample = smoother.filter(*sample, dt);
+                }
+            }
 
-                //check if we have a don't previous sample or if its meaningfully different to determine if we update the lights
+            // a light that fails is retried on later frames, so one unreachable light doesn't stop the others
+            let mut failed = vec![false; self.zones.len()];
+            for (area, failed) in self.zones.iter_mut().zip(&mut failed) {
+
+                let sample = area.blend(&samples);
+
+                //check if we have a don't previous sample or if any segment is meaningfully different to determine if we update the lights
                 let update = match &area.previous_sample {
+                                _ if cut => true,
+                                _ if area.zone_light.capabilities().every_frame => true,
                                 None => true,
-                                Some(prev) => sample.differs_from(prev, self.config.refresh_threshold),
+                                Some(prev) => sample.iter().zip(prev)
+                                    .any(|(segment, prev)| segment.differs_from(prev, self.config.refresh_threshold, self.config.color_metric)),
                             };
 
                 if !update {
                     continue;
                 }
 
-                // send light command and handle rate adaption
+                // send light command and handle rate adaption. segmented lights share one transition, so use the fastest
                 let transition = match &area.previous_sample {
-                    Some(prev) => SyncEngine::calculate_transition(&sample, prev),
-                    None => TRANSITION_MAX,
+                    _ if cut => self.aesthetics.transition_min,
+                    Some(prev) => sample.iter().zip(prev)
+                        .map(|(segment, prev)| SyncEngine::calculate_transition(segment, prev, self.config.color_metric, &self.aesthetics))
+                        .fold(self.aesthetics.transition_max, f32::min),
+                    None => self.aesthetics.transition_max,
                 };
 
-                let color = MessageColor::from(sample);
+                let updates: Vec<ZoneUpdate> = sample.iter()
+                    .map(|segment| ZoneUpdate { color: MessageColor::from_sample(*segment, &self.aesthetics), transition })
+                    .collect();
 
-                area.zone_light.set_light(color, Some(transition))?;
+                if let Err(error) = area.zone_light.send(&updates) {
+                    area.report(&error);
+                    *failed = true;
+                    continue;
+                }
                 area.previous_sample = Some(sample);
             }
 
+            // outputs that buffer during the frame (DMX universes, OpenRGB devices) send once every light is set
+            for (area, failed) in self.zones.iter_mut().zip(failed) {
+                match area.zone_light.flush() {
+                    Err(error) => area.report(&error),
+                    Ok(()) if !failed => area.failing = false,
+                    Ok(()) => {}
+                }
+            }
+
             self.send_fps_message();
 
             let elapsed_time = now.elapsed().as_millis() as u64;
             thread::sleep(Duration::from_millis(self.rate.adjust_timing(elapsed_time)));
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use anyhow::bail;
+    use image::{Rgba, RgbaImage};
+
+    use crate::lights::LightConfig;
+    use crate::sink::SinkCapabilities;
+
+    /// records the engine's calls, shared with the test through the Arc
+    #[derive(Clone)]
+    struct RecordingSink {
+        config: LightConfig,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        every_frame: bool,
+        unreachable: bool,          // every send fails
+    }
+
+    impl RecordingSink {
+        fn new() -> Self {
+            let config = serde_yaml::from_str("{ service: Zigbee2MQTT, light_name: recording, brightness: 1.0 }").unwrap();
+            RecordingSink { config, calls: Arc::default(), every_frame: false, unreachable: false }
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl LightSink for RecordingSink {
+        fn send(&mut self, _updates: &[ZoneUpdate]) -> Result<()> {
+            self.calls.lock().unwrap().push("send");
+            if self.unreachable {
+                bail!("light is unreachable");
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.calls.lock().unwrap().push("flush");
+            Ok(())
+        }
+
+        fn capabilities(&self) -> SinkCapabilities {
+            SinkCapabilities { segments: true, every_frame: self.every_frame }
+        }
+
+        fn config_mut(&mut self) -> &mut LightConfig {
+            &mut self.config
+        }
+    }
+
+    /// plays back solid color frames, then stops the engine
+    struct FakeScreen {
+        frames: Mutex<Vec<[u8; 3]>>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl ScreenCapture for FakeScreen {
+        fn new() -> Result<Box<dyn ScreenCapture>> {
+            bail!("tests build the screen with its frames");
+        }
+
+        fn capture_frame(&self) -> Result<RgbaImage> {
+            let mut frames = self.frames.lock().unwrap();
+            let [r, g, b] = frames.remove(0);
+            if frames.is_empty() {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+            Ok(RgbaImage::from_pixel(64, 36, Rgba([r, g, b, 255])))
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// syncs the frames to lights that all show the one full screen zone
+    fn run(frames: Vec<[u8; 3]>, sinks: &[RecordingSink]) -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let screen = FakeScreen { frames: Mutex::new(frames), stop: Arc::clone(&stop) };
+        let samplers = vec![ZoneSampler::new(serde_yaml::from_str("{ x: 0, y: 0, width: 64, height: 36 }").unwrap(), false).unwrap()];
+        let zones = sinks.iter()
+            .map(|sink| ZonePair::new(vec![vec![ZoneWeight { zone: 0, weight: 1.0 }]], Box::new(sink.clone()), None))
+            .collect();
+        let config: PerformanceConfig = serde_yaml::from_str(
+            "{ max_fps: 100, max_delay: 100, refresh_threshold: 0.1, percent_thread_work: 1.0, fps_reporting: 60 }").unwrap();
+
+        let mut engine = SyncEngine::new(Box::new(screen), samplers, zones, AdaptiveRate::new_from_fps(100, 100, 1.0), config, 1, Aesthetics::default());
+        engine.stop = stop;
+        engine.run()
+    }
+
+    fn pair(segments: Vec<Vec<(usize, f32)>>) -> ZonePair {
+        let segments = segments.into_iter()
+            .map(|zones| zones.into_iter().map(|(zone, weight)| ZoneWeight { zone, weight }).collect())
+            .collect();
+        ZonePair::new(segments, Box::new(RecordingSink::new()), None)
+    }
+
+    #[test]
+    fn several_zones_blend_by_weight() {
+        let samples = [ZoneColor::new(255, 0, 0), ZoneColor::new(0, 0, 255), ZoneColor::new(0, 200, 0)];
+
+        let light = pair(vec![vec![(0, 3.0), (1, 1.0)]]);
+        assert_eq!(light.blend(&samples), [ZoneColor::new(191, 0, 64)]);
+
+        let even = pair(vec![vec![(0, 1.0), (1, 1.0), (2, 2.0)]]);
+        assert_eq!(even.blend(&samples), [ZoneColor::new(64, 100, 64)]);
+    }
+
+    #[test]
+    fn one_zone_drives_several_lights() {
+        let samples = [ZoneColor::new(10, 20, 30), ZoneColor::new(200, 100, 0)];
+
+        let alone = pair(vec![vec![(1, 1.0)]]);
+        let shared = pair(vec![vec![(0, 1.0), (1, 1.0)]]);
+        let segmented = pair(vec![vec![(1, 1.0)], vec![(0, 1.0)]]);
+        assert_eq!(alone.blend(&samples), [samples[1]]);
+        assert_eq!(shared.blend(&samples), [ZoneColor::new(105, 60, 15)]);
+        assert_eq!(segmented.blend(&samples), [samples[1], samples[0]]);
+    }
+
+    #[test]
+    fn unchanged_colors_are_sent_once_and_every_frame_is_flushed() {
+        let light = RecordingSink::new();
+        run(vec![[255, 0, 0], [255, 0, 0], [255, 0, 0], [0, 0, 255]], std::slice::from_ref(&light)).unwrap();
+        assert_eq!(light.calls(), ["send", "flush", "flush", "flush", "send", "flush"]);
+    }
+
+    #[test]
+    fn streaming_lights_get_every_frame() {
+        let light = RecordingSink { every_frame: true, ..RecordingSink::new() };
+        run(vec![[255, 0, 0]; 3], std::slice::from_ref(&light)).unwrap();
+        assert_eq!(light.calls(), ["send", "flush", "send", "flush", "send", "flush"]);
+    }
+
+    #[test]
+    fn failing_light_doesnt_stop_the_others() {
+        let unreachable = RecordingSink { unreachable: true, ..RecordingSink::new() };
+        let working = RecordingSink::new();
+        run(vec![[255, 0, 0], [255, 0, 0], [0, 0, 255]], &[unreachable.clone(), working.clone()]).unwrap();
+
+        // nothing reached the failing light, so it's retried every frame
+        assert_eq!(unreachable.calls(), ["send", "flush", "send", "flush", "send", "flush"]);
+        assert_eq!(working.calls(), ["send", "flush", "flush", "send", "flush"]);
     }
 }
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use anyhow::{Context, Result, bail};
+
+use crate::dmx::DmxOutput;
+use crate::govee;
+use crate::lights::{LightConfig, LightService};
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+/// One UDP socket shared by every light that streams over UDP. Hosts are resolved once at startup
+/// so hostnames don't cost a DNS lookup every frame.
+pub struct UdpOutput {
+    socket: UdpSocket,
+    addresses: HashMap<(String, u16), SocketAddr>,
+}
+
+impl UdpOutput {
+    pub fn new<'a>(targets: impl IntoIterator<Item = (&'a str, u16)>) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open UDP socket")?;
+        socket.set_broadcast(true)?;
+
+        let mut addresses = HashMap::new();
+        for (host, port) in targets {
+            let address = (host, port).to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.find(SocketAddr::is_ipv4))
+                .with_context(|| format!("Could not resolve {}", host))?;
+            addresses.insert((host.to_string(), port), address);
+        }
+        Ok(UdpOutput { socket, addresses })
+    }
+
+    pub fn send(&self, host: &str, port: u16, packet: &[u8]) -> Result<()> {
+        let address = self.addresses.get(&(host.to_string(), port))
+            .with_context(|| format!("{}:{} was not resolved at startup", host, port))?;
+        self.socket.send_to(packet, address)
+            .with_context(|| format!("Failed to send to {}", address))?;
+        Ok(())
+    }
+}
+
+/// WLED, Govee, and DMX lights, sent over the shared UDP socket
+pub struct UdpSink {
+    config: LightConfig,
+    udp: Arc<UdpOutput>,
+    dmx: Option<Arc<DmxOutput>>,
+}
+
+impl UdpSink {
+    pub fn new(config: LightConfig, udp: Arc<UdpOutput>, dmx: Option<Arc<DmxOutput>>) -> Self {
+        UdpSink { config, udp, dmx }
+    }
+
+    /// one color per segment with brightness folded in
+    fn dimmed_colors(&self, updates: &[ZoneUpdate]) -> Vec<[u8; 3]> {
+        updates.iter()
+            .map(|update| self.config.dimmed(&update.color).map(|value| (value * 255.0).round() as u8))
+            .collect()
+    }
+}
+
+impl LightSink for UdpSink {
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        match self.config.service {
+            // one color per segment, spread over the segment's LEDs
+            LightService::WLED => {
+                let wled = self.config.wled.as_ref().context("WLED light has no wled section")?;
+                let colors = self.dimmed_colors(updates);
+                let ranges = wled.segment_ranges(colors.len())?;
+                for packet in wled.packets(&ranges, &colors) {
+                    self.udp.send(&wled.host, wled.port, &packet)?;
+                }
+                Ok(())
+            }
+            // one color per pixel, written into the universe buffer. the universe is sent when the frame is flushed
+            LightService::DMX => {
+                let fixture = self.config.dmx.as_ref().context("DMX light has no dmx section")?;
+                let dmx = self.dmx.as_ref().context("DMX output is not open")?;
+                dmx.set(fixture, &self.dimmed_colors(updates))
+            }
+            // Govee takes a single color and brightness. the LAN API has no transitions
+            LightService::Govee => {
+                let govee_light = self.config.govee.as_ref().context("Govee light has no govee section")?;
+                let (rgb, brightness) = self.config.color(&updates[0].color);
+                self.udp.send(&govee_light.host, govee::PORT, &govee::color(rgb))?;
+                self.udp.send(&govee_light.host, govee::PORT, &govee::brightness(brightness))
+            }
+            _ => bail!("{} is not a UDP light", self.config.light_name),
+        }
+    }
+
+    /// DMX universes go out once per frame with every fixture in them
+    fn flush(&mut self) -> Result<()> {
+        match (&self.config.service, &self.dmx) {
+            (LightService::DMX, Some(dmx)) => dmx.flush(&self.udp),
+            _ => Ok(()),
+        }
+    }
+
+    /// WLED falls back to its own effect when packets stop, and DMX receivers expect a steady refresh
+    fn capabilities(&self) -> SinkCapabilities {
+        let streaming = matches!(self.config.service, LightService::WLED | LightService::DMX);
+        SinkCapabilities { segments: streaming, every_frame: streaming }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, bail};
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+
+const GAUSSIAN_SIGMA: f32 = 0.35;       // falloff width as a fraction of the zone's depth
+const MIN_WEIGHT: f32 = 0.01;           // keeps the far side of a zone from dropping out entirely
+
+/// How pixel weight drops off with distance from the zone's edge
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Falloff {
+    Linear,
+    Gaussian,
+}
+
+/// Screen edge a zone's light sits next to. Pixels nearest this edge get the most weight.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+/// Per pixel weights for a zone, so the light follows what's right next to it instead of the whole rectangle equally
+pub enum ZoneWeighting {
+    Falloff { falloff: Falloff, edge: Edge },
+    /// greyscale image stretched over the zone. white is full weight, black is none
+    Mask(GrayImage),
+}
+
+impl ZoneWeighting {
+    /// builds weighting from zone settings. Returns None when the zone is weighted evenly.
+    pub fn from_config(falloff: Option<Falloff>, edge: Option<Edge>, mask: Option<&Path>) -> Result<Option<Self>> {
+        match (falloff, edge, mask) {
+            (None, None, None) => Ok(None),
+            (Some(_), _, Some(_)) => bail!("Zone can't have both a falloff and a mask"),
+            (Some(falloff), Some(edge), None) => Ok(Some(ZoneWeighting::Falloff { falloff, edge })),
+            (Some(_), None, None) => bail!("Zone falloff needs an edge (top, bottom, left, or right)"),
+            (None, Some(_), None) => bail!("Zone edge is set without a falloff"),
+            (None, _, Some(path)) => {
+                let path = resolve_path(path)?;
+                let mask = image::open(&path)
+                    .with_context(|| format!("Failed to load zone mask {:?}", path))?
+                    .to_luma8();
+                ZoneWeighting::mask(mask).with_context(|| format!("Zone mask {:?}", path)).map(Some)
+            }
+        }
+    }
+
+    /// a mask with no white at all would leave the zone without any weight and always black
+    fn mask(mask: GrayImage) -> Result<Self> {
+        if mask.pixels().all(|pixel| pixel[0] == 0) {
+            bail!("Mask is all black, so no pixel in the zone counts. Paint the area the light should follow white");
+        }
+        Ok(ZoneWeighting::Mask(mask))
+    }
+
+    /// weight of a pixel at relative position (u, v) within the zone, both 0-1 from the top left
+    pub fn weight(&self, u: f32, v: f32) -> f32 {
+        match self {
+            ZoneWeighting::Falloff { falloff, edge } => {
+                let distance = match edge {
+                    Edge::Top => v,
+                    Edge::Bottom => 1.0 - v,
+                    Edge::Left => u,
+                    Edge::Right => 1.0 - u,
+                };
+                let weight = match falloff {
+                    Falloff::Linear => 1.0 - distance,
+                    Falloff::Gaussian => (-distance.powi(2) / (2.0 * GAUSSIAN_SIGMA.powi(2))).exp(),
+                };
+                weight.max(MIN_WEIGHT)
+            }
+            ZoneWeighting::Mask(mask) => {
+                let x = ((u * mask.width() as f32) as u32).min(mask.width() - 1);
+                let y = ((v * mask.height() as f32) as u32).min(mask.height() - 1);
+                mask.get_pixel(x, y)[0] as f32 / 255.0
+            }
+        }
+    }
+}
+
+/// masks can use ~ for the home directory. Relative paths are relative to the config directory.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    if let Ok(rest) = path.strip_prefix("~") {
+        let home = dirs::home_dir().context("Could not find home directory")?;
+        return Ok(home.join(rest));
+    }
+    if path.is_relative() {
+        let config_path = AppConfig::path()?;
+        let config_dir = config_path.parent().context("Could not find config directory")?;
+        return Ok(config_dir.join(path));
+    }
+    Ok(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn falloff(falloff: Falloff, edge: Edge) -> ZoneWeighting {
+        ZoneWeighting::from_config(Some(falloff), Some(edge), None).unwrap().unwrap()
+    }
+
+    /// weights from the zone's edge inward along the axis the edge faces
+    fn profile(weighting: &ZoneWeighting, edge: Edge) -> Vec<f32> {
+        [0.0, 0.25, 0.5, 0.75, 1.0].iter()
+            .map(|&distance| match edge {
+                Edge::Top => weighting.weight(0.5, distance),
+                Edge::Bottom => weighting.weight(0.5, 1.0 - distance),
+                Edge::Left => weighting.weight(distance, 0.5),
+                Edge::Right => weighting.weight(1.0 - distance, 0.5),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn linear_falloff_drops_away_from_each_edge() {
+        for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+            let weights = profile(&falloff(Falloff::Linear, edge), edge);
+            assert_eq!(weights, [1.0, 0.75, 0.5, 0.25, MIN_WEIGHT], "{:?}", edge);
+        }
+        // weight only depends on the distance from the edge
+        let top = falloff(Falloff::Linear, Edge::Top);
+        assert_eq!(top.weight(0.0, 0.3), top.weight(1.0, 0.3));
+    }
+
+    #[test]
+    fn gaussian_falloff_drops_away_from_each_edge() {
+        for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+            let weights = profile(&falloff(Falloff::Gaussian, edge), edge);
+            assert_eq!(weights[0], 1.0);
+            assert!((weights[2] - (-0.5f32.powi(2) / (2.0 * GAUSSIAN_SIGMA.powi(2))).exp()).abs() < 1e-6);
+            assert!(weights.windows(2).all(|pair| pair[0] > pair[1]), "{:?}: {:?}", edge, weights);
+            assert!(weights[4] >= MIN_WEIGHT);
+        }
+    }
+
+    #[test]
+    fn masks_stretch_over_the_zone() {
+        // 2x2 mask: white top left, half grey bottom right
+        let mask = GrayImage::from_fn(2, 2, |x, y| match (x, y) {
+            (0, 0) => Luma([255]),
+            (1, 1) => Luma([102]),
+            _ => Luma([0]),
+        });
+        let weighting = ZoneWeighting::mask(mask).unwrap();
+        assert_eq!(weighting.weight(0.0, 0.0), 1.0);
+        assert_eq!(weighting.weight(0.49, 0.49), 1.0);
+        assert_eq!(weighting.weight(0.5, 0.2), 0.0);
+        assert_eq!(weighting.weight(0.2, 0.9), 0.0);
+        assert_eq!(weighting.weight(0.75, 0.75), 0.4);
+        assert_eq!(weighting.weight(1.0, 1.0), 0.4);
+    }
+
+    #[test]
+    fn rejects_all_black_masks() {
+        assert!(ZoneWeighting::mask(GrayImage::new(8, 8)).is_err());
+
+        let path = std::env::temp_dir().join(format!("zync-black-mask-{}.png", std::process::id()));
+        GrayImage::new(8, 8).save(&path).unwrap();
+        let result = ZoneWeighting::from_config(None, None, Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", result.err().unwrap()).contains("all black"));
+    }
+
+    #[test]
+    fn rejects_incomplete_settings() {
+        assert!(ZoneWeighting::from_config(None, None, None).unwrap().is_none());
+        assert!(ZoneWeighting::from_config(Some(Falloff::Linear), None, None).is_err());
+        assert!(ZoneWeighting::from_config(None, Some(Edge::Top), None).is_err());
+        assert!(ZoneWeighting::from_config(Some(Falloff::Linear), Some(Edge::Top), Some(Path::new("mask.png"))).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::ops::Range;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+const DNRGB: u8 = 4;
+const MAX_LEDS_PER_PACKET: usize = 489;     // DNRGB packets carry at most 489 LEDs. longer strips are split
+
+/// Settings for a WLED strip driven with the realtime UDP protocol
+#[derive(Deserialize, Debug, Clone)]
+pub struct WledConfig {
+    pub host: String,                   // IP address or hostname of the WLED controller
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub leds: u16,                      // number of LEDs on the strip
+    /// LEDs per segment in segment order. leave empty to split the strip evenly between segments
+    #[serde(default)]
+    pub segment_leds: Vec<u16>,
+    #[serde(default = "default_timeout")]
+    pub timeout: u8,                    // seconds without packets before WLED goes back to its own effect. 255 never does
+}
+
+fn default_port() -> u16 { 21324 }
+fn default_timeout() -> u8 { 2 }
+
+impl WledConfig {
+    /// LED index range of each segment
+    pub fn segment_ranges(&self, segments: usize) -> Result<Vec<Range<usize>>> {
+        split_leds(self.leds as usize, segments, &self.segment_leds)
+            .with_context(|| format!("WLED strip at {}", self.host))
+    }
+
+    /// DNRGB packets setting each LED range to its segment's color
+    pub fn packets(&self, ranges: &[Range<usize>], colors: &[[u8; 3]]) -> Vec<Vec<u8>> {
+        let mut leds = vec![[0u8; 3]; ranges.last().map_or(0, |range| range.end)];
+        for (range, color) in ranges.iter().zip(colors) {
+            leds[range.clone()].fill(*color);
+        }
+
+        leds.chunks(MAX_LEDS_PER_PACKET)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let start = (i * MAX_LEDS_PER_PACKET) as u16;
+                let mut packet = Vec::with_capacity(4 + chunk.len() * 3);
+                packet.extend_from_slice(&[DNRGB, self.timeout]);
+                packet.extend_from_slice(&start.to_be_bytes());
+                packet.extend(chunk.iter().flatten());
+                packet
+            })
+            .collect()
+    }
+}
+
+/// LED index range of each segment, from LEDs per segment in order or split evenly when segment_leds is empty
+pub fn split_leds(leds: usize, segments: usize, segment_leds: &[u16]) -> Result<Vec<Range<usize>>> {
+    if segments == 0 {
+        bail!("Needs at least one zone in segments");
+    }
+
+    let counts: Vec<usize> = if segment_leds.is_empty() {
+        // spread the remainder over the first segments so every LED is used
+        (0..segments).map(|i| leds / segments + usize::from(i < leds % segments)).collect()
+    } else if segment_leds.len() != segments {
+        bail!("Has {} segment_leds for {} segments", segment_leds.len(), segments);
+    } else {
+        segment_leds.iter().map(|count| *count as usize).collect()
+    };
+    if counts.iter().sum::<usize>() > leds {
+        bail!("Has more segment_leds than its {} LEDs", leds);
+    }
+
+    let mut start = 0;
+    Ok(counts.into_iter()
+        .map(|count| {
+            let range = start..start + count;
+            start += count;
+            range
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use crate::udp::UdpOutput;
+
+    fn strip(leds: u16, segment_leds: Vec<u16>) -> WledConfig {
+        WledConfig { host: "127.0.0.1".to_string(), port: default_port(), leds, segment_leds, timeout: 5 }
+    }
+
+    #[test]
+    fn uneven_segments_spread_the_remainder() {
+        assert_eq!(split_leds(10, 3, &[]).unwrap(), [0..4, 4..7, 7..10]);
+        assert_eq!(split_leds(11, 4, &[]).unwrap(), [0..3, 3..6, 6..9, 9..11]);
+        assert_eq!(split_leds(2, 3, &[]).unwrap(), [0..1, 1..2, 2..2]);
+        assert_eq!(split_leds(30, 3, &[5, 20, 4]).unwrap(), [0..5, 5..25, 25..29]);
+    }
+
+    #[test]
+    fn rejects_bad_segment_leds() {
+        assert!(split_leds(10, 0, &[]).is_err());
+        assert!(split_leds(10, 3, &[3, 3]).is_err());
+        assert!(split_leds(10, 2, &[6, 5]).is_err());
+    }
+
+    #[test]
+    fn packets_arrive_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut wled = strip(1000, vec![]);
+        wled.port = receiver.local_addr().unwrap().port();
+        let output = UdpOutput::new([(wled.host.as_str(), wled.port)]).unwrap();
+
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let ranges = wled.segment_ranges(colors.len()).unwrap();
+        assert_eq!(ranges, [0..334, 334..667, 667..1000]);
+        for packet in wled.packets(&ranges, &colors) {
+            output.send(&wled.host, wled.port, &packet).unwrap();
+        }
+
+        let mut leds = Vec::new();
+        for expected_start in [0u16, 489, 978] {
+            let mut buffer = [0u8; 2048];
+            let length = receiver.recv(&mut buffer).unwrap();
+            let packet = &buffer[..length];
+            assert_eq!(packet[0], DNRGB);
+            assert_eq!(packet[1], 5);
+            assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), expected_start);
+            assert_eq!((length - 4) / 3, if expected_start == 978 { 22 } else { 489 });
+            assert_eq!((length - 4) % 3, 0);
+            leds.extend(packet[4..].chunks(3).map(|led| [led[0], led[1], led[2]]));
+        }
+
+        assert_eq!(leds.len(), 1000);
+        for (range, color) in ranges.iter().zip(colors) {
+            assert!(leds[range.clone()].iter().all(|led| *led == color));
+        }
+    }
+}
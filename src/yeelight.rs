@@ -0,0 +1,255 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::lights::LightConfig;
+use crate::sink::{LightSink, SinkCapabilities, ZoneUpdate};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_SMOOTH_MS: u32 = 30;                  // shorter smooth transitions are rejected, so those are sent as sudden
+
+/// Settings for a Yeelight bulb or strip. Turn on LAN Control in the Yeelight app first.
+#[derive(Deserialize, Debug, Clone)]
+pub struct YeelightConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 { 55443 }
+
+/// Music mode connection to one bulb. We ask the bulb to connect back to a listener of ours, and commands on that
+/// connection skip the 60 per minute limit and get no replies.
+pub struct Yeelight {
+    stream: Mutex<TcpStream>,
+    initial: Option<YeelightState>,
+}
+
+/// what the bulb showed before music mode, to put back when syncing stops
+#[derive(Debug, Clone, Copy)]
+struct YeelightState {
+    on: bool,
+    brightness: u8,                 // 1-100
+    rgb: u32,
+}
+
+impl Yeelight {
+    pub fn connect(config: &YeelightConfig) -> Result<Self> {
+        let address = (config.host.as_str(), config.port).to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .with_context(|| format!("Could not resolve {}", config.host))?;
+        let control = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+            .with_context(|| format!("Failed to connect to Yeelight at {}. Is LAN Control on?", config.host))?;
+        control.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut control = BufReader::new(control);
+
+        // listen on the address the bulb reached us from
+        let local_ip = control.get_ref().local_addr()?.ip();
+        let listener = TcpListener::bind((local_ip, 0)).context("Failed to open Yeelight music mode listener")?;
+        let port = listener.local_addr()?.port();
+
+        let properties = request(&mut control, "get_prop", json!(["power", "bright", "rgb"]))?;
+        let property = |i: usize| properties[i].as_str().unwrap_or_default().to_string();
+        let initial = match (property(1).parse(), property(2).parse()) {
+            (Ok(brightness), Ok(rgb)) => Some(YeelightState { on: property(0) == "on", brightness, rgb }),
+            _ => None,
+        };
+
+        request(&mut control, "set_power", json!(["on", "smooth", 500]))?;
+        request(&mut control, "set_music", json!([1, local_ip.to_string(), port]))?;
+
+        listener.set_nonblocking(true)?;
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(error) if error.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Err(error) => return Err(error).with_context(|| format!("Yeelight at {} didn't open its music mode connection", config.host)),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Yeelight { stream: Mutex::new(stream), initial })
+    }
+
+    /// color and brightness with the transition as a smooth fade. brightness is 0-255 like in Zigbee2MQTT payloads
+    pub fn set(&self, [r, g, b]: [u8; 3], brightness: u8, transition: f32) -> Result<()> {
+        let duration = (transition.max(0.0) * 1000.0).round() as u32;
+        let effect = if duration < MIN_SMOOTH_MS { "sudden" } else { "smooth" };
+        // black isn't a valid color and brightness starts at 1
+        let rgb = (u32::from(r) << 16 | u32::from(g) << 8 | u32::from(b)).max(1);
+        let brightness = (brightness as f32 / 255.0 * 100.0).round().max(1.0) as u8;
+
+        self.send(&[("set_rgb", json!([rgb, effect, duration])), ("set_bright", json!([brightness, effect, duration]))])
+    }
+
+    /// puts back the color, brightness, and power from before music mode
+    pub fn restore(&self) -> Result<()> {
+        let Some(initial) = self.initial else { return Ok(()) };
+        let mut commands = vec![("set_rgb", json!([initial.rgb, "smooth", 500])), ("set_bright", json!([initial.brightness, "smooth", 500]))];
+        if !initial.on {
+            commands.push(("set_power", json!(["off", "smooth", 500])));
+        }
+        self.send(&commands)
+    }
+
+    /// commands on the music mode connection
+    fn send(&self, commands: &[(&str, Value)]) -> Result<()> {
+        let mut lines = String::new();
+        for (method, params) in commands {
+            lines.push_str(&json!({ "id": 1, "method": method, "params": params }).to_string());
+            lines.push_str("\r\n");
+        }
+        let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        stream.write_all(lines.as_bytes()).context("Failed to send to Yeelight")
+    }
+}
+
+/// A Yeelight bulb or strip over its music mode connection
+pub struct YeelightSink {
+    config: LightConfig,
+    yeelight: Arc<Yeelight>,
+}
+
+impl YeelightSink {
+    pub fn new(config: LightConfig, yeelight: Arc<Yeelight>) -> Self {
+        YeelightSink { config, yeelight }
+    }
+}
+
+impl LightSink for YeelightSink {
+    /// Yeelight takes a single color, faded over the transition
+    fn send(&mut self, updates: &[ZoneUpdate]) -> Result<()> {
+        let (rgb, brightness) = self.config.color(&updates[0].color);
+        self.yeelight.set(rgb, brightness, updates[0].transition)
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities { segments: false, every_frame: false }
+    }
+
+    fn config_mut(&mut self) -> &mut LightConfig {
+        &mut self.config
+    }
+
+    /// the state was read when connecting, before music mode took over
+    fn restore(&mut self) -> Result<()> {
+        self.yeelight.restore()
+    }
+}
+
+/// sends a command on the control connection and waits for its result. bulbs also send property notifications, which are skipped
+fn request(control: &mut BufReader<TcpStream>, method: &str, params: Value) -> Result<Value> {
+    let command = json!({ "id": 1, "method": method, "params": params });
+    control.get_mut().write_all(format!("{}\r\n", command).as_bytes()).context("Failed to send to Yeelight")?;
+
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line).context("Failed to read from Yeelight")? == 0 {
+            bail!("Yeelight closed the connection");
+        }
+        let Ok(response) = serde_json::from_str::<Value>(&line) else { continue };
+        if response["id"] != 1 {
+            continue;
+        }
+        if !response["error"].is_null() {
+            bail!("Yeelight rejected {}: {}", method, response["error"]["message"]);
+        }
+        return Ok(response["result"].clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+
+    /// mock bulb on a local port. answers control commands like a bulb that was on at 50% red, connects back for
+    /// music mode, and returns every line it receives on either connection
+    fn bulb() -> (YeelightConfig, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = YeelightConfig { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port() };
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (control, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(control.try_clone().unwrap());
+            let mut control = control;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command: Value = serde_json::from_str(&line).unwrap();
+                line.clear();
+                let result = match command["method"].as_str().unwrap() {
+                    "get_prop" => json!(["on", "50", "16711680"]),
+                    _ => json!(["ok"]),
+                };
+                // bulbs send notifications between replies
+                control.write_all(b"{\"method\":\"props\",\"params\":{\"power\":\"on\"}}\r\n").unwrap();
+                control.write_all(format!("{}\r\n", json!({ "id": command["id"], "result": result })).as_bytes()).unwrap();
+
+                if command["method"] == "set_music" {
+                    let params = &command["params"];
+                    let music = TcpStream::connect((params[1].as_str().unwrap(), params[2].as_u64().unwrap() as u16)).unwrap();
+                    sender.send(command).unwrap();
+                    for line in BufReader::new(music).lines() {
+                        sender.send(serde_json::from_str(&line.unwrap()).unwrap()).unwrap();
+                    }
+                    return;
+                }
+                sender.send(command).unwrap();
+            }
+        });
+        (config, receiver)
+    }
+
+    fn next(received: &Receiver<Value>) -> (String, Value) {
+        let command = received.recv_timeout(Duration::from_secs(2)).unwrap();
+        (command["method"].as_str().unwrap().to_string(), command["params"].clone())
+    }
+
+    #[test]
+    fn connect_reads_state_and_opens_music_mode() {
+        let (config, received) = bulb();
+        let yeelight = Yeelight::connect(&config).unwrap();
+
+        assert_eq!(next(&received), ("get_prop".to_string(), json!(["power", "bright", "rgb"])));
+        assert_eq!(next(&received), ("set_power".to_string(), json!(["on", "smooth", 500])));
+        let (method, params) = next(&received);
+        assert_eq!(method, "set_music");
+        assert_eq!(params[0], 1);
+        assert_eq!(params[1], "127.0.0.1");
+
+        let initial = yeelight.initial.unwrap();
+        assert!(initial.on);
+        assert_eq!((initial.brightness, initial.rgb), (50, 0xff0000));
+    }
+
+    #[test]
+    fn colors_go_over_the_music_connection() {
+        let (config, received) = bulb();
+        let yeelight = Yeelight::connect(&config).unwrap();
+        for _ in 0..3 {
+            next(&received);
+        }
+
+        yeelight.set([0, 128, 255], 255, 0.5).unwrap();
+        assert_eq!(next(&received), ("set_rgb".to_string(), json!([0x0080ff, "smooth", 500])));
+        assert_eq!(next(&received), ("set_bright".to_string(), json!([100, "smooth", 500])));
+
+        // short transitions are sudden, black and zero brightness are clamped to the lowest valid values
+        yeelight.set([0, 0, 0], 0, 0.01).unwrap();
+        assert_eq!(next(&received), ("set_rgb".to_string(), json!([1, "sudden", 10])));
+        assert_eq!(next(&received), ("set_bright".to_string(), json!([1, "sudden", 10])));
+
+        yeelight.restore().unwrap();
+        assert_eq!(next(&received), ("set_rgb".to_string(), json!([0xff0000, "smooth", 500])));
+        assert_eq!(next(&received), ("set_bright".to_string(), json!([50, "smooth", 500])));
+    }
+}